@@ -0,0 +1,92 @@
+#!/usr/bin/env bash
+# Benchmark the scripts in examples/ with a release build
+#
+# Usage: ./bench.sh [git revision...]
+#
+# The examples only take microseconds to run, far less than starting
+# the VM, so each one is wrapped in a loop that runs it ITERS times
+# (1000 by default). The looped script is run RUNS times (11 by
+# default) and the median wall time is reported.
+#
+# Each git revision given is built in a temporary worktree and
+# compared with the working tree. The runs of all the builds are
+# interleaved so that they see the same machine conditions.
+
+set -e
+
+ITERS=${ITERS:-1000}
+RUNS=${RUNS:-11}
+ROOT=$(cd "$(dirname "$0")" && pwd)
+TMP=$(mktemp -d)
+BINS=()
+NAMES=()
+
+cleanup()
+{
+    for name in "${NAMES[@]}"; do
+        if [ -d "$TMP/$name" ]; then
+            git -C "$ROOT" worktree remove --force "$TMP/$name"
+        fi
+    done
+    rm -rf "$TMP"
+}
+trap cleanup EXIT
+
+for rev in "$@"; do
+    NAMES+=("$rev")
+    git -C "$ROOT" worktree add --quiet --detach "$TMP/$rev" "$rev"
+    cargo build --release --quiet --manifest-path "$TMP/$rev/Cargo.toml" --target-dir "$TMP/target-$rev"
+    BINS+=("$TMP/target-$rev/release/ksvm")
+done
+
+cargo build --release --quiet --manifest-path "$ROOT/Cargo.toml"
+BINS+=("$ROOT/target/release/ksvm")
+NAMES+=("current")
+
+# Time a single run in milliseconds
+time_run()
+{
+    local start end
+    start=$(date +%s%N)
+    "$1" "$2" > /dev/null
+    end=$(date +%s%N)
+    echo $(( (end - start) / 1000000 ))
+}
+
+# Median of numbers read from stdin
+median()
+{
+    sort -n | awk '{ v[NR] = $1 } END { print v[int((NR + 1) / 2)] }'
+}
+
+printf "%-16s" "example"
+for name in "${NAMES[@]}"; do
+    printf "%14s" "$name"
+done
+echo " (median ms over $RUNS runs of $ITERS iterations)"
+
+for example in "$ROOT"/examples/*.ks; do
+    looped="$TMP/$(basename "$example")"
+    {
+        echo "for (bench_iter in 0..$ITERS) {"
+        cat "$example"
+        echo
+        echo "}"
+    } > "$looped"
+
+    declare -A times=()
+
+    for ((run = 0; run < RUNS; run++)); do
+        for idx in "${!BINS[@]}"; do
+            times[$idx]+="$(time_run "${BINS[$idx]}" "$looped") "
+        done
+    done
+
+    printf "%-16s" "$(basename "$example")"
+    for idx in "${!BINS[@]}"; do
+        printf "%14s" "$(echo "${times[$idx]}" | tr ' ' '\n' | grep . | median)"
+    done
+    echo
+
+    unset times
+done
@@ -0,0 +1,651 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use crate::vm::*;
+use crate::runtime::get_runtime_fn;
+use crate::regvm::compile_unit;
+use crate::verifier::verify_unit;
+
+/// Magic bytes at the start of a compiled bytecode (.ksc) file
+const MAGIC: &[u8; 4] = b"KSC\0";
+
+/// Format version, to be incremented whenever the encoding changes
+const VERSION: u32 = 3;
+
+/// Size of the magic bytes, version and checksum
+const HEADER_SIZE: usize = 16;
+
+/// Tags identifying the kind of a constant
+const CONST_INT64: u8 = 0;
+const CONST_NIL: u8 = 1;
+const CONST_STR: u8 = 2;
+const CONST_FUN: u8 = 3;
+const CONST_HOSTFN: u8 = 4;
+
+#[derive(Debug)]
+pub struct LoadError
+{
+    pub msg: String,
+}
+
+impl LoadError
+{
+    fn new(msg: &str) -> Self
+    {
+        LoadError { msg: msg.to_string() }
+    }
+}
+
+impl fmt::Display for LoadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid bytecode file: {}", self.msg)
+    }
+}
+
+/// FNV-1a hash, used to detect corrupted files
+fn checksum(data: &[u8]) -> u64
+{
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+/// Little-endian encoder for the contents of a bytecode file
+struct Writer
+{
+    bytes: Vec<u8>,
+}
+
+impl Writer
+{
+    fn write_u8(&mut self, val: u8)
+    {
+        self.bytes.push(val);
+    }
+
+    fn write_u32(&mut self, val: usize)
+    {
+        let val: u32 = val.try_into().expect("value too large to serialize");
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn write_i64(&mut self, val: i64)
+    {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn write_bool(&mut self, val: bool)
+    {
+        self.write_u8(val as u8);
+    }
+
+    fn write_str(&mut self, s: &str)
+    {
+        self.write_u32(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_strs(&mut self, strs: &[String])
+    {
+        self.write_u32(strs.len());
+        for s in strs {
+            self.write_str(s);
+        }
+    }
+}
+
+/// Decoder for the contents of a bytecode file, which
+/// fails instead of reading past the end of the data
+struct Reader<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a>
+{
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], LoadError>
+    {
+        if len > self.data.len() - self.pos {
+            return Err(LoadError::new("unexpected end of file"));
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, LoadError>
+    {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<usize, LoadError>
+    {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn read_i64(&mut self) -> Result<i64, LoadError>
+    {
+        let bytes = self.read_bytes(8)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, LoadError>
+    {
+        self.read_i64()?.try_into().map_err(|_| LoadError::new("operand out of range"))
+    }
+
+    fn read_offset(&mut self) -> Result<isize, LoadError>
+    {
+        self.read_i64()?.try_into().map_err(|_| LoadError::new("invalid branch offset"))
+    }
+
+    fn read_bool(&mut self) -> Result<bool, LoadError>
+    {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LoadError::new("invalid boolean"))
+        }
+    }
+
+    fn read_str(&mut self) -> Result<String, LoadError>
+    {
+        let len = self.read_u32()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::new("invalid utf-8 string"))
+    }
+
+    fn read_strs(&mut self) -> Result<Vec<String>, LoadError>
+    {
+        let len = self.read_u32()?;
+        (0..len).map(|_| self.read_str()).collect()
+    }
+
+    /// Read a count of items that each take at least item_size bytes,
+    /// so that a corrupted count can't cause a huge allocation
+    fn read_count(&mut self, item_size: usize) -> Result<usize, LoadError>
+    {
+        let count = self.read_u32()?;
+        if count * item_size > self.data.len() - self.pos {
+            return Err(LoadError::new("unexpected end of file"));
+        }
+        Ok(count)
+    }
+}
+
+/// List a unit function and all the functions it refers to,
+/// with the unit function first and each function listed once
+pub fn list_funs(unit_fun: &Function) -> Vec<*const Function>
+{
+    let mut funs: Vec<*const Function> = vec![unit_fun];
+    let mut visited = HashSet::new();
+    let mut idx = 0;
+
+    // Functions can refer to each other recursively
+    while idx < funs.len() {
+        let fun = unsafe { &*funs[idx] };
+        for insn in &fun.insns {
+            if let Insn::Push { val } = insn {
+                if let ValueKind::Fun(fun_ptr) = val.kind() {
+                    if visited.insert(fun_ptr) {
+                        funs.push(fun_ptr);
+                    }
+                }
+            }
+        }
+        idx += 1;
+    }
+
+    funs
+}
+
+/// Serialize a unit function and all the functions it refers to
+pub fn serialize_unit(unit_fun: &Function) -> Vec<u8>
+{
+    // Number the functions so that constants can refer to them
+    let funs = list_funs(unit_fun);
+    let fun_idxs: HashMap<*const Function, usize> = funs.iter().enumerate().map(|(idx, fun_ptr)| (*fun_ptr, idx)).collect();
+
+    let mut w = Writer { bytes: Vec::default() };
+    w.write_u32(funs.len());
+
+    for fun_ptr in funs {
+        write_fun(&mut w, unsafe { &*fun_ptr }, &fun_idxs);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + w.bytes.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&checksum(&w.bytes).to_le_bytes());
+    out.extend_from_slice(&w.bytes);
+    out
+}
+
+fn write_fun(w: &mut Writer, fun: &Function, fun_idxs: &HashMap<*const Function, usize>)
+{
+    w.write_str(&fun.name);
+    w.write_str(&fun.src_name);
+    w.write_strs(&fun.params);
+    w.write_u32(fun.num_required);
+    w.write_bool(fun.has_rest);
+    w.write_u32(fun.entry_idxs.len());
+    for entry_idx in &fun.entry_idxs {
+        w.write_u32(*entry_idx);
+    }
+    w.write_strs(&fun.unbound_vars);
+    w.write_u32(fun.num_locals);
+    w.write_u32(fun.local_vars.len());
+    for var in &fun.local_vars {
+        w.write_str(&var.name);
+        w.write_u32(var.idx);
+        w.write_u32(var.start);
+        w.write_u32(var.end);
+    }
+    w.write_bool(fun.is_generator);
+
+    w.write_u32(fun.jump_tables.len());
+    for table in &fun.jump_tables {
+        w.write_i64(table.min_val);
+        w.write_u32(table.offsets.len());
+        for offset in &table.offsets {
+            w.write_i64(*offset as i64);
+        }
+        w.write_i64(table.default_offset as i64);
+    }
+
+    w.write_u32(fun.insns.len());
+    for insn in &fun.insns {
+        write_insn(w, insn, fun_idxs);
+    }
+
+    w.write_u32(fun.insn_lines.len());
+    for line_no in &fun.insn_lines {
+        w.write_u32(*line_no as usize);
+    }
+}
+
+fn write_const(w: &mut Writer, val: Value, fun_idxs: &HashMap<*const Function, usize>)
+{
+    match val.kind() {
+        ValueKind::Int64(v) => {
+            w.write_u8(CONST_INT64);
+            w.write_i64(v);
+        }
+        ValueKind::Nil => w.write_u8(CONST_NIL),
+        ValueKind::Str(str_ptr) => {
+            w.write_u8(CONST_STR);
+            w.write_str(unsafe { &*str_ptr });
+        }
+        ValueKind::Fun(fun_ptr) => {
+            w.write_u8(CONST_FUN);
+            w.write_u32(fun_idxs[&(fun_ptr as *const Function)]);
+        }
+        ValueKind::HostFn(host_fn) => {
+            w.write_u8(CONST_HOSTFN);
+            w.write_str(host_fn.name());
+        }
+        kind => panic!("cannot serialize constant {:?}", kind)
+    }
+}
+
+fn write_insn(w: &mut Writer, insn: &Insn, fun_idxs: &HashMap<*const Function, usize>)
+{
+    use Insn::*;
+
+    // Each instruction is an opcode followed by its operands
+    match *insn {
+        Panic => w.write_u8(0),
+        Halt => w.write_u8(1),
+        Debugger => w.write_u8(35),
+        GetLocal { idx } => { w.write_u8(2); w.write_u32(idx); }
+        SetLocal { idx } => { w.write_u8(3); w.write_u32(idx); }
+        Push { val } => { w.write_u8(4); write_const(w, val, fun_idxs); }
+        Pop => w.write_u8(5),
+        Dup => w.write_u8(6),
+        GetIndex => w.write_u8(7),
+        Add => w.write_u8(8),
+        Sub => w.write_u8(9),
+        Mul => w.write_u8(10),
+        Mod => w.write_u8(11),
+        Neg => w.write_u8(12),
+        Eq => w.write_u8(13),
+        Ne => w.write_u8(14),
+        Lt => w.write_u8(15),
+        Le => w.write_u8(16),
+        Gt => w.write_u8(17),
+        Ge => w.write_u8(18),
+        Not => w.write_u8(19),
+        Range => w.write_u8(20),
+        GetIter => w.write_u8(21),
+        IterNext { offset } => { w.write_u8(22); w.write_i64(offset as i64); }
+        Yield => w.write_u8(23),
+        Resume => w.write_u8(24),
+        Jump { offset } => { w.write_u8(25); w.write_i64(offset as i64); }
+        IfTrue { offset } => { w.write_u8(26); w.write_i64(offset as i64); }
+        IfFalse { offset } => { w.write_u8(27); w.write_i64(offset as i64); }
+        JumpTable { table_idx } => { w.write_u8(28); w.write_u32(table_idx); }
+        Call { argc } => { w.write_u8(29); w.write_u32(argc); }
+        TailCall { argc } => { w.write_u8(30); w.write_u32(argc); }
+        Return => w.write_u8(31),
+        AddConst { val } => { w.write_u8(32); w.write_i64(val); }
+        AddLocalConst { idx, val } => {
+            w.write_u8(33);
+            w.write_u32(idx as usize);
+            w.write_i64(val as i64);
+        }
+        JumpIfLocalGeConst { idx, val, offset } => {
+            w.write_u8(34);
+            w.write_u32(idx as usize);
+            w.write_i64(val as i64);
+            w.write_i64(offset as i64);
+        }
+    }
+}
+
+/// Deserialize a unit function, allocating the functions
+/// it refers to and interning its strings in the VM
+pub fn deserialize_unit(vm: &mut VM, data: &[u8]) -> Result<Function, LoadError>
+{
+    if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+        return Err(LoadError::new("not a bytecode file"));
+    }
+
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(LoadError::new(&format!("unsupported version {}, expected {}", version, VERSION)));
+    }
+
+    let body = &data[HEADER_SIZE..];
+    if u64::from_le_bytes(data[8..16].try_into().unwrap()) != checksum(body) {
+        return Err(LoadError::new("checksum mismatch"));
+    }
+
+    let mut r = Reader { data: body, pos: 0 };
+
+    // A function takes at least 46 bytes
+    let num_funs = r.read_count(46)?;
+    if num_funs == 0 {
+        return Err(LoadError::new("missing unit function"));
+    }
+
+    // Allocate the nested functions up front so that constants can refer
+    // to functions that come later. This doesn't trigger a collection
+    // because the GC only runs during execution.
+    let mut unit_fun = Function::new("");
+    let mut fun_vals = vec![Value::Nil];
+    for _ in 1..num_funs {
+        fun_vals.push(vm.into_gc_heap(Function::new("")));
+    }
+
+    for idx in 0..num_funs {
+        let fun = match fun_vals[idx].kind() {
+            ValueKind::Fun(fun_ptr) => unsafe { &mut *fun_ptr },
+            _ => &mut unit_fun
+        };
+        read_fun(vm, &mut r, fun, &fun_vals)?;
+    }
+
+    if r.pos != body.len() {
+        return Err(LoadError::new("trailing data"));
+    }
+
+    // Files can be corrupted or crafted, so the code has
+    // to be checked before it can be compiled or run
+    if let Err(err) = verify_unit(&unit_fun) {
+        return Err(LoadError::new(&err.to_string()));
+    }
+
+    // The register code isn't stored, since it's quick to regenerate
+    compile_unit(&mut unit_fun);
+
+    Ok(unit_fun)
+}
+
+fn read_fun(vm: &mut VM, r: &mut Reader, fun: &mut Function, fun_vals: &[Value]) -> Result<(), LoadError>
+{
+    fun.name = r.read_str()?;
+    fun.src_name = r.read_str()?;
+    fun.params = r.read_strs()?;
+    fun.num_required = r.read_u32()?;
+    fun.has_rest = r.read_bool()?;
+    let num_entries = r.read_count(4)?;
+    fun.entry_idxs = (0..num_entries).map(|_| r.read_u32()).collect::<Result<_, _>>()?;
+    fun.unbound_vars = r.read_strs()?;
+    fun.num_locals = r.read_u32()?;
+    let num_vars = r.read_count(16)?;
+    for _ in 0..num_vars {
+        let name = r.read_str()?;
+        fun.local_vars.push(LocalVar { name, idx: r.read_u32()?, start: r.read_u32()?, end: r.read_u32()? });
+    }
+    fun.is_generator = r.read_bool()?;
+
+    let num_tables = r.read_count(20)?;
+    for _ in 0..num_tables {
+        let min_val = r.read_i64()?;
+        let num_offsets = r.read_count(8)?;
+        let offsets = (0..num_offsets).map(|_| r.read_offset()).collect::<Result<_, _>>()?;
+        let default_offset = r.read_offset()?;
+        fun.jump_tables.push(JumpTable { min_val, offsets, default_offset });
+    }
+
+    let num_insns = r.read_count(1)?;
+    fun.insns = Vec::with_capacity(num_insns);
+    for _ in 0..num_insns {
+        let insn = read_insn(vm, r, fun_vals)?;
+        fun.insns.push(insn);
+    }
+
+    let num_lines = r.read_count(4)?;
+    if num_lines != 0 && num_lines != num_insns {
+        return Err(LoadError::new("line table doesn't match the instructions"));
+    }
+    fun.insn_lines = (0..num_lines).map(|_| r.read_u32().map(|line_no| line_no as u32)).collect::<Result<_, _>>()?;
+
+    Ok(())
+}
+
+fn read_const(vm: &mut VM, r: &mut Reader, fun_vals: &[Value]) -> Result<Value, LoadError>
+{
+    match r.read_u8()? {
+        CONST_INT64 => {
+            let v = r.read_i64()?;
+            Ok(vm.int64(v))
+        }
+        CONST_NIL => Ok(Value::Nil),
+        CONST_STR => {
+            let s = r.read_str()?;
+            Ok(vm.intern(&s))
+        }
+        CONST_FUN => {
+            match r.read_u32()? {
+                idx if idx > 0 && idx < fun_vals.len() => Ok(fun_vals[idx]),
+                _ => Err(LoadError::new("invalid function index"))
+            }
+        }
+        CONST_HOSTFN => {
+            let name = r.read_str()?;
+            match get_runtime_fn(&name) {
+                Some(host_fn) => Ok(Value::HostFn(host_fn)),
+                None => Err(LoadError::new(&format!("unknown runtime function {}", name)))
+            }
+        }
+        tag => Err(LoadError::new(&format!("invalid constant tag {}", tag)))
+    }
+}
+
+fn read_insn(vm: &mut VM, r: &mut Reader, fun_vals: &[Value]) -> Result<Insn, LoadError>
+{
+    use Insn::*;
+
+    Ok(match r.read_u8()? {
+        0 => Panic,
+        1 => Halt,
+        2 => GetLocal { idx: r.read_u32()? },
+        3 => SetLocal { idx: r.read_u32()? },
+        4 => Push { val: read_const(vm, r, fun_vals)? },
+        5 => Pop,
+        6 => Dup,
+        7 => GetIndex,
+        8 => Add,
+        9 => Sub,
+        10 => Mul,
+        11 => Mod,
+        12 => Neg,
+        13 => Eq,
+        14 => Ne,
+        15 => Lt,
+        16 => Le,
+        17 => Gt,
+        18 => Ge,
+        19 => Not,
+        20 => Range,
+        21 => GetIter,
+        22 => IterNext { offset: r.read_offset()? },
+        23 => Yield,
+        24 => Resume,
+        25 => Jump { offset: r.read_offset()? },
+        26 => IfTrue { offset: r.read_offset()? },
+        27 => IfFalse { offset: r.read_offset()? },
+        28 => JumpTable { table_idx: r.read_u32()? },
+        29 => Call { argc: r.read_u32()? },
+        30 => TailCall { argc: r.read_u32()? },
+        31 => Return,
+        32 => AddConst { val: r.read_i64()? },
+        33 => AddLocalConst { idx: r.read_u32()? as u32, val: r.read_i32()? },
+        34 => JumpIfLocalGeConst { idx: r.read_u32()? as u32, val: r.read_i32()?, offset: r.read_i32()? },
+        35 => Debugger,
+        op => return Err(LoadError::new(&format!("invalid opcode {}", op)))
+    })
+}
+
+/// Compile a unit function into a bytecode file
+pub fn save_file(unit_fun: &Function, file_name: &str)
+{
+    fs::write(file_name, serialize_unit(unit_fun))
+        .unwrap_or_else(|_| panic!("could not write output file {}", file_name));
+}
+
+/// Load a unit function from a bytecode file
+pub fn load_file(vm: &mut VM, file_name: &str) -> Result<Function, LoadError>
+{
+    let data = fs::read(file_name)
+        .unwrap_or_else(|_| panic!("could not read input file {}", file_name));
+
+    deserialize_unit(vm, &data)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+
+    fn eval(vm: &mut VM, unit_fn: &Function) -> Value
+    {
+        match vm.eval(unit_fn).unwrap() {
+            EvalStatus::Done(val) => val,
+            status => panic!("unexpected status {:?}", status)
+        }
+    }
+
+    /// Check that a program gives the same result after a round trip
+    fn round_trip(src: &str)
+    {
+        dbg!(src);
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let expected = eval(&mut vm, &unit_fn);
+        let data = serialize_unit(&unit_fn);
+
+        // Load into a fresh VM, so nothing is shared with the original
+        for backend in [Backend::Stack, Backend::Register, Backend::Jit] {
+            let mut vm = VM::new();
+            vm.set_backend(backend);
+            let unit_fn = deserialize_unit(&mut vm, &data).unwrap();
+            assert_eq!(eval(&mut vm, &unit_fn), expected);
+
+            // Serializing again gives the same bytes
+            assert_eq!(serialize_unit(&unit_fn), data);
+        }
+    }
+
+    #[test]
+    fn programs()
+    {
+        round_trip("return 7;");
+        round_trip("let s = 0; for (i in 0..10) s = s + i; return s;");
+        round_trip("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } return fib(15);");
+        round_trip("fun f() { return 3; } fun g(h) { return h() * 2; } return g(f) + g(f);");
+        round_trip("fun f(a, b = a + 1, ...rest) { return a + b + len(rest); } return f(1) + f(1, 5) + f(1, 5, 9, 9);");
+        round_trip("let s = 0; for (i in 0..6) match (i) { 0 => s = s + 1; 1 => s = s + 10; 2 => s = s + 100; _ => s = s - 1; } return s;");
+        round_trip("fun g(n) { for (i in 0..n) yield i; } let s = 0; for (x in g(5)) s = s + x; return s;");
+        round_trip("let a = 'foo'; let b = string_builder(); append(b, a); append(b, 'bar'); return to_string(b) == 'foobar';");
+        round_trip("let a = 'x'; match (a) { 'x' => return 1; 'y' => return 2; } return 0;");
+        round_trip("let f = heap_stats; let g = gc; if (f == heap_stats) return g != f; return 0;");
+    }
+
+    #[test]
+    fn strings_interned()
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "return 'ab' == 'a' + 'b';").unwrap();
+        let data = serialize_unit(&unit_fn);
+
+        let mut vm = VM::new();
+        let unit_fn = deserialize_unit(&mut vm, &data).unwrap();
+        assert!(vm.intern("ab").is_interned());
+        assert_eq!(eval(&mut vm, &unit_fn), Value::Int64(1));
+    }
+
+    #[test]
+    fn invalid_files()
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "fun f(x) { return x * 2; } return f(21);").unwrap();
+        let data = serialize_unit(&unit_fn);
+
+        let load_err = |data: &[u8]| {
+            let mut vm = VM::new();
+            match deserialize_unit(&mut vm, data) {
+                Ok(_) => panic!("invalid file was loaded"),
+                Err(err) => err.msg
+            }
+        };
+
+        assert_eq!(load_err(b"not bytecode"), "not a bytecode file");
+        assert_eq!(load_err(&data[..10]), "not a bytecode file");
+
+        let mut bad_version = data.clone();
+        bad_version[4] = 99;
+        assert_eq!(load_err(&bad_version), "unsupported version 99, expected 3");
+
+        // Any corrupted byte in the body is detected
+        for idx in [HEADER_SIZE, data.len() / 2, data.len() - 1] {
+            let mut corrupted = data.clone();
+            corrupted[idx] ^= 0x40;
+            assert_eq!(load_err(&corrupted), "checksum mismatch");
+        }
+
+        let mut truncated = data[..data.len() - 3].to_vec();
+        let sum = checksum(&truncated[HEADER_SIZE..]);
+        truncated[8..16].copy_from_slice(&sum.to_le_bytes());
+        assert_eq!(load_err(&truncated), "unexpected end of file");
+
+        // Well-formed files with invalid code are rejected by the verifier
+        let mut unit_fn = Function::new("src");
+        unit_fn.insns = vec![Insn::GetLocal { idx: 5 }, Insn::Return];
+        assert_eq!(
+            load_err(&serialize_unit(&unit_fn)),
+            "local variable index out of bounds at instruction 0 of function src"
+        );
+    }
+}
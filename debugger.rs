@@ -0,0 +1,244 @@
+use std::fs;
+use std::io::{BufRead, Write};
+use crate::vm::*;
+
+const HELP: &str = "\
+commands:
+  break [file:]line    set a breakpoint (b)
+  delete [file:]line   remove a breakpoint (d)
+  step                 step to the next line, into calls (s)
+  next                 step to the next line, over calls (n)
+  finish               run until the current function returns (f)
+  continue             run until a breakpoint (c)
+  backtrace            show the call stack (bt)
+  print name           print a local variable (p)
+  locals               print all the local variables in scope
+  quit                 stop the program (q)";
+
+/// Format a value for display in the debugger
+pub fn format_value(val: Value) -> String
+{
+    use ValueKind::*;
+
+    match val.kind() {
+        Int64(v) => v.to_string(),
+        UInt64(v) => v.to_string(),
+        Str(str_ptr) => format!("{:?}", unsafe { &*str_ptr }),
+        Nil => "nil".to_string(),
+        Fun(fun_ptr) => format!("<fun {}>", unsafe { &*fun_ptr }.name),
+        HostFn(_) => "<host function>".to_string(),
+        Iter(_) => "<iterator>".to_string(),
+        Gen(_) => "<generator>".to_string(),
+        Builder(_) => "<string builder>".to_string(),
+        Array(arr_ptr) => {
+            let elems: Vec<String> = unsafe { &*arr_ptr }.iter().map(|elem| format_value(*elem)).collect();
+            format!("[{}]", elems.join(", "))
+        }
+    }
+}
+
+/// Interactive debugger running a unit function
+struct Debugger<'a>
+{
+    /// Source file of the unit function
+    src_name: &'a str,
+
+    /// Lines of the source file, if it could be read
+    src_lines: Vec<String>,
+}
+
+impl Debugger<'_>
+{
+    /// Parse a breakpoint location, with the file defaulting to the unit's
+    fn parse_location(&self, arg: &str) -> Option<(String, u32)>
+    {
+        match arg.rsplit_once(':') {
+            Some((file, line_no)) => {
+                // Allow leaving out the directory of the file
+                let src_name = if self.src_name.ends_with(file) { self.src_name } else { file };
+                Some((src_name.to_string(), line_no.parse().ok()?))
+            }
+            None => Some((self.src_name.to_string(), arg.parse().ok()?))
+        }
+    }
+
+    /// Show where execution stopped
+    fn show_location(&self, vm: &VM, out: &mut impl Write)
+    {
+        let frames = vm.backtrace();
+        let frame = &frames[0];
+
+        match frame.line_no {
+            Some(line_no) => {
+                writeln!(out, "stopped in {} at {}:{}", frame.fun_name, frame.src_name, line_no).unwrap();
+                if frame.src_name == self.src_name {
+                    if let Some(line) = (line_no as usize).checked_sub(1).and_then(|idx| self.src_lines.get(idx)) {
+                        writeln!(out, "{:>5} | {}", line_no, line).unwrap();
+                    }
+                }
+            }
+            None => writeln!(out, "stopped in {}", frame.fun_name).unwrap()
+        }
+    }
+
+    /// Read and run commands until execution is resumed
+    /// Returns false if the program should be stopped
+    fn command_loop(&self, vm: &mut VM, input: &mut impl BufRead, out: &mut impl Write) -> bool
+    {
+        loop {
+            write!(out, "(debug) ").unwrap();
+            out.flush().unwrap();
+
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+
+            let mut words = line.split_whitespace();
+            let cmd = words.next().unwrap_or("");
+            let arg = words.next();
+
+            match (cmd, arg) {
+                ("break" | "b", Some(arg)) => match self.parse_location(arg) {
+                    Some((src_name, line_no)) => {
+                        vm.add_breakpoint(&src_name, line_no);
+                        writeln!(out, "breakpoint at {}:{}", src_name, line_no).unwrap();
+                    }
+                    None => writeln!(out, "invalid location {}", arg).unwrap()
+                }
+
+                ("delete" | "d", Some(arg)) => match self.parse_location(arg) {
+                    Some((src_name, line_no)) if vm.remove_breakpoint(&src_name, line_no) => {}
+                    _ => writeln!(out, "no breakpoint at {}", arg).unwrap()
+                }
+
+                ("step" | "s", None) => { vm.step(StepMode::Into); return true; }
+                ("next" | "n", None) => { vm.step(StepMode::Over); return true; }
+                ("finish" | "f", None) => { vm.step(StepMode::Out); return true; }
+                ("continue" | "c", None) => { vm.step(StepMode::Continue); return true; }
+
+                ("backtrace" | "bt", None) => {
+                    for (idx, frame) in vm.backtrace().iter().enumerate() {
+                        match frame.line_no {
+                            Some(line_no) => writeln!(out, "#{} {} at {}:{}", idx, frame.fun_name, frame.src_name, line_no),
+                            None => writeln!(out, "#{} {}", idx, frame.fun_name)
+                        }.unwrap();
+                    }
+                }
+
+                ("print" | "p", Some(name)) => {
+                    let frames = vm.backtrace();
+                    match frames[0].locals.iter().find(|(var_name, _)| var_name == name) {
+                        Some((_, val)) => writeln!(out, "{} = {}", name, format_value(*val)).unwrap(),
+                        None => writeln!(out, "no variable named {} in scope", name).unwrap()
+                    }
+                }
+
+                ("locals", None) => {
+                    for (name, val) in &vm.backtrace()[0].locals {
+                        writeln!(out, "{} = {}", name, format_value(*val)).unwrap();
+                    }
+                }
+
+                ("quit" | "q", None) => return false,
+                ("help" | "h", None) => writeln!(out, "{}", HELP).unwrap(),
+                ("", None) => {}
+                _ => writeln!(out, "unknown command, type help for a list of commands").unwrap()
+            }
+        }
+    }
+}
+
+/// Run a unit function under the debugger, stopping at its first line.
+/// Commands are read from input, and what the debugger shows is
+/// written to out, separately from what the program prints.
+pub fn debug_unit(vm: &mut VM, unit_fun: &Function, input: &mut impl BufRead, out: &mut impl Write) -> Result<(), RuntimeError>
+{
+    let debugger = Debugger {
+        src_name: &unit_fun.src_name,
+        src_lines: fs::read_to_string(&unit_fun.src_name)
+            .map(|src| src.lines().map(|line| line.to_string()).collect())
+            .unwrap_or_default(),
+    };
+
+    vm.set_debugging(true);
+    vm.step(StepMode::Into);
+    let mut status = vm.eval(unit_fun)?;
+
+    loop {
+        match status {
+            EvalStatus::Done(val) => {
+                writeln!(out, "program returned {}", format_value(val)).unwrap();
+                return Ok(());
+            }
+            _ => {
+                debugger.show_location(vm, out);
+
+                if !debugger.command_loop(vm, input, out) {
+                    vm.abort();
+                    writeln!(out, "program stopped").unwrap();
+                    return Ok(());
+                }
+
+                status = vm.resume()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+
+    /// Run a script in the debugger with a list of commands
+    fn debug(src: &str, commands: &str) -> String
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let mut out = Vec::new();
+        debug_unit(&mut vm, &unit_fn, &mut commands.as_bytes(), &mut out).unwrap();
+        assert_eq!(vm.stack_size(), 0);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn session()
+    {
+        let src = "fun f(n) {\n  let m = n * 2;\n  return m + 1;\n}\nlet a = f(1);\nlet b = f(a);\nreturn a + b;\n";
+        let out = debug(src, "b 3\nc\np n\nlocals\nbt\nd src:3\nfinish\nn\nn\nc\n");
+
+        let expected = [
+            "stopped in src at src:5",
+            "(debug) breakpoint at src:3",
+            "(debug) stopped in f at src:3",
+            "(debug) n = 1",
+            "(debug) n = 1",
+            "m = 2",
+            "(debug) #0 f at src:3",
+            "#1 src at src:5",
+            "(debug) (debug) stopped in src at src:5",
+            "(debug) stopped in src at src:6",
+            "(debug) stopped in src at src:7",
+            "(debug) program returned 10",
+        ];
+
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn quit()
+    {
+        let out = debug("let s = 'a';\ndebugger;\nreturn 1;", "c\np s\np t\nfoo\nq\n");
+        assert!(out.contains("stopped in src at src:2"));
+        assert!(out.contains("s = \"a\""));
+        assert!(out.contains("no variable named t in scope"));
+        assert!(out.contains("unknown command"));
+        assert!(out.ends_with("program stopped\n"));
+
+        // The end of the input stops the program too
+        let out = debug("return 1;", "");
+        assert!(out.ends_with("program stopped\n"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use crate::vm::*;
+use crate::bytecode::list_funs;
+use crate::optimizer::branch_targets;
+
+/// Disassemble a unit function and all the functions it refers to
+pub fn disasm_unit(unit_fun: &Function) -> String
+{
+    let funs = list_funs(unit_fun);
+    let fun_idxs: HashMap<*const Function, usize> = funs.iter().enumerate().map(|(idx, fun_ptr)| (*fun_ptr, idx)).collect();
+
+    let mut out = String::new();
+
+    for (idx, fun_ptr) in funs.iter().enumerate() {
+        if idx > 0 {
+            out.push('\n');
+        }
+        disasm_fun(&mut out, unsafe { &**fun_ptr }, idx, &fun_idxs);
+    }
+
+    out
+}
+
+/// Disassemble one function, with branch targets shown as labels
+/// Functions are referred to by their index, e.g. fun#2
+fn disasm_fun(out: &mut String, fun: &Function, fun_idx: usize, fun_idxs: &HashMap<*const Function, usize>)
+{
+    // Number the labels in the order they appear
+    let is_target = branch_targets(fun);
+    let mut labels = HashMap::new();
+    for (insn_idx, _) in is_target.iter().enumerate().filter(|(_, is_target)| **is_target) {
+        labels.insert(insn_idx, labels.len());
+    }
+
+    let label = |insn_idx: usize, offset: isize| {
+        format!("L{}", labels[&((insn_idx as isize + 1 + offset) as usize)])
+    };
+
+    let params: Vec<String> = fun.params.iter().enumerate().map(|(idx, param)| {
+        if fun.has_rest && idx == fun.params.len() - 1 { format!("...{}", param) } else { param.clone() }
+    }).collect();
+
+    let kind = if fun.is_generator { ", generator" } else { "" };
+    writeln!(out, "fun#{} {}({}), {} locals{}", fun_idx, fun.name, params.join(", "), fun.num_locals, kind).unwrap();
+
+    // Functions with default parameter values have several entry points
+    if fun.entry_idxs.len() > 1 {
+        let entries: Vec<String> = fun.entry_idxs.iter().map(|idx| format!("L{}", labels[idx])).collect();
+        writeln!(out, "entry points: {}", entries.join(", ")).unwrap();
+    }
+
+    let mut last_line = None;
+
+    for (insn_idx, insn) in fun.insns.iter().enumerate() {
+        if let Some(label_no) = labels.get(&insn_idx) {
+            writeln!(out, "L{}:", label_no).unwrap();
+        }
+
+        // Only show the source line where it changes
+        let line_no = fun.insn_lines.get(insn_idx).copied();
+        let line_str = match line_no {
+            Some(line_no) if line_no != last_line.unwrap_or(0) => format!("line {}", line_no),
+            _ => String::new()
+        };
+        last_line = line_no;
+
+        let local = |idx: usize| match fun.params.get(idx) {
+            Some(name) => format!("{} ({})", idx, name),
+            None => idx.to_string()
+        };
+
+        use Insn::*;
+        let insn_str = match *insn {
+            GetLocal { idx } => format!("GetLocal {}", local(idx)),
+            SetLocal { idx } => format!("SetLocal {}", local(idx)),
+            Push { val } => format!("Push {}", const_str(val, fun_idxs)),
+            IterNext { offset } => format!("IterNext {}", label(insn_idx, offset)),
+            Jump { offset } => format!("Jump {}", label(insn_idx, offset)),
+            IfTrue { offset } => format!("IfTrue {}", label(insn_idx, offset)),
+            IfFalse { offset } => format!("IfFalse {}", label(insn_idx, offset)),
+            JumpTable { table_idx } => {
+                let table = &fun.jump_tables[table_idx];
+                let targets: Vec<String> = table.offsets.iter().map(|offset| label(insn_idx, *offset)).collect();
+                format!(
+                    "JumpTable {}.. [{}] else {}",
+                    table.min_val,
+                    targets.join(", "),
+                    label(insn_idx, table.default_offset)
+                )
+            }
+            Call { argc } => format!("Call {}", argc),
+            TailCall { argc } => format!("TailCall {}", argc),
+            AddConst { val } => format!("AddConst {}", val),
+            AddLocalConst { idx, val } => format!("AddLocalConst {}, {}", local(idx as usize), val),
+            JumpIfLocalGeConst { idx, val, offset } => {
+                format!("JumpIfLocalGeConst {}, {}, {}", local(idx as usize), val, label(insn_idx, offset as isize))
+            }
+            _ => format!("{:?}", insn)
+        };
+
+        writeln!(out, "  {:>4}  {:<10} {}", insn_idx, line_str, insn_str).unwrap();
+    }
+
+    // Branches can target the end of the function
+    if let Some(label_no) = labels.get(&fun.insns.len()) {
+        writeln!(out, "L{}:", label_no).unwrap();
+    }
+}
+
+/// Format a constant, showing strings inline
+fn const_str(val: Value, fun_idxs: &HashMap<*const Function, usize>) -> String
+{
+    match val.kind() {
+        ValueKind::Int64(v) => v.to_string(),
+        ValueKind::Nil => "nil".to_string(),
+        ValueKind::Str(str_ptr) => format!("{:?}", unsafe { &*str_ptr }),
+        ValueKind::Fun(fun_ptr) => {
+            let fun = unsafe { &*fun_ptr };
+            format!("fun#{} {}", fun_idxs[&(fun_ptr as *const Function)], fun.name)
+        }
+        ValueKind::HostFn(host_fn) => {
+            format!("host {}", host_fn.name())
+        }
+        kind => format!("{:?}", kind)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+
+    fn disasm(src: &str) -> String
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        disasm_unit(&unit_fn)
+    }
+
+    #[test]
+    fn labels_and_lines()
+    {
+        let out = disasm("let s = 'a';\nwhile (s != 'aaa') {\n  s = s + 'a';\n}\nprintln(s);");
+
+        let expected = [
+            "fun#0 src(), 1 locals",
+            "     0  line 1     Push \"a\"",
+            "     1             SetLocal 0",
+            "L0:",
+            "     2  line 2     GetLocal 0",
+            "     3             Push \"aaa\"",
+            "     4             Ne",
+            "     5             IfFalse L1",
+            "     6  line 3     GetLocal 0",
+            "     7             Push \"a\"",
+            "     8             Add",
+            "     9             SetLocal 0",
+            "    10  line 2     Jump L0",
+            "L1:",
+            "    11  line 5     Push host println",
+            "    12             GetLocal 0",
+            "    13             Call 1",
+            "    14             Pop",
+            "    15             Push nil",
+            "    16             Return",
+        ];
+
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn functions()
+    {
+        let out = disasm("fun f(a, b = 2, ...rest) { return f(a); }\nfun g() { yield 1; }\nfor (x in g()) f(x);");
+
+        assert!(out.contains("fun#1 g(), 0 locals, generator"));
+        assert!(out.contains("fun#2 f(a, b, ...rest), 3 locals"));
+        assert!(out.contains("entry points: L0, L1"));
+        assert!(out.contains("Push fun#2 f"));
+        assert!(out.contains("GetLocal 0 (a)"));
+        assert!(out.contains("TailCall 1"));
+
+        let out = disasm("let x = 2; match (x) { 0 => x = 1; 1 => x = 2; 2 => x = 3; }");
+        assert!(out.contains("JumpTable 0.. [L0, L1, L2] else L3"));
+    }
+}
@@ -0,0 +1,901 @@
+use std::any::Any;
+use std::sync::atomic::AtomicBool;
+use crate::vm::*;
+
+/// Returned by helpers when the machine code can keep running
+pub const JIT_CONTINUE: u64 = u64::MAX;
+
+/// Returned by helpers when a branch is taken
+pub const JIT_BRANCH: u64 = u64::MAX - 1;
+
+/// Returned when a helper panicked, the panic is resumed by the VM
+pub const JIT_PANIC: u64 = u64::MAX - 2;
+
+/// State shared between machine code and the VM
+/// The machine code accesses the fields by offset
+#[repr(C)]
+pub struct JitCtx
+{
+    /// Address of the first local of the current frame
+    /// Reloaded after calling a helper, since the stack may have moved
+    pub base: *mut Value,
+
+    /// Flag checked on loop back-edges to stop execution
+    pub interrupt: *const AtomicBool,
+
+    pub vm: *mut VM,
+
+    /// Panic raised inside of a helper, which can't
+    /// unwind through the machine code
+    pub panic: Option<Box<dyn Any + Send>>,
+}
+
+/// Machine code compiled from the instructions of a function
+/// Execution can enter at any instruction and leaves the machine code
+/// at instructions it can't handle, returning the index of the
+/// instruction for the interpreter to continue at. Values are kept
+/// in the VM stack, so the interpreter can pick up where it left off.
+pub struct JitCode
+{
+    /// Executable memory
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mem: *mut u8,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mem_size: usize,
+
+    /// Offset of the code of each instruction
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    insn_offsets: Vec<usize>,
+
+    /// Stack depth before each instruction, above the locals
+    pub depths: Vec<Option<usize>>,
+
+    /// Maximum stack depth the code can reach
+    pub max_depth: usize,
+}
+
+/// Compile the instructions of a function into machine code
+/// Returns None, since machine code can't be generated on this platform
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub fn compile(_fun: &Function) -> Option<JitCode>
+{
+    None
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+impl JitCode
+{
+    /// Can't be called, since no code is compiled on this platform
+    pub fn run(&self, _ctx: &mut JitCtx, _insn_idx: usize) -> u64
+    {
+        unreachable!()
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use x86_64::compile;
+
+/// Code generation for x86-64 Linux, using the System V calling
+/// convention and executable memory mapped with mmap
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod x86_64
+{
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use crate::regvm::stack_depths;
+    use super::*;
+
+    const CTX_BASE: i32 = 0;
+    const CTX_INTERRUPT: i32 = 8;
+
+    impl JitCode
+    {
+        /// Run the machine code starting at the given instruction
+        /// Returns the index of the instruction to continue at, or JIT_PANIC
+        pub fn run(&self, ctx: &mut JitCtx, insn_idx: usize) -> u64
+        {
+            unsafe {
+                let entry: extern "sysv64" fn(*mut JitCtx, *const u8) -> u64 = std::mem::transmute(self.mem);
+                entry(ctx, self.mem.add(self.insn_offsets[insn_idx]))
+            }
+        }
+    }
+
+    impl Drop for JitCode
+    {
+        fn drop(&mut self)
+        {
+            unsafe { sys::munmap(self.mem as *mut _, self.mem_size) };
+        }
+    }
+
+    /// Memory mapping functions from libc
+    mod sys
+    {
+        use std::ffi::c_void;
+
+        pub const PROT_READ: i32 = 1;
+        pub const PROT_WRITE: i32 = 2;
+        pub const PROT_EXEC: i32 = 4;
+        pub const MAP_PRIVATE: i32 = 2;
+        pub const MAP_ANONYMOUS: i32 = 0x20;
+
+        extern "C" {
+            pub fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+            pub fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+            pub fn munmap(addr: *mut c_void, len: usize) -> i32;
+        }
+    }
+
+    /// Copy machine code into executable memory
+    fn map_code(code: &[u8]) -> Option<(*mut u8, usize)>
+    {
+        let size = code.len().next_multiple_of(4096);
+
+        unsafe {
+            let mem = sys::mmap(
+                std::ptr::null_mut(),
+                size,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_PRIVATE | sys::MAP_ANONYMOUS,
+                -1,
+                0
+            );
+
+            // mmap returns MAP_FAILED, which is -1
+            if mem as isize == -1 {
+                return None;
+            }
+
+            std::ptr::copy_nonoverlapping(code.as_ptr(), mem as *mut u8, code.len());
+
+            if sys::mprotect(mem, size, sys::PROT_READ | sys::PROT_EXEC) != 0 {
+                sys::munmap(mem, size);
+                return None;
+            }
+
+            Some((mem as *mut u8, size))
+        }
+    }
+
+    /// Call a VM helper, catching panics so they don't unwind through the machine code
+    fn call_helper(ctx: *mut JitCtx, f: impl FnOnce(&mut VM) -> u64) -> u64
+    {
+        let ctx = unsafe { &mut *ctx };
+        let vm = unsafe { &mut *ctx.vm };
+
+        let ret = match catch_unwind(AssertUnwindSafe(|| f(vm))) {
+            Ok(ret) => ret,
+            Err(payload) => {
+                ctx.panic = Some(payload);
+                JIT_PANIC
+            }
+        };
+
+        ctx.base = vm.jit_frame_base();
+        ret
+    }
+
+    extern "sysv64" fn binop_helper(ctx: *mut JitCtx, insn_idx: u64, depth: u64) -> u64
+    {
+        call_helper(ctx, |vm| vm.jit_binop(insn_idx as usize, depth as usize))
+    }
+
+    extern "sysv64" fn call_host_helper(ctx: *mut JitCtx, insn_idx: u64, depth: u64) -> u64
+    {
+        call_helper(ctx, |vm| vm.jit_call_host(insn_idx as usize, depth as usize))
+    }
+
+    extern "sysv64" fn iter_next_helper(ctx: *mut JitCtx, insn_idx: u64, depth: u64) -> u64
+    {
+        call_helper(ctx, |vm| vm.jit_iter_next(insn_idx as usize, depth as usize))
+    }
+
+    // Registers
+    const RAX: u8 = 0;
+    const RCX: u8 = 1;
+    const RDX: u8 = 2;
+    const RBX: u8 = 3;
+    const RSI: u8 = 6;
+    const RDI: u8 = 7;
+    const R12: u8 = 12;
+    const R13: u8 = 13;
+
+    // Condition codes
+    const CC_O: u8 = 0x0;
+    const CC_E: u8 = 0x4;
+    const CC_NE: u8 = 0x5;
+    const CC_L: u8 = 0xC;
+    const CC_GE: u8 = 0xD;
+    const CC_G: u8 = 0xF;
+
+    // Opcodes of register to register operations
+    const OP_ADD: u8 = 0x01;
+    const OP_OR: u8 = 0x09;
+    const OP_SUB: u8 = 0x29;
+    const OP_XOR: u8 = 0x31;
+    const OP_CMP: u8 = 0x39;
+    const OP_TEST: u8 = 0x85;
+    const OP_MOV: u8 = 0x89;
+
+    /// Minimal x86-64 assembler, with the instructions the JIT needs
+    #[derive(Default)]
+    struct Assembler
+    {
+        code: Vec<u8>,
+
+        /// Position of each label once placed
+        labels: Vec<Option<usize>>,
+
+        /// Positions of 32-bit relative offsets and the labels they refer to
+        fixups: Vec<(usize, usize)>,
+    }
+
+    impl Assembler
+    {
+        fn new_label(&mut self) -> usize
+        {
+            self.labels.push(None);
+            self.labels.len() - 1
+        }
+
+        fn place(&mut self, label: usize)
+        {
+            assert!(self.labels[label].is_none());
+            self.labels[label] = Some(self.code.len());
+        }
+
+        fn bytes(&mut self, bytes: &[u8])
+        {
+            self.code.extend_from_slice(bytes);
+        }
+
+        fn rel32(&mut self, label: usize)
+        {
+            self.fixups.push((self.code.len(), label));
+            self.bytes(&[0; 4]);
+        }
+
+        /// REX prefix for 64-bit operands
+        fn rex_w(&mut self, reg: u8, rm: u8)
+        {
+            self.bytes(&[0x48 | ((reg >> 3) << 2) | (rm >> 3)]);
+        }
+
+        /// ModRM byte for a register operand
+        fn modrm_reg(&mut self, reg: u8, rm: u8)
+        {
+            self.bytes(&[0xC0 | ((reg & 7) << 3) | (rm & 7)]);
+        }
+
+        /// ModRM byte for a [base + disp32] memory operand
+        fn modrm_mem(&mut self, reg: u8, base: u8, disp: i32)
+        {
+            self.bytes(&[0x80 | ((reg & 7) << 3) | (base & 7)]);
+
+            // rsp and r12 as a base need a SIB byte
+            if base & 7 == 4 {
+                self.bytes(&[0x24]);
+            }
+
+            self.bytes(&disp.to_le_bytes());
+        }
+
+        /// mov dst, [base + disp]
+        fn load(&mut self, dst: u8, base: u8, disp: i32)
+        {
+            self.rex_w(dst, base);
+            self.bytes(&[0x8B]);
+            self.modrm_mem(dst, base, disp);
+        }
+
+        /// mov [base + disp], src
+        fn store(&mut self, base: u8, disp: i32, src: u8)
+        {
+            self.rex_w(src, base);
+            self.bytes(&[0x89]);
+            self.modrm_mem(src, base, disp);
+        }
+
+        /// Register to register operation, op dst, src
+        fn alu(&mut self, op: u8, dst: u8, src: u8)
+        {
+            self.rex_w(src, dst);
+            self.bytes(&[op]);
+            self.modrm_reg(src, dst);
+        }
+
+        /// mov dst, imm64
+        fn mov_imm(&mut self, dst: u8, imm: u64)
+        {
+            self.bytes(&[0x48 | (dst >> 3), 0xB8 + (dst & 7)]);
+            self.bytes(&imm.to_le_bytes());
+        }
+
+        /// cmp reg, imm32 (sign-extended)
+        fn cmp_imm(&mut self, reg: u8, imm: i32)
+        {
+            self.rex_w(0, reg);
+            self.bytes(&[0x81]);
+            self.modrm_reg(7, reg);
+            self.bytes(&imm.to_le_bytes());
+        }
+
+        /// and reg, imm8 (sign-extended)
+        fn and_imm(&mut self, reg: u8, imm: i8)
+        {
+            self.rex_w(0, reg);
+            self.bytes(&[0x83]);
+            self.modrm_reg(4, reg);
+            self.bytes(&[imm as u8]);
+        }
+
+        /// test reg32, imm32, for the low registers
+        fn test_imm(&mut self, reg: u8, imm: u32)
+        {
+            self.bytes(&[0xF7]);
+            self.modrm_reg(0, reg);
+            self.bytes(&imm.to_le_bytes());
+        }
+
+        /// imul dst, src
+        fn imul(&mut self, dst: u8, src: u8)
+        {
+            self.rex_w(dst, src);
+            self.bytes(&[0x0F, 0xAF]);
+            self.modrm_reg(dst, src);
+        }
+
+        /// shl reg, imm8
+        fn shl(&mut self, reg: u8, imm: u8)
+        {
+            self.rex_w(0, reg);
+            self.bytes(&[0xC1]);
+            self.modrm_reg(4, reg);
+            self.bytes(&[imm]);
+        }
+
+        /// sar reg, imm8
+        fn sar(&mut self, reg: u8, imm: u8)
+        {
+            self.rex_w(0, reg);
+            self.bytes(&[0xC1]);
+            self.modrm_reg(7, reg);
+            self.bytes(&[imm]);
+        }
+
+        /// neg reg
+        fn neg(&mut self, reg: u8)
+        {
+            self.rex_w(0, reg);
+            self.bytes(&[0xF7]);
+            self.modrm_reg(3, reg);
+        }
+
+        /// Sign-extend rax into rdx, then divide rdx:rax by src
+        fn idiv(&mut self, src: u8)
+        {
+            self.bytes(&[0x48, 0x99]);
+            self.rex_w(0, src);
+            self.bytes(&[0xF7]);
+            self.modrm_reg(7, src);
+        }
+
+        /// Set rax to 1 if the condition holds, 0 otherwise
+        fn setcc_rax(&mut self, cc: u8)
+        {
+            // setcc al; movzx eax, al
+            self.bytes(&[0x0F, 0x90 | cc, 0xC0]);
+            self.bytes(&[0x0F, 0xB6, 0xC0]);
+        }
+
+        fn jmp(&mut self, label: usize)
+        {
+            self.bytes(&[0xE9]);
+            self.rel32(label);
+        }
+
+        fn jcc(&mut self, cc: u8, label: usize)
+        {
+            self.bytes(&[0x0F, 0x80 | cc]);
+            self.rel32(label);
+        }
+
+        /// jmp reg
+        fn jmp_reg(&mut self, reg: u8)
+        {
+            self.bytes(&[0xFF]);
+            self.modrm_reg(4, reg);
+        }
+
+        /// call reg
+        fn call_reg(&mut self, reg: u8)
+        {
+            self.bytes(&[0xFF]);
+            self.modrm_reg(2, reg);
+        }
+
+        fn push(&mut self, reg: u8)
+        {
+            if reg >= 8 {
+                self.bytes(&[0x41]);
+            }
+            self.bytes(&[0x50 + (reg & 7)]);
+        }
+
+        fn pop(&mut self, reg: u8)
+        {
+            if reg >= 8 {
+                self.bytes(&[0x41]);
+            }
+            self.bytes(&[0x58 + (reg & 7)]);
+        }
+
+        fn ret(&mut self)
+        {
+            self.bytes(&[0xC3]);
+        }
+
+        /// Resolve the label references
+        fn finish(mut self) -> Vec<u8>
+        {
+            for (pos, label) in std::mem::take(&mut self.fixups) {
+                let target = self.labels[label].expect("label not placed");
+                let rel = (target as i64 - (pos as i64 + 4)) as i32;
+                self.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+            }
+
+            self.code
+        }
+    }
+
+    /// State of the compilation of one function
+    struct JitCompiler
+    {
+        asm: Assembler,
+
+        num_locals: usize,
+
+        /// Label of the code of each instruction
+        insn_labels: Vec<usize>,
+
+        /// Labels of the stubs returning to the interpreter
+        /// at each instruction, created when needed
+        exit_labels: Vec<Option<usize>>,
+
+        /// Stubs checking for interrupts before a loop back-edge
+        /// goes to its target, with the target instruction index
+        back_edges: Vec<(usize, usize)>,
+
+        /// Code restoring the registers and returning
+        epilogue: usize,
+    }
+
+    impl JitCompiler
+    {
+        fn local(&self, idx: usize) -> i32
+        {
+            (idx * 8) as i32
+        }
+
+        /// Stack slot at the given depth, above the locals
+        fn temp(&self, depth: usize) -> i32
+        {
+            ((self.num_locals + depth) * 8) as i32
+        }
+
+        /// Label returning to the interpreter at an instruction
+        fn exit(&mut self, insn_idx: usize) -> usize
+        {
+            match self.exit_labels[insn_idx] {
+                Some(label) => label,
+                None => {
+                    let label = self.asm.new_label();
+                    self.exit_labels[insn_idx] = Some(label);
+                    label
+                }
+            }
+        }
+
+        /// Label to branch to from an instruction
+        /// Loop back-edges check for interrupts first
+        fn branch(&mut self, from_idx: usize, target_idx: usize) -> usize
+        {
+            if target_idx > from_idx {
+                return self.insn_labels[target_idx];
+            }
+
+            let label = self.asm.new_label();
+            self.back_edges.push((label, target_idx));
+            label
+        }
+
+        /// Branch to a label if a register doesn't hold an integer
+        fn guard_int(&mut self, reg: u8, label: usize)
+        {
+            self.asm.test_imm(reg, TAG_MASK as u32);
+            self.asm.jcc(CC_NE, label);
+        }
+
+        /// Branch to a label if rax and rcx don't both hold integers
+        fn guard_ints(&mut self, label: usize)
+        {
+            self.asm.alu(OP_MOV, RDX, RAX);
+            self.asm.alu(OP_OR, RDX, RCX);
+            self.guard_int(RDX, label);
+        }
+
+        /// Store a boolean from the flags into a stack slot
+        fn store_bool(&mut self, cc: u8, disp: i32)
+        {
+            self.asm.setcc_rax(cc);
+            self.asm.shl(RAX, TAG_BITS as u8);
+            self.asm.store(RBX, disp, RAX);
+        }
+
+        /// Call a helper with the instruction index and stack depth
+        /// Returns to the interpreter unless the helper returns JIT_CONTINUE
+        fn call_helper(&mut self, helper: extern "sysv64" fn(*mut JitCtx, u64, u64) -> u64, insn_idx: usize, depth: usize, branch: Option<usize>)
+        {
+            self.asm.alu(OP_MOV, RDI, R12);
+            self.asm.mov_imm(RSI, insn_idx as u64);
+            self.asm.mov_imm(RDX, depth as u64);
+            self.asm.mov_imm(RAX, helper as usize as u64);
+            self.asm.call_reg(RAX);
+
+            // The stack may have been reallocated
+            self.asm.load(RBX, R12, CTX_BASE);
+
+            let cont = self.asm.new_label();
+            self.asm.cmp_imm(RAX, JIT_CONTINUE as i64 as i32);
+            self.asm.jcc(CC_E, cont);
+
+            if let Some(branch) = branch {
+                self.asm.cmp_imm(RAX, JIT_BRANCH as i64 as i32);
+                self.asm.jcc(CC_E, branch);
+            }
+
+            // The helper returned the instruction to exit at
+            self.asm.jmp(self.epilogue);
+            self.asm.place(cont);
+        }
+
+        /// Add a constant to an integer stack slot or local
+        fn add_const(&mut self, insn_idx: usize, disp: i32, val: i64)
+        {
+            let exit = self.exit(insn_idx);
+
+            // Constants that can't be packed are left to the interpreter
+            if Value::try_int64(val).is_none() {
+                self.asm.jmp(exit);
+                return;
+            }
+
+            self.asm.load(RAX, RBX, disp);
+            self.guard_int(RAX, exit);
+            self.asm.mov_imm(RCX, (val << TAG_BITS) as u64);
+            self.asm.alu(OP_ADD, RAX, RCX);
+            self.asm.jcc(CC_O, exit);
+            self.asm.store(RBX, disp, RAX);
+        }
+
+        fn compile_insn(&mut self, insn_idx: usize, insn: Insn, depth: usize)
+        {
+            use Insn::*;
+
+            let target = |offset: isize| (insn_idx as isize + 1 + offset) as usize;
+
+            // Slots of the operands of binary operations
+            let (a, b) = (self.temp(depth.saturating_sub(2)), self.temp(depth.saturating_sub(1)));
+
+            match insn {
+                Push { val } => {
+                    self.asm.mov_imm(RAX, val.bits());
+                    self.asm.store(RBX, self.temp(depth), RAX);
+                }
+
+                Pop => {}
+
+                Dup => {
+                    self.asm.load(RAX, RBX, b);
+                    self.asm.store(RBX, self.temp(depth), RAX);
+                }
+
+                GetLocal { idx } => {
+                    self.asm.load(RAX, RBX, self.local(idx));
+                    self.asm.store(RBX, self.temp(depth), RAX);
+                }
+
+                SetLocal { idx } => {
+                    self.asm.load(RAX, RBX, b);
+                    self.asm.store(RBX, self.local(idx), RAX);
+                }
+
+                // Packed integers can be added and subtracted directly, and
+                // overflow exactly when the result doesn't fit in a value
+                Add | Sub => {
+                    let slow = self.asm.new_label();
+                    let done = self.asm.new_label();
+                    let exit = self.exit(insn_idx);
+
+                    self.asm.load(RAX, RBX, a);
+                    self.asm.load(RCX, RBX, b);
+                    self.guard_ints(if let Add = insn { slow } else { exit });
+                    self.asm.alu(if let Add = insn { OP_ADD } else { OP_SUB }, RAX, RCX);
+                    self.asm.jcc(CC_O, exit);
+                    self.asm.store(RBX, a, RAX);
+                    self.asm.jmp(done);
+
+                    // String concatenation
+                    self.asm.place(slow);
+                    if let Add = insn {
+                        self.call_helper(binop_helper, insn_idx, depth, None);
+                    }
+                    self.asm.place(done);
+                }
+
+                Mul => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.load(RAX, RBX, a);
+                    self.asm.load(RCX, RBX, b);
+                    self.guard_ints(exit);
+                    self.asm.sar(RCX, TAG_BITS as u8);
+                    self.asm.imul(RAX, RCX);
+                    self.asm.jcc(CC_O, exit);
+                    self.asm.store(RBX, a, RAX);
+                }
+
+                Mod => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.load(RAX, RBX, a);
+                    self.asm.load(RCX, RBX, b);
+                    self.guard_ints(exit);
+
+                    // Division by zero panics in the interpreter
+                    self.asm.alu(OP_TEST, RCX, RCX);
+                    self.asm.jcc(CC_E, exit);
+
+                    self.asm.sar(RAX, TAG_BITS as u8);
+                    self.asm.sar(RCX, TAG_BITS as u8);
+                    self.asm.idiv(RCX);
+                    self.asm.shl(RDX, TAG_BITS as u8);
+                    self.asm.store(RBX, a, RDX);
+                }
+
+                Neg => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.load(RAX, RBX, b);
+                    self.guard_int(RAX, exit);
+                    self.asm.neg(RAX);
+                    self.asm.jcc(CC_O, exit);
+                    self.asm.store(RBX, b, RAX);
+                }
+
+                AddConst { val } => self.add_const(insn_idx, b, val),
+
+                AddLocalConst { idx, val } => self.add_const(insn_idx, self.local(idx as usize), val as i64),
+
+                // Values with different tags are never equal, and values other
+                // than strings and boxed integers are equal if their bits are equal
+                Eq | Ne => {
+                    let slow = self.asm.new_label();
+                    let fast = self.asm.new_label();
+                    let done = self.asm.new_label();
+
+                    self.asm.load(RAX, RBX, a);
+                    self.asm.load(RCX, RBX, b);
+
+                    self.asm.alu(OP_MOV, RDX, RAX);
+                    self.asm.alu(OP_XOR, RDX, RCX);
+                    self.asm.and_imm(RDX, TAG_MASK as i8);
+                    self.asm.jcc(CC_NE, fast);
+                    self.asm.alu(OP_MOV, RDX, RAX);
+                    self.asm.and_imm(RDX, TAG_MASK as i8);
+                    self.asm.cmp_imm(RDX, TAG_STR as i32);
+                    self.asm.jcc(CC_E, slow);
+                    self.asm.cmp_imm(RDX, TAG_BOXED as i32);
+                    self.asm.jcc(CC_E, slow);
+
+                    self.asm.place(fast);
+                    self.asm.alu(OP_CMP, RAX, RCX);
+                    self.store_bool(if let Eq = insn { CC_E } else { CC_NE }, a);
+                    self.asm.jmp(done);
+
+                    // String comparison, boxed integers exit to the interpreter
+                    self.asm.place(slow);
+                    self.call_helper(binop_helper, insn_idx, depth, None);
+                    self.asm.place(done);
+                }
+
+                // Packing integers preserves their order
+                Lt | Gt => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.load(RAX, RBX, a);
+                    self.asm.load(RCX, RBX, b);
+                    self.guard_ints(exit);
+                    self.asm.alu(OP_CMP, RAX, RCX);
+                    self.store_bool(if let Lt = insn { CC_L } else { CC_G }, a);
+                }
+
+                Not => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.load(RAX, RBX, b);
+                    self.guard_int(RAX, exit);
+                    self.asm.alu(OP_TEST, RAX, RAX);
+                    self.store_bool(CC_E, b);
+                }
+
+                Jump { offset } => {
+                    let label = self.branch(insn_idx, target(offset));
+                    self.asm.jmp(label);
+                }
+
+                IfTrue { offset } | IfFalse { offset } => {
+                    let exit = self.exit(insn_idx);
+                    let label = self.branch(insn_idx, target(offset));
+                    self.asm.load(RAX, RBX, b);
+                    self.guard_int(RAX, exit);
+                    self.asm.alu(OP_TEST, RAX, RAX);
+                    self.asm.jcc(if let IfTrue { .. } = insn { CC_NE } else { CC_E }, label);
+                }
+
+                JumpIfLocalGeConst { idx, val, offset } => {
+                    let exit = self.exit(insn_idx);
+                    let label = self.branch(insn_idx, target(offset as isize));
+                    self.asm.load(RAX, RBX, self.local(idx as usize));
+                    self.guard_int(RAX, exit);
+                    self.asm.mov_imm(RCX, ((val as i64) << TAG_BITS) as u64);
+                    self.asm.alu(OP_CMP, RAX, RCX);
+                    self.asm.jcc(CC_GE, label);
+                }
+
+                IterNext { offset } => {
+                    let label = self.branch(insn_idx, target(offset));
+                    self.call_helper(iter_next_helper, insn_idx, depth, Some(label));
+                }
+
+                // Calls to script functions return to the interpreter
+                Call { .. } | TailCall { .. } => {
+                    self.call_helper(call_host_helper, insn_idx, depth, None);
+                }
+
+                // Everything else is left to the interpreter
+                _ => {
+                    let exit = self.exit(insn_idx);
+                    self.asm.jmp(exit);
+                }
+            }
+        }
+    }
+
+    /// Compile the instructions of a function into machine code
+    /// Returns None if executable memory can't be allocated
+    pub fn compile(fun: &Function) -> Option<JitCode>
+    {
+        let depths = stack_depths(fun);
+        let max_depth = depths.iter().flatten().max().copied().unwrap_or(0) + 1;
+        let num_insns = fun.insns.len();
+
+        let mut asm = Assembler::default();
+        let insn_labels = (0..num_insns).map(|_| asm.new_label()).collect();
+        let epilogue = asm.new_label();
+
+        let mut c = JitCompiler {
+            asm,
+            num_locals: fun.num_locals,
+            insn_labels,
+            exit_labels: vec![None; num_insns + 1],
+            back_edges: Vec::default(),
+            epilogue,
+        };
+
+        // Save the callee-saved registers, keeping the stack
+        // aligned for helper calls, then jump to the entry point
+        c.asm.push(RBX);
+        c.asm.push(R12);
+        c.asm.push(R13);
+        c.asm.alu(OP_MOV, R12, RDI);
+        c.asm.load(RBX, R12, CTX_BASE);
+        c.asm.jmp_reg(RSI);
+
+        let mut insn_offsets = Vec::with_capacity(num_insns);
+
+        for (insn_idx, insn) in fun.insns.iter().enumerate() {
+            insn_offsets.push(c.asm.code.len());
+            c.asm.place(c.insn_labels[insn_idx]);
+
+            // Execution can't reach this instruction
+            let Some(depth) = depths[insn_idx] else {
+                continue;
+            };
+
+            c.compile_insn(insn_idx, *insn, depth);
+        }
+
+        // The last instruction always returns or jumps, but
+        // return to the interpreter just in case
+        let end = c.exit(num_insns);
+        c.asm.jmp(end);
+
+        for (label, target_idx) in std::mem::take(&mut c.back_edges) {
+            let exit = c.exit(target_idx);
+            c.asm.place(label);
+            c.asm.load(RAX, R12, CTX_INTERRUPT);
+            c.asm.bytes(&[0x80, 0x38, 0x00]); // cmp byte [rax], 0
+            c.asm.jcc(CC_NE, exit);
+            c.asm.jmp(c.insn_labels[target_idx]);
+        }
+
+        for insn_idx in 0..=num_insns {
+            if let Some(label) = c.exit_labels[insn_idx] {
+                c.asm.place(label);
+                c.asm.mov_imm(RAX, insn_idx as u64);
+                c.asm.jmp(c.epilogue);
+            }
+        }
+
+        c.asm.place(c.epilogue);
+        c.asm.pop(R13);
+        c.asm.pop(R12);
+        c.asm.pop(RBX);
+        c.asm.ret();
+
+        let (mem, mem_size) = map_code(&c.asm.finish())?;
+
+        Some(JitCode {
+            mem,
+            mem_size,
+            insn_offsets,
+            depths,
+            max_depth,
+        })
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        #[test]
+        fn encodings()
+        {
+            let mut asm = Assembler::default();
+            asm.load(RAX, RBX, 8);
+            asm.store(RBX, 16, RCX);
+            asm.load(RBX, R12, 0);
+            asm.alu(OP_MOV, RDI, R12);
+            asm.alu(OP_ADD, RAX, RCX);
+            asm.imul(RAX, RCX);
+            asm.sar(RCX, 4);
+            asm.test_imm(RDX, 15);
+            asm.cmp_imm(RAX, -1);
+            asm.push(R12);
+            asm.pop(RBX);
+
+            assert_eq!(asm.finish(), vec![
+                0x48, 0x8B, 0x83, 8, 0, 0, 0,
+                0x48, 0x89, 0x8B, 16, 0, 0, 0,
+                0x49, 0x8B, 0x9C, 0x24, 0, 0, 0, 0,
+                0x4C, 0x89, 0xE7,
+                0x48, 0x01, 0xC8,
+                0x48, 0x0F, 0xAF, 0xC1,
+                0x48, 0xC1, 0xF9, 4,
+                0xF7, 0xC2, 15, 0, 0, 0,
+                0x48, 0x81, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF,
+                0x41, 0x54,
+                0x5B,
+            ]);
+        }
+
+        #[test]
+        fn labels()
+        {
+            let mut asm = Assembler::default();
+            let label = asm.new_label();
+            asm.jmp(label);
+            asm.ret();
+            asm.place(label);
+            asm.jcc(CC_E, label);
+            assert_eq!(asm.finish(), vec![0xE9, 1, 0, 0, 0, 0xC3, 0x0F, 0x84, 0xFA, 0xFF, 0xFF, 0xFF]);
+        }
+    }
+}
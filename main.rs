@@ -5,6 +5,7 @@
 #![allow(unused_mut)]
 
 use std::env;
+use std::path::Path;
 
 mod vm;
 use vm::*;
@@ -12,17 +13,143 @@ use vm::*;
 mod parser;
 use parser::*;
 
+mod optimizer;
+
+mod regvm;
+
+mod jit;
+
 mod runtime;
 
+mod bytecode;
+use bytecode::*;
+
+mod disasm;
+use disasm::disasm_unit;
+
+mod verifier;
+
+mod debugger;
+use debugger::debug_unit;
+
+mod profiler;
+
 fn main()
 {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     println!("{:?}", args);
 
+    // Use the register-based interpreter
+    let use_regs = args.iter().any(|arg| arg == "--regs");
+    args.retain(|arg| arg != "--regs");
+
+    // Compile hot functions into machine code
+    let use_jit = args.iter().any(|arg| arg == "--jit");
+    args.retain(|arg| arg != "--jit");
+
+    // Print the bytecode instead of running it
+    let disasm = args.iter().any(|arg| arg == "--disasm");
+    args.retain(|arg| arg != "--disasm");
+
+    // Run under the interactive debugger
+    let debug = args.iter().any(|arg| arg == "--debug");
+    args.retain(|arg| arg != "--debug");
+
+    // Print a profile of the execution to stderr
+    let profile = args.iter().any(|arg| arg == "--profile");
+    args.retain(|arg| arg != "--profile");
+
+    // Write the call stacks in the folded format for flame graphs, e.g.
+    // ksvm --profile-folded out.folded foo.ks
+    let folded_name = match args.iter().position(|arg| arg == "--profile-folded") {
+        Some(idx) => {
+            let name = args.get(idx + 1).expect("missing output file name after --profile-folded").clone();
+            args.drain(idx..idx + 2);
+            Some(name)
+        }
+        None => None
+    };
+
+    // Compile a source file into a bytecode file, e.g.
+    // ksvm compile foo.ks -o foo.ksc
+    if args.len() >= 3 && args[1] == "compile" {
+        let src_name = &args[2];
+
+        let out_name = match args.iter().position(|arg| arg == "-o") {
+            Some(idx) => args.get(idx + 1).expect("missing output file name after -o").clone(),
+            None => Path::new(src_name).with_extension("ksc").to_string_lossy().into_owned()
+        };
+
+        let mut vm = VM::new();
+        let unit_fn = parse_file(&mut vm, src_name).unwrap();
+        save_file(&unit_fn, &out_name);
+        return;
+    }
+
     // If an input file was specified
     if args.len() == 2 {
         let mut vm = VM::new();
-        let unit_fn = parse_file(&mut vm, &args[1]).unwrap();
-        vm.eval(&unit_fn);
+
+        // The debugger and profiler only support the stack interpreter
+        if debug && (use_regs || use_jit) {
+            eprintln!("--debug can't be combined with --regs or --jit");
+            std::process::exit(-1);
+        }
+        if (profile || folded_name.is_some()) && (use_regs || use_jit) {
+            eprintln!("--profile can't be combined with --regs or --jit");
+            std::process::exit(-1);
+        }
+
+        if use_regs {
+            vm.set_backend(Backend::Register);
+        }
+        else if use_jit {
+            vm.set_backend(Backend::Jit);
+        }
+
+        // Bytecode files are loaded without parsing
+        let unit_fn = if args[1].ends_with(".ksc") {
+            match load_file(&mut vm, &args[1]) {
+                Ok(unit_fn) => unit_fn,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(-1);
+                }
+            }
+        }
+        else {
+            parse_file(&mut vm, &args[1]).unwrap()
+        };
+
+        if disasm {
+            print!("{}", disasm_unit(&unit_fn));
+            return;
+        }
+
+        if profile || folded_name.is_some() {
+            vm.set_profiling(true);
+        }
+
+        let result = if debug {
+            debug_unit(&mut vm, &unit_fn, &mut std::io::stdin().lock(), &mut std::io::stdout())
+        }
+        else {
+            vm.eval(&unit_fn).map(|_| ())
+        };
+
+        // Report the profile even if the program failed
+        if let Some(profile_data) = vm.take_profile() {
+            if profile {
+                eprint!("{}", profile_data.report());
+            }
+            if let Some(folded_name) = &folded_name {
+                std::fs::write(folded_name, profile_data.folded_stacks()).unwrap();
+            }
+        }
+
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(-1);
+        }
     }
 }
@@ -0,0 +1,314 @@
+use crate::vm::*;
+
+/// Optimize a unit function and all the functions it refers to
+pub fn optimize_unit(unit_fun: &mut Function)
+{
+    unit_fun.for_each_fun(optimize);
+}
+
+/// Rewrite the instructions of a function into fused superinstructions
+pub fn optimize(fun: &mut Function)
+{
+    // Fusing instructions can expose new sequences to fuse,
+    // e.g. AddConst produces the pattern for AddLocalConst
+    while peephole_pass(fun) {}
+}
+
+/// Get the branch offset of an instruction, if it has one
+/// Offsets are relative to the next instruction
+fn branch_offset(insn: &Insn) -> Option<isize>
+{
+    use Insn::*;
+
+    match *insn {
+        Jump { offset } |
+        IfTrue { offset } |
+        IfFalse { offset } |
+        IterNext { offset } => Some(offset),
+        JumpIfLocalGeConst { offset, .. } => Some(offset as isize),
+        _ => None
+    }
+}
+
+/// Change the branch offset of an instruction
+fn set_branch_offset(insn: &mut Insn, new_offset: isize)
+{
+    use Insn::*;
+
+    match insn {
+        Jump { offset } |
+        IfTrue { offset } |
+        IfFalse { offset } |
+        IterNext { offset } => *offset = new_offset,
+        JumpIfLocalGeConst { offset, .. } => *offset = new_offset.try_into().unwrap(),
+        _ => panic!("instruction has no branch offset: {:?}", insn)
+    }
+}
+
+/// Find the instructions that execution can branch or enter at
+pub fn branch_targets(fun: &Function) -> Vec<bool>
+{
+    // Branches may target the end of the instruction list
+    let mut is_target = vec![false; fun.insns.len() + 1];
+
+    for (idx, insn) in fun.insns.iter().enumerate() {
+        if let Some(offset) = branch_offset(insn) {
+            is_target[(idx as isize + 1 + offset) as usize] = true;
+        }
+
+        if let Insn::JumpTable { table_idx } = insn {
+            let table = &fun.jump_tables[*table_idx];
+            for offset in table.offsets.iter().chain(std::iter::once(&table.default_offset)) {
+                is_target[(idx as isize + 1 + offset) as usize] = true;
+            }
+        }
+    }
+
+    for entry_idx in &fun.entry_idxs {
+        is_target[*entry_idx] = true;
+    }
+
+    is_target
+}
+
+/// Get the value of an integer constant that fits in 32 bits
+fn small_int(val: Value) -> Option<i32>
+{
+    match val.kind() {
+        ValueKind::Int64(v) => i32::try_from(v).ok(),
+        _ => None
+    }
+}
+
+/// Addition of a constant, only for constants that can be packed,
+/// so that the register compiler can load them without allocating
+fn add_const(val: Option<i64>) -> Option<(Insn, usize)>
+{
+    let val = val.filter(|v| Value::try_int64(*v).is_some())?;
+    Some((Insn::AddConst { val }, 2))
+}
+
+/// Try to fuse the instructions at the start of a slice
+/// Returns the fused instruction and the number of instructions it replaces
+fn fuse(insns: &[Insn]) -> Option<(Insn, usize)>
+{
+    use Insn::*;
+
+    match insns {
+        // Assignment used as a statement
+        [Dup, SetLocal { idx }, Pop, ..] => Some((SetLocal { idx: *idx }, 3)),
+
+        [Push { val }, Add, ..] => match val.kind() {
+            ValueKind::Int64(v) => add_const(Some(v)),
+            _ => None
+        }
+
+        [Push { val }, Sub, ..] => match val.kind() {
+            ValueKind::Int64(v) => add_const(v.checked_neg()),
+            _ => None
+        }
+
+        // Increment of a local, e.g. i = i + 1
+        [GetLocal { idx }, AddConst { val }, SetLocal { idx: idx2 }, ..] if idx == idx2 => {
+            let idx = u32::try_from(*idx).ok()?;
+            let val = i32::try_from(*val).ok()?;
+            Some((AddLocalConst { idx, val }, 3))
+        }
+
+        // Loop header, e.g. while (i < 10)
+        [GetLocal { idx }, Push { val }, Lt, IfFalse { offset }, ..] => {
+            let idx = u32::try_from(*idx).ok()?;
+            let val = small_int(*val)?;
+
+            // The fused instruction is three instructions
+            // further back, so the offset grows by 3
+            let offset = i32::try_from(*offset + 3).ok()?;
+
+            Some((JumpIfLocalGeConst { idx, val, offset }, 4))
+        }
+
+        _ => None
+    }
+}
+
+/// Run one pass of the peephole optimizer over a function
+/// Returns true if any instructions were fused
+fn peephole_pass(fun: &mut Function) -> bool
+{
+    let is_target = branch_targets(fun);
+    let insns = &fun.insns;
+
+    let mut new_insns = Vec::with_capacity(insns.len());
+
+    // New index of each old instruction, plus the end of the list
+    let mut new_idxs = Vec::with_capacity(insns.len() + 1);
+
+    // Old index of the instruction each new instruction
+    // was produced at, used to find its branch targets
+    let mut old_idxs = Vec::with_capacity(insns.len());
+
+    let mut changed = false;
+    let mut idx = 0;
+
+    while idx < insns.len() {
+        let fused = fuse(&insns[idx..]).filter(|(_, len)| {
+            // Instructions after the first can't be fused if
+            // execution can branch into the middle of the sequence
+            !is_target[idx + 1 .. idx + len].contains(&true)
+        });
+
+        let (insn, len) = fused.unwrap_or((insns[idx], 1));
+        changed |= len > 1;
+
+        for _ in 0..len {
+            new_idxs.push(new_insns.len());
+        }
+
+        old_idxs.push(idx);
+        new_insns.push(insn);
+        idx += len;
+    }
+
+    if !changed {
+        return false;
+    }
+
+    new_idxs.push(new_insns.len());
+
+    // Compute a new offset for a branch from an old instruction
+    let remap = |old_idx: usize, new_idx: usize, offset: isize| {
+        let old_target = (old_idx as isize + 1 + offset) as usize;
+        new_idxs[old_target] as isize - (new_idx as isize + 1)
+    };
+
+    for (new_idx, insn) in new_insns.iter_mut().enumerate() {
+        let old_idx = old_idxs[new_idx];
+
+        if let Some(offset) = branch_offset(insn) {
+            set_branch_offset(insn, remap(old_idx, new_idx, offset));
+        }
+
+        if let Insn::JumpTable { table_idx } = insn {
+            let table = &mut fun.jump_tables[*table_idx];
+            for offset in table.offsets.iter_mut() {
+                *offset = remap(old_idx, new_idx, *offset);
+            }
+            table.default_offset = remap(old_idx, new_idx, table.default_offset);
+        }
+    }
+
+    for entry_idx in fun.entry_idxs.iter_mut() {
+        *entry_idx = new_idxs[*entry_idx];
+    }
+
+    for var in fun.local_vars.iter_mut() {
+        var.start = new_idxs[var.start];
+        var.end = new_idxs[var.end];
+    }
+
+    // Fused instructions take the line of the first one they replace
+    if !fun.insn_lines.is_empty() {
+        fun.insn_lines = old_idxs.iter().map(|old_idx| fun.insn_lines[*old_idx]).collect();
+    }
+
+    fun.insns = new_insns;
+    true
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+    use Insn::*;
+
+    fn eval(src: &str) -> Value
+    {
+        dbg!(src);
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        match vm.eval(&unit_fn).unwrap() {
+            EvalStatus::Done(val) => val,
+            status => panic!("unexpected status {:?}", status)
+        }
+    }
+
+    fn count_insns(fun: &Function, pred: fn(&Insn) -> bool) -> usize
+    {
+        fun.insns.iter().filter(|insn| pred(insn)).count()
+    }
+
+    #[test]
+    fn superinsns()
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "let i = 0; let s = 0; while (i < 10) { s = s + i; i = i + 1; }").unwrap();
+
+        assert_eq!(count_insns(&unit_fn, |insn| matches!(insn, Dup)), 0);
+        assert_eq!(count_insns(&unit_fn, |insn| matches!(insn, AddLocalConst { idx: 0, val: 1 })), 1);
+        assert_eq!(count_insns(&unit_fn, |insn| matches!(insn, JumpIfLocalGeConst { idx: 0, val: 10, .. })), 1);
+
+        // Nested functions are optimized too
+        let unit_fn = parse_str(&mut vm, "fun f(n) { return n - 7; } return f(9);").unwrap();
+        let fun_ptr = unit_fn.insns.iter().find_map(|insn| match insn {
+            Push { val } => match val.kind() {
+                ValueKind::Fun(fun_ptr) => Some(fun_ptr),
+                _ => None
+            }
+            _ => None
+        }).unwrap();
+        let fun = unsafe { &*fun_ptr };
+        assert_eq!(count_insns(fun, |insn| matches!(insn, AddConst { val: -7 })), 1);
+    }
+
+    #[test]
+    fn branch_into_sequence()
+    {
+        // The Pop is a branch target, so the sequence can't be fused
+        let mut fun = Function::new("f");
+        fun.insns = vec![
+            Push { val: Value::Int64(1) },
+            IfTrue { offset: 3 },
+            Push { val: Value::Int64(2) },
+            Dup,
+            SetLocal { idx: 0 },
+            Pop,
+        ];
+        optimize(&mut fun);
+        assert_eq!(fun.insns.len(), 6);
+
+        // Offsets are adjusted around fused instructions
+        let mut fun = Function::new("f");
+        fun.insns = vec![
+            Jump { offset: 4 },
+            Push { val: Value::Int64(1) },
+            Dup,
+            SetLocal { idx: 0 },
+            Pop,
+            Jump { offset: -5 },
+        ];
+        optimize(&mut fun);
+        assert_eq!(fun.insns.len(), 4);
+        assert!(matches!(fun.insns[0], Jump { offset: 2 }));
+        assert!(matches!(fun.insns[3], Jump { offset: -3 }));
+    }
+
+    #[test]
+    fn jump_fixups()
+    {
+        assert_eq!(eval("let s = 0; let i = 0; while (i < 100) { if (i % 2 == 0) s = s + i; else s = s - 1; i = i + 1; } return s;"), ValueKind::Int64(2400));
+        assert_eq!(eval("let i = 0; while (i < 5) { let j = 0; while (j < i) j = j + 1; i = i + 1; } return i;"), ValueKind::Int64(5));
+        assert_eq!(eval("let s = 0; for (i in 0..10) { s = s + i; s = s - 1; } return s;"), ValueKind::Int64(35));
+        assert_eq!(eval("let s = 0; for (i in 0..6) match (i) { 0 => s = s + 1; 1 => s = s + 10; 2 => s = s + 100; _ => s = s - 1; } return s;"), ValueKind::Int64(108));
+        assert_eq!(eval("fun f(a, b = a + 1, c = b + 1) { return a + b + c; } return f(1) + f(1, 5) + f(1, 5, 9);"), ValueKind::Int64(6 + 12 + 15));
+        assert_eq!(eval("fun g(n) { let i = 0; while (i < n) { yield i; i = i + 1; } } let s = 0; for (x in g(5)) s = s + x; return s;"), ValueKind::Int64(10));
+        assert_eq!(eval("fun f(n, acc) { if (n < 1) return acc; return f(n - 1, acc + n); } return f(100, 0);"), ValueKind::Int64(5050));
+    }
+
+    #[test]
+    fn insn_size()
+    {
+        // Superinstructions shouldn't make every instruction bigger
+        assert_eq!(std::mem::size_of::<Insn>(), 16);
+    }
+}
@@ -6,6 +6,9 @@ use std::fmt;
 use std::cmp::max;
 use crate::vm::*;
 use crate::runtime::get_runtime_fn;
+use crate::optimizer::optimize_unit;
+use crate::regvm::compile_unit;
+use crate::verifier::verify_unit;
 
 #[derive(Debug)]
 pub struct ParseError
@@ -57,6 +60,10 @@ pub struct Input
 
     // Current column number
     col_no : u32,
+
+    // Line of the statement being parsed, which
+    // the instructions it emits are attributed to
+    stmt_line: u32,
 }
 
 impl Input
@@ -68,14 +75,15 @@ impl Input
             src_name: src_name.to_string(),
             pos: 0,
             line_no: 1,
-            col_no: 1
+            col_no: 1,
+            stmt_line: 1,
         }
     }
 
     /// Test if the end of the input has been reached
     pub fn eof(&self) -> bool
     {
-        return self.pos >= self.input_str.len();
+        self.pos >= self.input_str.len()
     }
 
     /// Peek at a character from the input
@@ -86,7 +94,7 @@ impl Input
             return '\0';
         }
 
-        return self.input_str[self.pos];
+        self.input_str[self.pos]
     }
 
     /// Consume a character from the input
@@ -107,7 +115,7 @@ impl Input
             self.col_no += 1;
         }
 
-        return ch;
+        ch
     }
 
     /// Consume whitespace
@@ -159,10 +167,8 @@ impl Input
         }
 
         // Compare the characters to match
-        for i in 0..chars.len() {
-            if chars[i] != self.input_str[self.pos + i] {
-                return false;
-            }
+        if self.input_str[self.pos..end_pos] != *chars {
+            return false;
         }
 
         // Consumed the matched characters
@@ -170,7 +176,7 @@ impl Input
             self.eat_ch();
         }
 
-        return true;
+        true
     }
 
     /// Match a string in the input, ignoring preceding whitespace
@@ -182,7 +188,7 @@ impl Input
         self.eat_ws();
 
         let token_chars: Vec<char> = token.chars().collect();
-        return self.match_chars(&token_chars);
+        self.match_chars(&token_chars)
     }
 
     /// Match a keyword in the input, ignoring preceding whitespace
@@ -201,7 +207,7 @@ impl Input
             return false;
         }
 
-        return self.match_chars(&chars);
+        self.match_chars(&chars)
     }
 
     /// Shortcut for yielding a parse error wrapped in a result type
@@ -249,11 +255,14 @@ impl Input
                 break
             }
 
-            int_val = 10 * int_val + digit.unwrap() as i64;
+            int_val = match int_val.checked_mul(10).and_then(|v| v.checked_add(digit.unwrap() as i64)) {
+                Some(v) => v,
+                None => return self.parse_error("integer literal too large")
+            };
             self.eat_ch();
         }
 
-        return Ok(int_val);
+        Ok(int_val)
     }
 
     /// Parse a string literal
@@ -290,7 +299,7 @@ impl Input
             out.push(ch);
         }
 
-        return Ok(out);
+        Ok(out)
     }
 
     /// Parse a C-style alphanumeric identifier
@@ -318,7 +327,7 @@ impl Input
             self.eat_ch();
         }
 
-        return Ok(ident);
+        Ok(ident)
     }
 }
 
@@ -327,6 +336,9 @@ struct Scope
     /// Map of variables to local indices
     vars: HashMap<String, usize>,
 
+    /// Map of named functions declared in this scope
+    funs: HashMap<String, Value>,
+
     /// Function this scope resides in
     fun: *mut Function,
 
@@ -335,6 +347,10 @@ struct Scope
 
     /// Next local idx to assign
     next_idx: usize,
+
+    /// Entries of the function's local variable list for the
+    /// variables declared in this scope, which end with it
+    var_idxs: Vec<usize>,
 }
 
 impl Scope
@@ -343,9 +359,25 @@ impl Scope
     {
         Scope {
             vars: HashMap::default(),
+            funs: HashMap::default(),
             fun: fun as *mut Function,
             parent: None,
             next_idx: 0,
+            var_idxs: Vec::default(),
+        }
+    }
+
+    /// Create the outermost scope of a function nested in another scope
+    /// Local variables of the parent are not visible, but named functions are
+    fn new_fun(fun: &mut Function, parent: &mut Scope) -> Scope
+    {
+        Scope {
+            vars: HashMap::default(),
+            funs: HashMap::default(),
+            fun: fun as *mut Function,
+            parent: Some(parent as *mut Scope),
+            next_idx: 0,
+            var_idxs: Vec::default(),
         }
     }
 
@@ -354,9 +386,20 @@ impl Scope
     {
         Scope {
             vars: HashMap::default(),
+            funs: HashMap::default(),
             fun: parent.fun,
             parent: Some(parent as *mut Scope),
             next_idx: parent.next_idx,
+            var_idxs: Vec::default(),
+        }
+    }
+
+    /// End the scope, variables go out of scope after
+    /// the last instruction emitted in it
+    fn end(self, fun: &mut Function)
+    {
+        for var_idx in &self.var_idxs {
+            fun.local_vars[*var_idx].end = fun.insns.len();
         }
     }
 
@@ -364,7 +407,7 @@ impl Scope
     fn decl_var(&mut self, ident: &str) -> Option<usize>
     {
         // Can't declare a variable twice in the same scope
-        if let Some(local_idx) = self.vars.get(ident) {
+        if self.vars.contains_key(ident) || self.funs.contains_key(ident) {
             return None;
         }
 
@@ -376,27 +419,160 @@ impl Scope
         let mut fun = unsafe { &mut *self.fun };
         fun.num_locals = max(fun.num_locals, local_idx + 1);
 
-        return Some(local_idx);
+        // The variable is visible from the next instruction
+        self.var_idxs.push(fun.local_vars.len());
+        fun.local_vars.push(LocalVar {
+            name: ident.to_string(),
+            idx: local_idx,
+            start: fun.insns.len(),
+            end: fun.insns.len(),
+        });
+
+        Some(local_idx)
+    }
+
+    /// Declare a named function
+    fn decl_fun(&mut self, ident: &str, fun_val: Value) -> bool
+    {
+        if self.vars.contains_key(ident) || self.funs.contains_key(ident) {
+            return false;
+        }
+
+        self.funs.insert(ident.to_string(), fun_val);
+        true
     }
 
     /// Look up a variable by name
     fn lookup(&self, ident: &str) -> Option<usize>
     {
         if let Some(idx) = self.vars.get(ident) {
-            return Some(*idx);
+            Some(*idx)
         }
         else
         {
             if let Some(parent_ptr) = self.parent {
                 let parent = unsafe { &*parent_ptr };
-                return parent.lookup(ident);
+
+                // Stop at the function boundary
+                if parent.fun != self.fun {
+                    return None;
+                }
+
+                parent.lookup(ident)
             }
             else
             {
-                return None;
+                None
             }
         }
     }
+
+    /// Look up a named function, including in enclosing functions
+    fn lookup_fun(&self, ident: &str) -> Option<Value>
+    {
+        if let Some(fun_val) = self.funs.get(ident) {
+            return Some(*fun_val);
+        }
+
+        if let Some(parent_ptr) = self.parent {
+            let parent = unsafe { &*parent_ptr };
+            return parent.lookup_fun(ident);
+        }
+
+        None
+    }
+}
+
+/// Parse the parameter list and body of a function
+/// The function object is allocated by the caller so that
+/// it can be declared before its body is parsed
+fn parse_fun(vm: &mut VM, input: &mut Input, fun_val: Value, scope: &mut Scope) -> Result<(), ParseError>
+{
+    let new_fun = match fun_val.kind() {
+        ValueKind::Fun(fun_ptr) => unsafe { &mut *fun_ptr },
+        _ => panic!()
+    };
+    new_fun.src_name = input.src_name.clone();
+    let mut scope = Scope::new_fun(new_fun, scope);
+
+    input.expect_token("(")?;
+
+    loop {
+        if input.eof() {
+            return input.parse_error("end of file in function parameter list");
+        }
+
+        if input.match_token(")") {
+            break;
+        }
+
+        // Rest parameter collecting the extra arguments,
+        // which has to be the last parameter
+        let is_rest = input.match_token("...");
+
+        input.eat_ws();
+        let param_name = input.parse_ident()?;
+
+        if scope.decl_var(&param_name).is_none() {
+            return input.parse_error(&format!("duplicate parameter {}", param_name));
+        }
+
+        let local_idx = new_fun.params.len();
+        new_fun.params.push(param_name);
+
+        if is_rest {
+            new_fun.has_rest = true;
+            input.match_token(",");
+            input.expect_token(")")?;
+            break;
+        }
+
+        // Parameter with a default value
+        if input.match_token("=") {
+            // The default value is evaluated in the function prologue,
+            // which calls supplying this argument skip over
+            new_fun.entry_idxs.push(new_fun.insns.len());
+            parse_expr(vm, input, new_fun, &mut scope)?;
+            new_fun.insns.push(Insn::SetLocal { idx: local_idx });
+        }
+        else if !new_fun.entry_idxs.is_empty() {
+            return input.parse_error("parameters without a default value must come first");
+        }
+        else
+        {
+            new_fun.num_required += 1;
+        }
+
+        if input.match_token(")") {
+            break;
+        }
+
+        input.expect_token(",")?;
+    }
+
+    // Calls supplying every argument start after the prologue
+    new_fun.entry_idxs.push(new_fun.insns.len());
+
+    // Parse the function body
+    parse_stmt(vm, input, new_fun, &mut scope)?;
+
+    // Return nil if the end of the body is reached
+    new_fun.insns.push(Insn::Push { val: Value::Nil });
+    new_fun.insns.push(Insn::Return);
+    scope.end(new_fun);
+    mark_lines(new_fun, input.line_no);
+
+    // A generator's frame must be kept when it calls
+    // another function, so it can't make tail calls
+    if new_fun.is_generator {
+        for insn in &mut new_fun.insns {
+            if let Insn::TailCall { argc } = *insn {
+                *insn = Insn::Call { argc };
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Parse an atomic expression
@@ -406,16 +582,16 @@ fn parse_atom(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
     let ch = input.peek_ch();
 
     // Decimal integer literal
-    if ch.is_digit(10) {
+    if ch.is_ascii_digit() {
         let int_val = input.parse_int()?;
-        fun.insns.push(Insn::Push { val: Value::Int64(int_val) });
+        fun.insns.push(Insn::Push { val: vm.int64(int_val) });
         return Ok(());
     }
 
     // String literal
     if ch == '\"' || ch == '\'' {
         let str_val = input.parse_str()?;
-        let gc_val = vm.into_gc_heap(str_val);
+        let gc_val = vm.intern(&str_val);
         fun.insns.push(Insn::Push { val: gc_val });
         return Ok(());
     }
@@ -432,7 +608,7 @@ fn parse_atom(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
     if ch == '!' {
         input.eat_ch();
         parse_atom(vm, input, fun, scope)?;
-        fun.insns.push(Insn::Not);
+        emit_folded(vm, Insn::Not, fun);
         return Ok(());
     }
 
@@ -440,44 +616,24 @@ fn parse_atom(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
     if ch == '-' {
         input.eat_ch();
         parse_atom(vm, input, fun, scope)?;
-        fun.insns.push(Insn::Neg);
+        emit_folded(vm, Insn::Neg, fun);
         return Ok(());
     }
 
-    // Function expression
-    if input.match_keyword("fun") {
-        let mut new_fun = Function::new(&input.src_name);
-        let mut scope = Scope::new(&mut new_fun);
-
+    // Resume a generator
+    if input.match_keyword("resume") {
         input.expect_token("(")?;
+        parse_expr(vm, input, fun, scope)?;
+        input.expect_token(")")?;
+        fun.insns.push(Insn::Resume);
+        return Ok(());
+    }
 
-        loop {
-            if input.eof() {
-                return input.parse_error("end of file in function parameter list");
-            }
-
-            if input.match_token(")") {
-                break;
-            }
-
-            let param_name = input.parse_ident()?;
-            scope.decl_var(&param_name);
-            new_fun.params.push(param_name);
-
-            if input.match_token(")") {
-                break;
-            }
-
-            input.expect_token(",")?;
-        }
-
-        // Parse the function body
-        parse_stmt(vm, input, &mut new_fun, &mut scope)?;
-
-        // TODO: need to GC allocate fun
-        // TODO: need to push stmt on stack, Insn::Push
-        fun.insns.push(Insn::Push{ val: Value::Nil });
-
+    // Function expression
+    if input.match_keyword("fun") {
+        let fun_val = vm.into_gc_heap(Function::new(&input.src_name));
+        parse_fun(vm, input, fun_val, scope)?;
+        fun.insns.push(Insn::Push { val: fun_val });
         return Ok(());
     }
 
@@ -486,10 +642,8 @@ fn parse_atom(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
         let ident = input.parse_ident()?;
 
         // Check if there is a runtime function with this name
-        let runtime_fn = get_runtime_fn(&ident);
-
-        if runtime_fn.is_some() {
-            let host_fn = Value::HostFn(runtime_fn.unwrap());
+        if let Some(runtime_fn) = get_runtime_fn(&ident) {
+            let host_fn = Value::HostFn(runtime_fn);
             fun.insns.push(Insn::Push { val: host_fn });
             return Ok(());
         }
@@ -498,11 +652,22 @@ fn parse_atom(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
 
         // If the variable is not found
         if local_idx.is_none() {
+            // Check if there is a named function with this name
+            if let Some(fun_val) = scope.lookup_fun(&ident) {
+                fun.insns.push(Insn::Push { val: fun_val });
+                return Ok(());
+            }
+
             return input.parse_error(&format!("undeclared variable {}", ident));
         }
 
-        // If this is actually an assignment
-        if input.match_token("=") {
+        // If this is actually an assignment, and not an equality comparison
+        input.eat_ws();
+        let is_assign = input.peek_ch() == '=' && input.input_str.get(input.pos + 1) != Some(&'=');
+
+        if is_assign {
+            input.eat_ch();
+
             // Parse the expression to assign
             parse_expr(vm, input, fun, scope)?;
 
@@ -567,42 +732,109 @@ struct OpInfo
 
 /// Binary operators and their precedence level
 /// https://en.cppreference.com/w/c/language/operator_precedence
-const BIN_OPS: [OpInfo; 8] = [
-    OpInfo { op: "*", prec: 2 },
-    OpInfo { op: "%", prec: 2 },
-    OpInfo { op: "+", prec: 1 },
-    OpInfo { op: "-", prec: 1 },
-    OpInfo { op: "==", prec: 0 },
-    OpInfo { op: "!=", prec: 0 },
-    OpInfo { op: "<", prec: 0 },
-    OpInfo { op: ">", prec: 0 },
+const BIN_OPS: [OpInfo; 9] = [
+    OpInfo { op: "*", prec: 3 },
+    OpInfo { op: "%", prec: 3 },
+    OpInfo { op: "+", prec: 2 },
+    OpInfo { op: "-", prec: 2 },
+    OpInfo { op: "==", prec: 1 },
+    OpInfo { op: "!=", prec: 1 },
+    OpInfo { op: "<", prec: 1 },
+    OpInfo { op: ">", prec: 1 },
+    OpInfo { op: "..", prec: 0 },
 ];
 
 /// Try to match a binary operator in the input
 fn match_bin_op(input: &mut Input) -> Option<OpInfo>
 {
-    for op_info in BIN_OPS {
-        if input.match_token(op_info.op) {
-            return Some(op_info);
+    BIN_OPS.into_iter().find(|op_info| input.match_token(op_info.op))
+}
+
+fn emit_op(vm: &mut VM, op: &str, fun: &mut Function)
+{
+    let insn = match op {
+        "*" => Insn::Mul,
+        "%" => Insn::Mod,
+        "+" => Insn::Add,
+        "-" => Insn::Sub,
+        "==" => Insn::Eq,
+        "!=" => Insn::Ne,
+        "<" => Insn::Lt,
+        ">" => Insn::Gt,
+        ".." => Insn::Range,
+        _ => panic!()
+    };
+
+    emit_folded(vm, insn, fun);
+}
+
+/// Make an integer constant, if the operation didn't overflow
+fn int_const(vm: &mut VM, v: Option<i64>) -> Option<Value>
+{
+    v.map(|v| vm.int64(v))
+}
+
+/// Evaluate an operation on constant operands at compile time
+/// Operations that would produce an error at run time aren't folded,
+/// so that the error still happens when the code is executed
+fn fold_const(vm: &mut VM, insn: Insn, args: &[ValueKind]) -> Option<Value>
+{
+    use ValueKind::*;
+
+    let bool_const = |b: bool| Some(Value::Int64(b as i64));
+
+    match (insn, args) {
+        (Insn::Neg, [Int64(v)]) => int_const(vm, v.checked_neg()),
+        (Insn::Not, [Int64(v)]) => bool_const(*v == 0),
+
+        (Insn::Add, [Int64(v0), Int64(v1)]) => int_const(vm, v0.checked_add(*v1)),
+        (Insn::Sub, [Int64(v0), Int64(v1)]) => int_const(vm, v0.checked_sub(*v1)),
+        (Insn::Mul, [Int64(v0), Int64(v1)]) => int_const(vm, v0.checked_mul(*v1)),
+        (Insn::Mod, [Int64(v0), Int64(v1)]) => int_const(vm, v0.checked_rem(*v1)),
+        (Insn::Eq, [Int64(v0), Int64(v1)]) => bool_const(v0 == v1),
+        (Insn::Ne, [Int64(v0), Int64(v1)]) => bool_const(v0 != v1),
+        (Insn::Lt, [Int64(v0), Int64(v1)]) => bool_const(v0 < v1),
+        (Insn::Gt, [Int64(v0), Int64(v1)]) => bool_const(v0 > v1),
+
+        (Insn::Add, [Str(s0), Str(s1)]) => unsafe {
+            let (s0, s1) = (&**s0, &**s1);
+            if s0.len() + s1.len() > vm.config().max_string_len {
+                return None;
+            }
+            Some(vm.intern(&(s0.to_string() + s1)))
         }
-    }
+        (Insn::Eq, [Str(s0), Str(s1)]) => unsafe { bool_const(**s0 == **s1) },
+        (Insn::Ne, [Str(s0), Str(s1)]) => unsafe { bool_const(**s0 != **s1) },
 
-    None
+        _ => None
+    }
 }
 
-fn emit_op(op: &str, fun: &mut Function)
+/// Emit a unary or binary operator, replacing it and its
+/// operands with a single constant if they are all constants
+fn emit_folded(vm: &mut VM, insn: Insn, fun: &mut Function)
 {
-    match op {
-        "*" => fun.insns.push(Insn::Mul),
-        "%" => fun.insns.push(Insn::Mod),
-        "+" => fun.insns.push(Insn::Add),
-        "-" => fun.insns.push(Insn::Sub),
-        "==" => fun.insns.push(Insn::Eq),
-        "!=" => fun.insns.push(Insn::Ne),
-        "<" => fun.insns.push(Insn::Lt),
-        ">" => fun.insns.push(Insn::Gt),
-        _ => panic!()
+    let num_args = match insn {
+        Insn::Neg | Insn::Not => 1,
+        _ => 2
+    };
+
+    // The operands are the values pushed by the last instructions
+    let args_idx = fun.insns.len().saturating_sub(num_args);
+    let args: Vec<ValueKind> = fun.insns[args_idx..].iter().filter_map(|insn| match insn {
+        Insn::Push { val } => Some(val.kind()),
+        _ => None
+    }).collect();
+
+    if args.len() == num_args {
+        if let Some(val) = fold_const(vm, insn, &args) {
+            fun.insns.truncate(args_idx);
+            fun.insns.push(Insn::Push { val });
+            return;
+        }
     }
+
+    fun.insns.push(insn);
 }
 
 /// Parse a complex expression
@@ -628,6 +860,14 @@ fn parse_expr(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
             continue;
         }
 
+        // If this is an indexing expression
+        if input.match_token("[") {
+            parse_expr(vm, input, fun, scope)?;
+            input.expect_token("]")?;
+            fun.insns.push(Insn::GetIndex);
+            continue;
+        }
+
         let new_op = match_bin_op(input);
 
         // If no operator could be matched, stop
@@ -637,12 +877,12 @@ fn parse_expr(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
 
         let new_op = new_op.unwrap();
 
-        while op_stack.len() > 0 {
+        while !op_stack.is_empty() {
             // Get the operator at the top of the stack
             let top_op = &op_stack[op_stack.len() - 1];
 
             if top_op.prec > new_op.prec {
-                emit_op(top_op.op, fun);
+                emit_op(vm, top_op.op, fun);
                 op_stack.pop();
             }
             else {
@@ -657,27 +897,251 @@ fn parse_expr(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
     }
 
     // Emit all operators remaining on the operator stack
-    while op_stack.len() > 0 {
+    while !op_stack.is_empty() {
         let top_op = &op_stack[op_stack.len() - 1];
-        emit_op(top_op.op, fun);
+        emit_op(vm, top_op.op, fun);
         op_stack.pop();
     }
 
     Ok(())
 }
 
-/// Parse a statement
+/// Constant value that an arm of a match statement compares against
+#[derive(PartialEq)]
+enum MatchCase
+{
+    Int(i64),
+    Str(String),
+    Default,
+}
+
+/// Minimum number of integer cases for a match to use a jump table
+const MIN_JUMP_TABLE_CASES: usize = 3;
+
+/// Parse the case value of a match arm
+fn parse_match_case(input: &mut Input) -> Result<MatchCase, ParseError>
+{
+    input.eat_ws();
+    let ch = input.peek_ch();
+
+    if ch == '_' {
+        input.eat_ch();
+        return Ok(MatchCase::Default);
+    }
+
+    if ch == '\"' || ch == '\'' {
+        return Ok(MatchCase::Str(input.parse_str()?));
+    }
+
+    if input.match_token("-") {
+        input.eat_ws();
+        return Ok(MatchCase::Int(-input.parse_int()?));
+    }
+
+    if ch.is_ascii_digit() {
+        return Ok(MatchCase::Int(input.parse_int()?));
+    }
+
+    input.parse_error("expected integer, string or _ in match case")
+}
+
+/// Parse a match statement
+/// The arm bodies are emitted first, followed by the dispatch code,
+/// because we can only decide if a jump table can be used once all
+/// the case values are known.
+fn parse_match(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Scope) -> Result<(), ParseError>
+{
+    // Parse the value to match on
+    input.expect_token("(")?;
+    parse_expr(vm, input, fun, scope)?;
+    input.expect_token(")")?;
+    input.expect_token("{")?;
+
+    // Jump to the dispatch code, patched at the end
+    let dispatch_jmp_idx = fun.insns.len();
+    fun.insns.push(Insn::Jump { offset: 0 });
+
+    // Case values and the index where each arm starts
+    let mut cases: Vec<(MatchCase, usize)> = Vec::default();
+    let mut default_idx: Option<usize> = None;
+
+    // Jumps from the end of each arm to the end of the match
+    let mut end_jmp_idxs: Vec<usize> = Vec::default();
+
+    loop
+    {
+        input.eat_ws();
+
+        if input.eof() {
+            return input.parse_error("unexpected end of input in match statement");
+        }
+
+        if input.match_token("}") {
+            break;
+        }
+
+        let case = parse_match_case(input)?;
+        let arm_idx = fun.insns.len();
+
+        if case == MatchCase::Default {
+            if default_idx.is_some() {
+                return input.parse_error("duplicate default case in match statement");
+            }
+            default_idx = Some(arm_idx);
+        }
+        else
+        {
+            if cases.iter().any(|(c, _)| *c == case) {
+                return input.parse_error("duplicate case value in match statement");
+            }
+            cases.push((case, arm_idx));
+        }
+
+        input.expect_token("=>")?;
+
+        // Parse the arm body, then jump to the end of the match
+        parse_stmt(vm, input, fun, scope)?;
+        end_jmp_idxs.push(fun.insns.len());
+        fun.insns.push(Insn::Jump { offset: 0 });
+
+        // Arms can optionally be separated by commas
+        input.match_token(",");
+    }
+
+    // Collect the integer case values
+    let int_vals: Vec<i64> = cases.iter().filter_map(|(c, _)| match c {
+        MatchCase::Int(v) => Some(*v),
+        _ => None
+    }).collect();
+
+    // Use a jump table if every case is an integer and the values are dense
+    let use_table = int_vals.len() == cases.len() && int_vals.len() >= MIN_JUMP_TABLE_CASES && {
+        let min_val = *int_vals.iter().min().unwrap() as i128;
+        let max_val = *int_vals.iter().max().unwrap() as i128;
+        max_val - min_val < 2 * int_vals.len() as i128
+    };
+
+    if use_table {
+        let end_idx = fun.insns.len();
+        let min_val = *int_vals.iter().min().unwrap();
+        let max_val = *int_vals.iter().max().unwrap();
+
+        // Offsets are relative to the instruction after the jump table
+        let rel = |idx: usize| idx as isize - (dispatch_jmp_idx as isize + 1);
+        let default_offset = rel(default_idx.unwrap_or(end_idx));
+
+        let mut offsets = vec![default_offset; (max_val - min_val + 1) as usize];
+        for (case, arm_idx) in &cases {
+            if let MatchCase::Int(v) = case {
+                offsets[(v - min_val) as usize] = rel(*arm_idx);
+            }
+        }
+
+        let table_idx = fun.jump_tables.len();
+        fun.jump_tables.push(JumpTable { min_val, offsets, default_offset });
+        fun.insns[dispatch_jmp_idx] = Insn::JumpTable { table_idx };
+    }
+    else
+    {
+        // Patch the jump to the dispatch code
+        let dispatch_idx = fun.insns.len() as isize;
+        fun.insns[dispatch_jmp_idx] = Insn::Jump { offset: dispatch_idx - (dispatch_jmp_idx as isize + 1) };
+
+        // Compare the value against each case in turn
+        for (case, arm_idx) in &cases {
+            let case_val = match case {
+                MatchCase::Int(v) => vm.int64(*v),
+                MatchCase::Str(s) => vm.intern(s),
+                MatchCase::Default => unreachable!()
+            };
+
+            fun.insns.push(Insn::Dup);
+            fun.insns.push(Insn::Push { val: case_val });
+            fun.insns.push(Insn::Eq);
+            fun.insns.push(Insn::IfFalse { offset: 2 });
+            fun.insns.push(Insn::Pop);
+            let jmp_idx = fun.insns.len() as isize;
+            fun.insns.push(Insn::Jump { offset: *arm_idx as isize - (jmp_idx + 1) });
+        }
+
+        // No case matched, go to the default arm if there is one
+        fun.insns.push(Insn::Pop);
+        if let Some(default_idx) = default_idx {
+            let jmp_idx = fun.insns.len() as isize;
+            fun.insns.push(Insn::Jump { offset: default_idx as isize - (jmp_idx + 1) });
+        }
+    }
+
+    // Patch the jumps to the end of the match
+    let end_idx = fun.insns.len() as isize;
+    for jmp_idx in end_jmp_idxs {
+        fun.insns[jmp_idx] = Insn::Jump { offset: end_idx - (jmp_idx as isize + 1) };
+    }
+
+    Ok(())
+}
+
+/// Attribute the instructions emitted since the last call to a source line
+fn mark_lines(fun: &mut Function, line_no: u32)
+{
+    fun.insn_lines.truncate(fun.insns.len());
+    fun.insn_lines.resize(fun.insns.len(), line_no);
+}
+
+/// Parse a statement, recording the source line of its instructions
 fn parse_stmt(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Scope) -> Result<(), ParseError>
 {
     input.eat_ws();
 
+    // Instructions emitted so far belong to the enclosing statement
+    let outer_line = input.stmt_line;
+    mark_lines(fun, outer_line);
+
+    input.stmt_line = input.line_no;
+    parse_stmt_insns(vm, input, fun, scope)?;
+    mark_lines(fun, input.stmt_line);
+    input.stmt_line = outer_line;
+
+    Ok(())
+}
+
+/// Parse a statement
+fn parse_stmt_insns(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Scope) -> Result<(), ParseError>
+{
     if input.match_keyword("return") {
         parse_expr(vm, input, fun, scope)?;
+
+        // A call in tail position reuses the frame of the caller
+        // The return is kept for calls that can't be tail calls
+        if let Some(Insn::Call { argc }) = fun.insns.last() {
+            let argc = *argc;
+            fun.insns.pop();
+            fun.insns.push(Insn::TailCall { argc });
+        }
+
         fun.insns.push(Insn::Return);
         input.expect_token(";")?;
         return Ok(());
     }
 
+    // Named function declaration
+    if input.match_keyword("fun") {
+        input.eat_ws();
+        let ident = input.parse_ident()?;
+
+        if get_runtime_fn(&ident).is_some() {
+            return input.parse_error(&format!("there is already a runtime function named {}", ident));
+        }
+
+        // Declare the function before parsing its body so it can call itself
+        let fun_val = vm.into_gc_heap(Function::new(&ident));
+        if !scope.decl_fun(&ident, fun_val) {
+            return input.parse_error(&format!("{} already declared", ident));
+        }
+
+        return parse_fun(vm, input, fun_val, scope);
+    }
+
     // Variable declaration
     if input.match_keyword("let") {
         input.eat_ws();
@@ -687,10 +1151,8 @@ fn parse_stmt(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
         input.expect_token(";")?;
 
         // Check if there is a runtime function with this name
-        let runtime_fn = get_runtime_fn(&ident);
-
-        if runtime_fn.is_some() {
-            let host_fn = Value::HostFn(runtime_fn.unwrap());
+        if let Some(runtime_fn) = get_runtime_fn(&ident) {
+            let host_fn = Value::HostFn(runtime_fn);
             fun.insns.push(Insn::Push { val: host_fn });
             return input.parse_error(&format!("there is already a runtime function named {}", ident));
         }
@@ -775,6 +1237,70 @@ fn parse_stmt(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
         return Ok(());
     }
 
+    // For-in loop
+    if input.match_keyword("for") {
+        // The loop variable lives in its own scope
+        let mut scope = Scope::new_nested(scope);
+
+        input.expect_token("(")?;
+        input.eat_ws();
+        let ident = input.parse_ident()?;
+
+        if !input.match_keyword("in") {
+            return input.parse_error("expected in keyword in for loop");
+        }
+
+        // Parse the iterable expression
+        parse_expr(vm, input, fun, &mut scope)?;
+        input.expect_token(")")?;
+        fun.insns.push(Insn::GetIter);
+
+        let local_idx = match scope.decl_var(&ident) {
+            Some(idx) => idx,
+            None => return input.parse_error(&format!("variable {} already declared", ident))
+        };
+
+        // Get the next value, or jump past the loop body once
+        // the iterator is exhausted
+        let next_idx = fun.insns.len() as isize;
+        fun.insns.push(Insn::IterNext { offset: 0 });
+        fun.insns.push(Insn::SetLocal { idx: local_idx });
+
+        // Parse the loop body
+        parse_stmt(vm, input, fun, &mut scope)?;
+
+        // Jump back to get the next value
+        let jump_idx = fun.insns.len() as isize;
+        fun.insns.push(Insn::Jump { offset: next_idx - (jump_idx + 1) });
+
+        // Patch the iterator exit jump
+        fun.insns[next_idx as usize] = Insn::IterNext { offset: (jump_idx + 1) - (next_idx + 1) };
+        scope.end(fun);
+
+        return Ok(());
+    }
+
+    // Debugger statement, which stops execution if debugging is enabled
+    if input.match_keyword("debugger") {
+        input.expect_token(";")?;
+        fun.insns.push(Insn::Debugger);
+        return Ok(());
+    }
+
+    // Yield statement, which makes the function a generator
+    if input.match_keyword("yield") {
+        parse_expr(vm, input, fun, scope)?;
+        input.expect_token(";")?;
+        fun.insns.push(Insn::Yield);
+        fun.is_generator = true;
+        return Ok(());
+    }
+
+    // Match statement
+    if input.match_keyword("match") {
+        return parse_match(vm, input, fun, scope);
+    }
+
     // Assert statement
     if input.match_keyword("assert") {
         parse_expr(vm, input, fun, scope)?;
@@ -807,6 +1333,7 @@ fn parse_stmt(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
             parse_stmt(vm, input, fun, &mut scope)?;
         }
 
+        scope.end(fun);
         return Ok(());
     }
 
@@ -820,6 +1347,7 @@ fn parse_stmt(vm: &mut VM, input: &mut Input, fun: &mut Function, scope: &mut Sc
 pub fn parse_unit(vm: &mut VM, input: &mut Input) -> Result<Function, ParseError>
 {
     let mut unit_fun = Function::new(&input.src_name);
+    unit_fun.src_name = input.src_name.clone();
     let mut scope = Scope::new(&mut unit_fun);
 
     loop
@@ -832,29 +1360,39 @@ pub fn parse_unit(vm: &mut VM, input: &mut Input) -> Result<Function, ParseError
 
         parse_stmt(vm, input, &mut unit_fun, &mut scope)?;
 
-        // TODO: detect function keyword
+        if unit_fun.is_generator {
+            return input.parse_error("yield outside of a function");
+        }
     }
 
     // Return nil
     unit_fun.insns.push(Insn::Push { val: Value::Nil });
     unit_fun.insns.push(Insn::Return);
+    mark_lines(&mut unit_fun, input.line_no);
+    scope.end(&mut unit_fun);
+
+    optimize_unit(&mut unit_fun);
 
-    //dbg!(unit_fun.num_locals);
-    //dbg!(&unit_fun.insns);
+    // The register compiler relies on the code being valid
+    if let Err(err) = verify_unit(&unit_fun) {
+        panic!("parser generated invalid bytecode: {}", err);
+    }
+
+    compile_unit(&mut unit_fun);
 
     Ok(unit_fun)
 }
 
 pub fn parse_str(vm: &mut VM, src: &str) -> Result<Function, ParseError>
 {
-    let mut input = Input::new(&src, "src");
+    let mut input = Input::new(src, "src");
     parse_unit(vm, &mut input)
 }
 
 pub fn parse_file(vm: &mut VM, file_name: &str) -> Result<Function, ParseError>
 {
     let data = fs::read_to_string(file_name)
-        .expect(&format!("could not read input file {}", file_name));
+        .unwrap_or_else(|_| panic!("could not read input file {}", file_name));
 
     let mut input = Input::new(&data, file_name);
 
@@ -869,14 +1407,14 @@ mod tests
     fn parse_ok(src: &str)
     {
         let mut vm = VM::new();
-        let mut input = Input::new(&src, "src");
+        let mut input = Input::new(src, "src");
         assert!(parse_unit(&mut vm, &mut input).is_ok());
     }
 
     fn parse_fails(src: &str)
     {
         let mut vm = VM::new();
-        let mut input = Input::new(&src, "src");
+        let mut input = Input::new(src, "src");
         assert!(parse_unit(&mut vm, &mut input).is_err());
     }
 
@@ -923,6 +1461,10 @@ mod tests
         parse_ok(" \"foobar\";");
         parse_ok("'foo\tbar\nbif';");
         parse_ok("1_000_000;");
+        parse_ok("576_460_752_303_423_488;");
+        parse_ok("9_223_372_036_854_775_807;");
+        parse_fails("9_223_372_036_854_775_808;");
+        parse_fails("100000000000000000000000;");
     }
 
     #[test]
@@ -989,4 +1531,129 @@ mod tests
         parse_ok("let f = fun(x,y) { return 1; };");
         parse_fails("let f = fun(x,y,1) {};");
     }
+
+    #[test]
+    fn fun_decl()
+    {
+        parse_ok("fun f() {}");
+        parse_ok("fun f(n) { return f(n - 1); }");
+        parse_ok("fun f() {} fun g() { return f(); }");
+        parse_ok("fun f() { fun g() { return f(); } return g(); }");
+        parse_ok("fun f() {} { fun f() {} }");
+        parse_fails("fun f() {} fun f() {}");
+        parse_fails("let f = 1; fun f() {}");
+        parse_fails("fun f() {} let f = 1;");
+        parse_fails("fun println() {}");
+
+        // Functions can't access the local variables of enclosing functions
+        parse_fails("let x = 1; fun f() { return x; }");
+        parse_fails("fun f(x) { fun g() { return x; } }");
+    }
+
+    #[test]
+    fn const_folding()
+    {
+        // Number of instructions in the unit function, excluding the final return
+        let num_insns = |src: &str| {
+            let mut vm = VM::new();
+            let fun = parse_str(&mut vm, src).unwrap();
+            fun.insns.len() - 2
+        };
+
+        assert_eq!(num_insns("return 1 + 2 * 3;"), 2);
+        assert_eq!(num_insns("return -(4 - 1) < 2;"), 2);
+        assert_eq!(num_insns("return !(1 == 1) + 5 % 3;"), 2);
+        assert_eq!(num_insns("return 'foo' + 'bar' == 'foobar';"), 2);
+        assert_eq!(num_insns("let x = 1; return x + 2 * 3;"), 5);
+
+        // Operations that fail at run time are not folded
+        assert_eq!(num_insns("return 1 % 0;"), 4);
+        assert_eq!(num_insns("return 9_223_372_036_854_775_807 + 1;"), 3);
+        assert_eq!(num_insns("return 1 + 'a';"), 4);
+        assert_eq!(num_insns("return 'a' < 'b';"), 4);
+    }
+
+    #[test]
+    fn tail_calls()
+    {
+        let has_tail_call = |src: &str| {
+            let mut vm = VM::new();
+            let fun = parse_str(&mut vm, src).unwrap();
+            fun.insns.iter().any(|insn| matches!(insn, Insn::TailCall { .. }))
+        };
+
+        assert!(has_tail_call("return println(1);"));
+        assert!(!has_tail_call("return println(1) + 1;"));
+        assert!(!has_tail_call("println(1); return 1;"));
+    }
+
+    #[test]
+    fn fun_params()
+    {
+        parse_ok("let f = fun(x, y = 2) {};");
+        parse_ok("let f = fun(x = 1, y = x + 1) {};");
+        parse_ok("let f = fun(...rest) {};");
+        parse_ok("let f = fun(x, y = 2, ...rest) { return rest[0]; };");
+        parse_ok("let f = fun(x, ...rest,) {};");
+        parse_fails("let f = fun(x = 1, y) {};");
+        parse_fails("let f = fun(x, x) {};");
+        parse_fails("let f = fun(...rest, x) {};");
+        parse_fails("let f = fun(...rest = 1) {};");
+        parse_fails("let f = fun(x = y) {};");
+    }
+
+    #[test]
+    fn for_in()
+    {
+        parse_ok("for (i in 0..10) {}");
+        parse_ok("for (i in 0..10) println(i);");
+        parse_ok("for (c in 'foo') { println(c); }");
+        parse_ok("let s = 'foo'; for (c in s) println(c);");
+        parse_fails("for (i in) {}");
+        parse_fails("for (i 0..10) {}");
+        parse_fails("for i in 0..10 {}");
+    }
+
+    #[test]
+    fn generators()
+    {
+        parse_ok("let g = fun() { yield 1; };");
+        parse_ok("let g = fun(n) { while (n > 0) { yield n; n = n - 1; } };");
+        parse_ok("let g = fun() { yield 1; }; resume(g());");
+        parse_ok("let g = fun() { yield 1; }; for (x in g()) println(x);");
+        parse_fails("yield 1;");
+        parse_fails("{ yield 1; }");
+        parse_fails("let g = fun() { yield; };");
+        parse_fails("resume 1;");
+    }
+
+    #[test]
+    fn match_stmt()
+    {
+        parse_ok("match (1) {}");
+        parse_ok("match (1) { 1 => 2; }");
+        parse_ok("match (1) { 1 => 2; 'a' => {}, _ => { 3; }, }");
+        parse_ok("match (1) { -1 => 1; 0 => 2; 1 => 3; }");
+        parse_fails("match (1) { 1 => 2; 1 => 3; }");
+        parse_fails("match (1) { 'a' => 2; 'a' => 3; }");
+        parse_fails("match (1) { _ => 2; _ => 3; }");
+        parse_fails("match (1) { x => 2; }");
+        parse_fails("match (1) { 1 => 2;");
+    }
+
+    #[test]
+    fn match_jump_table()
+    {
+        let uses_table = |src: &str| {
+            let mut vm = VM::new();
+            let fun = parse_str(&mut vm, src).unwrap();
+            fun.insns.iter().any(|insn| matches!(insn, Insn::JumpTable { .. }))
+        };
+
+        assert!(uses_table("match (1) { 1 => 1; 2 => 2; 3 => 3; _ => 4; }"));
+        assert!(uses_table("match (1) { 1 => 1; 3 => 3; 5 => 5; }"));
+        assert!(!uses_table("match (1) { 1 => 1; 2 => 2; }"));
+        assert!(!uses_table("match (1) { 1 => 1; 2 => 2; 1000 => 3; }"));
+        assert!(!uses_table("match (1) { 1 => 1; 2 => 2; 'a' => 3; }"));
+    }
 }
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use crate::vm::*;
+use crate::runtime::RuntimeFn;
+
+/// Statistics for one function
+struct FunStats
+{
+    name: String,
+
+    /// Opcode name of each instruction, copied so that the
+    /// report doesn't need the function to still be alive
+    opcodes: Vec<&'static str>,
+
+    /// Number of times each instruction was executed
+    insn_counts: Vec<u64>,
+
+    /// Number of activations, including generator resumptions
+    calls: u64,
+
+    /// Number of activations currently on the call stack, so that
+    /// the time of recursive calls is only counted once
+    active: u32,
+
+    /// Time spent in this function, including and excluding callees
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+/// Call stack seen while profiling, stored as a tree
+struct StackNode
+{
+    parent: Option<usize>,
+    fun_idx: usize,
+
+    /// Time spent with this call stack
+    time: Duration,
+}
+
+/// Function activation on the profiler's copy of the call stack
+struct Activation
+{
+    fun: *const Function,
+    fun_idx: usize,
+    node_idx: usize,
+    start: Instant,
+}
+
+/// Instruction counts and call timings collected by a VM in profiling
+/// mode. The VM updates its copy of the call stack before each
+/// instruction, and the time between two changes is charged to the
+/// function at the top.
+#[derive(Default)]
+pub struct Profile
+{
+    funs: Vec<FunStats>,
+
+    /// Index of the statistics of each function, by function id
+    /// since a function's address can be reused once it is freed
+    fun_idxs: HashMap<u64, usize>,
+
+    nodes: Vec<StackNode>,
+    node_idxs: HashMap<(Option<usize>, usize), usize>,
+
+    stack: Vec<Activation>,
+
+    /// Time of the last call stack change, None while not running
+    last_change: Option<Instant>,
+
+    /// Number of calls to each host function, by name
+    host_calls: HashMap<&'static str, u64>,
+}
+
+impl Profile
+{
+    /// Update the copy of the call stack to match the VM's frames,
+    /// given their depth and a way to get the function of each frame.
+    /// Only one frame is pushed or replaced between instructions, but
+    /// any number can be popped, so only the top frame is compared.
+    #[inline(always)]
+    pub fn sync(&mut self, depth: usize, frame_fun: impl Fn(usize) -> *const Function)
+    {
+        let top_fun = match depth {
+            0 => None,
+            _ => Some(frame_fun(depth - 1))
+        };
+
+        if self.stack.len() == depth && self.stack.last().map(|act| act.fun) == top_fun {
+            return;
+        }
+
+        let now = Instant::now();
+        self.charge(now);
+
+        let mut keep = depth.min(self.stack.len());
+        if keep > 0 && self.stack[keep - 1].fun != frame_fun(keep - 1) {
+            keep -= 1;
+        }
+
+        while self.stack.len() > keep {
+            let act = self.stack.pop().unwrap();
+            let stats = &mut self.funs[act.fun_idx];
+            stats.active -= 1;
+            if stats.active == 0 {
+                stats.inclusive += now - act.start;
+            }
+        }
+
+        while self.stack.len() < depth {
+            let fun = frame_fun(self.stack.len());
+            let fun_idx = self.fun_idx(fun);
+            let parent = self.stack.last().map(|act| act.node_idx);
+            let node_idx = self.node_idx(parent, fun_idx);
+
+            let stats = &mut self.funs[fun_idx];
+            stats.calls += 1;
+            stats.active += 1;
+
+            self.stack.push(Activation { fun, fun_idx, node_idx, start: now });
+        }
+    }
+
+    /// Charge the time since the last change to the function at the top
+    fn charge(&mut self, now: Instant)
+    {
+        if let (Some(last_change), Some(act)) = (self.last_change, self.stack.last()) {
+            let elapsed = now - last_change;
+            self.funs[act.fun_idx].exclusive += elapsed;
+            self.nodes[act.node_idx].time += elapsed;
+        }
+
+        self.last_change = Some(now);
+    }
+
+    /// Start timing when execution starts or resumes
+    pub fn start_clock(&mut self)
+    {
+        self.last_change = Some(Instant::now());
+    }
+
+    /// Stop timing when execution returns, so that time spent
+    /// while suspended isn't charged to any function
+    pub fn stop_clock(&mut self)
+    {
+        self.charge(Instant::now());
+        self.last_change = None;
+    }
+
+    /// Index of the statistics of a function, added on first sight
+    fn fun_idx(&mut self, fun_ptr: *const Function) -> usize
+    {
+        let fun = unsafe { &*fun_ptr };
+        if let Some(idx) = self.fun_idxs.get(&fun.id) {
+            return *idx;
+        }
+
+        // Anonymous functions are named after their source file, so
+        // add the line they start at to tell them apart. Unit functions
+        // have the same name but no entry points.
+        let name = match fun.insn_lines.first() {
+            Some(line_no) if fun.name == fun.src_name && !fun.entry_idxs.is_empty() => {
+                format!("{}:{}", fun.name, line_no)
+            }
+            _ => fun.name.clone()
+        };
+
+        self.funs.push(FunStats {
+            name,
+            opcodes: fun.insns.iter().map(|insn| insn.name()).collect(),
+            insn_counts: vec![0; fun.insns.len()],
+            calls: 0,
+            active: 0,
+            inclusive: Duration::ZERO,
+            exclusive: Duration::ZERO,
+        });
+
+        self.fun_idxs.insert(fun.id, self.funs.len() - 1);
+        self.funs.len() - 1
+    }
+
+    /// Index of the node for a function called from a call stack
+    fn node_idx(&mut self, parent: Option<usize>, fun_idx: usize) -> usize
+    {
+        let nodes = &mut self.nodes;
+        *self.node_idxs.entry((parent, fun_idx)).or_insert_with(|| {
+            nodes.push(StackNode { parent, fun_idx, time: Duration::ZERO });
+            nodes.len() - 1
+        })
+    }
+
+    /// Count the execution of an instruction of the function at the top
+    #[inline(always)]
+    pub fn count_insn(&mut self, insn_idx: usize)
+    {
+        let act = self.stack.last().unwrap();
+        self.funs[act.fun_idx].insn_counts[insn_idx] += 1;
+    }
+
+    /// Count a call to a host function
+    pub fn count_host_call(&mut self, host_fn: RuntimeFn)
+    {
+        *self.host_calls.entry(host_fn.name()).or_default() += 1;
+    }
+
+    /// Total number of instructions executed
+    pub fn num_insns(&self) -> u64
+    {
+        self.funs.iter().map(|stats| stats.insn_counts.iter().sum::<u64>()).sum()
+    }
+
+    /// Instruction counts by opcode, most executed first
+    fn opcode_counts(&self) -> Vec<(&'static str, u64)>
+    {
+        let mut counts: HashMap<&'static str, u64> = HashMap::new();
+        for stats in &self.funs {
+            for (opcode, count) in stats.opcodes.iter().zip(&stats.insn_counts) {
+                if *count > 0 {
+                    *counts.entry(opcode).or_default() += count;
+                }
+            }
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Text report with the functions sorted by exclusive time, and
+    /// the opcodes and host functions by number of executions
+    pub fn report(&self) -> String
+    {
+        let mut out = String::new();
+
+        let num_insns = self.num_insns();
+        let total_time: Duration = self.funs.iter().map(|stats| stats.exclusive).sum();
+        writeln!(out, "{} instructions executed in {:.3} ms", num_insns, total_time.as_secs_f64() * 1e3).unwrap();
+
+        let percent = |count: u64| 100.0 * count as f64 / num_insns.max(1) as f64;
+        let ms = |time: Duration| time.as_secs_f64() * 1e3;
+
+        let mut funs: Vec<&FunStats> = self.funs.iter().collect();
+        funs.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.name.cmp(&b.name)));
+
+        writeln!(out).unwrap();
+        writeln!(out, "{:>10} {:>12} {:>10} {:>10}  function", "calls", "insns", "incl ms", "excl ms").unwrap();
+        for stats in funs {
+            writeln!(
+                out,
+                "{:>10} {:>12} {:>10.3} {:>10.3}  {}",
+                stats.calls,
+                stats.insn_counts.iter().sum::<u64>(),
+                ms(stats.inclusive),
+                ms(stats.exclusive),
+                stats.name
+            ).unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "{:>10} {:>7}  opcode", "count", "%").unwrap();
+        for (opcode, count) in self.opcode_counts() {
+            writeln!(out, "{:>10} {:>6.2}%  {}", count, percent(count), opcode).unwrap();
+        }
+
+        if !self.host_calls.is_empty() {
+            let mut host_calls: Vec<_> = self.host_calls.iter().collect();
+            host_calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+            writeln!(out).unwrap();
+            writeln!(out, "{:>10}  host function", "calls").unwrap();
+            for (name, count) in host_calls {
+                writeln!(out, "{:>10}  {}", count, name).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// Call stacks in the folded format used by flame graph tools, one
+    /// per line, with the functions from the outermost to the innermost
+    /// separated by semicolons, followed by the time spent with that
+    /// exact stack in nanoseconds
+    pub fn folded_stacks(&self) -> String
+    {
+        let mut lines = Vec::new();
+
+        for node in self.nodes.iter().filter(|node| node.time > Duration::ZERO) {
+            let mut names = Vec::new();
+            let mut cur = Some(node);
+            while let Some(node) = cur {
+                // Semicolons separate the frames
+                names.push(self.funs[node.fun_idx].name.replace(';', "_"));
+                cur = node.parent.map(|idx| &self.nodes[idx]);
+            }
+            names.reverse();
+
+            lines.push(format!("{} {}\n", names.join(";"), node.time.as_nanos()));
+        }
+
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+
+    fn profile_src(src: &str) -> Profile
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        vm.set_profiling(true);
+        vm.eval(&unit_fn).unwrap();
+        assert_eq!(vm.stack_size(), 0);
+        vm.take_profile().unwrap()
+    }
+
+    fn fun_stats<'a>(profile: &'a Profile, name: &str) -> &'a FunStats
+    {
+        profile.funs.iter().find(|stats| stats.name == name).unwrap()
+    }
+
+    #[test]
+    fn counts()
+    {
+        let profile = profile_src("fun f(n) { return n + 1; }\nlet s = 0;\nfor (i in 0..10) s = f(s);\nprintln(s);\nprint('');");
+
+        assert_eq!(profile.host_calls["println"], 1);
+        assert_eq!(profile.host_calls["print"], 1);
+        assert!(!profile.host_calls.contains_key("len"));
+
+        // GetLocal, AddConst, Return for each call
+        let f = fun_stats(&profile, "f");
+        assert_eq!(f.calls, 10);
+        assert_eq!(f.insn_counts.iter().sum::<u64>(), 30);
+        assert!(f.inclusive >= f.exclusive);
+
+        let src = fun_stats(&profile, "src");
+        assert_eq!(src.calls, 1);
+        assert!(src.inclusive >= src.exclusive + f.exclusive);
+
+        let opcode_counts = profile.opcode_counts();
+        assert!(opcode_counts.contains(&("Return", 11)));
+        assert!(opcode_counts.contains(&("Call", 12)));
+
+        let report = profile.report();
+        assert!(report.contains("        10           30"));
+        assert!(report.contains("         1  println"));
+        assert!(report.contains("Return"));
+    }
+
+    #[test]
+    fn freed_functions()
+    {
+        // Units parsed after others are freed can reuse their
+        // addresses, but must not share their statistics
+        let mut vm = VM::new();
+        vm.set_profiling(true);
+        for src in ["return 1;", "let s = 0; for (i in 0..3) s = s + i; return s;"] {
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            vm.eval(&unit_fn).unwrap();
+        }
+
+        let profile = vm.take_profile().unwrap();
+        assert_eq!(profile.funs.len(), 2);
+        assert!(profile.funs.iter().all(|stats| stats.calls == 1));
+    }
+
+    #[test]
+    fn folded_stacks()
+    {
+        let src = "
+            fun g(n) { let s = 0; for (i in 0..n) s = s + i; return s; }
+            fun f(n) { if (n == 0) return g(100); return f(n - 1) + 1; }
+            fun h() { yield g(50); }
+            let s = f(2);
+            for (x in h()) s = s + x;
+            return s;
+        ";
+        let profile = profile_src(src);
+
+        let stacks = profile.folded_stacks();
+
+        let mut names = Vec::new();
+        for line in stacks.lines() {
+            let (names_str, time) = line.rsplit_once(' ').unwrap();
+            time.parse::<u64>().unwrap();
+            names.push(names_str);
+        }
+
+        // The tail call to g takes over the frame of the innermost f
+        assert!(names.contains(&"src;f;f;g"));
+        assert!(names.contains(&"src;h;g"));
+        assert!(!names.contains(&"src;f;f;f;g"));
+
+        // Recursive calls are counted, but their time only once
+        let f = fun_stats(&profile, "f");
+        assert_eq!(f.calls, 3);
+        assert!(f.inclusive <= fun_stats(&profile, "src").inclusive);
+
+        // The generator is entered again when resumed after yielding
+        assert_eq!(fun_stats(&profile, "h").calls, 2);
+
+        let profile = profile_src("let f = fun() {\n  return 1;\n};\nf();");
+        assert!(profile.folded_stacks().lines().any(|line| line.starts_with("src;src:2 ")));
+    }
+}
@@ -0,0 +1,563 @@
+use crate::vm::*;
+use crate::optimizer::branch_targets;
+use crate::verifier::stack_effect;
+
+/// Register-based instructions
+/// Registers are the slots of a frame: the locals come first, followed
+/// by the temporaries, which are placed where the stack-based code would
+/// keep its operands. This keeps the calling convention of the stack
+/// interpreter, with the callee and arguments in consecutive registers.
+#[derive(Debug, Copy, Clone)]
+pub enum RegInsn
+{
+    Panic,
+    Halt,
+
+    // Register moves
+    Move { dst: u32, src: u32 },
+    LoadConst { dst: u32, val: Value },
+
+    // Array element access
+    GetIndex { dst: u32, arr: u32, idx: u32 },
+
+    // Arithmetic operations
+    Add { dst: u32, a: u32, b: u32 },
+    Sub { dst: u32, a: u32, b: u32 },
+    Mul { dst: u32, a: u32, b: u32 },
+    Mod { dst: u32, a: u32, b: u32 },
+    AddConst { dst: u32, src: u32, val: i32 },
+    Neg { dst: u32, src: u32 },
+
+    // Comparisons
+    Eq { dst: u32, a: u32, b: u32 },
+    Ne { dst: u32, a: u32, b: u32 },
+    Lt { dst: u32, a: u32, b: u32 },
+    Gt { dst: u32, a: u32, b: u32 },
+    Not { dst: u32, src: u32 },
+
+    // Create an iterator over an integer range
+    Range { dst: u32, a: u32, b: u32 },
+
+    // Iteration for for-in loops
+    GetIter { dst: u32, src: u32 },
+    IterNext { iter: u32, dst: u32, offset: i32 },
+
+    // Generators, the generator is in the dst register of Resume
+    Yield { src: u32 },
+    Resume { dst: u32 },
+
+    // Branch instructions
+    Jump { offset: i32 },
+    IfTrue { src: u32, offset: i32 },
+    IfFalse { src: u32, offset: i32 },
+    JumpIfGeConst { src: u32, val: i32, offset: i32 },
+    JumpTable { src: u32, table_idx: u32 },
+
+    // The callee is in the base register, followed by the arguments,
+    // and the return value is written to the base register
+    Call { base: u32, argc: u32 },
+    TailCall { base: u32, argc: u32 },
+    Return { src: u32 },
+}
+
+/// Register-based version of the code of a function
+#[derive(Default)]
+pub struct RegCode
+{
+    pub insns: Vec<RegInsn>,
+
+    /// Jump tables referenced by JumpTable instructions
+    pub jump_tables: Vec<JumpTable>,
+
+    /// Index of the instruction to start execution at,
+    /// matching Function::entry_idxs
+    pub entry_idxs: Vec<usize>,
+
+    /// Number of registers in a frame, including the locals
+    pub num_regs: usize,
+}
+
+/// Stack depth before each instruction, None for unreachable instructions.
+/// The function must have passed verification.
+pub fn stack_depths(fun: &Function) -> Vec<Option<usize>>
+{
+    use Insn::*;
+
+    let mut depths = vec![None; fun.insns.len()];
+    let mut worklist: Vec<(usize, usize)> = fun.entry_idxs.iter().map(|idx| (*idx, 0)).collect();
+    worklist.push((0, 0));
+
+    while let Some((idx, depth)) = worklist.pop() {
+        if depths[idx].is_some() {
+            continue;
+        }
+        depths[idx] = Some(depth);
+
+        let insn = &fun.insns[idx];
+        let target = |offset: isize| (idx as isize + 1 + offset) as usize;
+        let (num_pops, num_pushes) = stack_effect(insn);
+        let depth = depth - num_pops;
+
+        match *insn {
+            Jump { offset } | IfTrue { offset } | IfFalse { offset } => worklist.push((target(offset), depth)),
+            JumpIfLocalGeConst { offset, .. } => worklist.push((target(offset as isize), depth)),
+            JumpTable { table_idx } => {
+                let table = &fun.jump_tables[table_idx];
+                for offset in table.offsets.iter().chain(std::iter::once(&table.default_offset)) {
+                    worklist.push((target(*offset), depth));
+                }
+            }
+            // The iterator is popped once it is exhausted
+            IterNext { offset } => worklist.push((target(offset), depth)),
+            _ => {}
+        }
+
+        if !matches!(insn, Panic | Halt | Return | Jump { .. } | JumpTable { .. }) {
+            worklist.push((idx + 1, depth + num_pushes));
+        }
+    }
+
+    depths
+}
+
+/// Compile a unit function and all the functions it refers to
+pub fn compile_unit(unit_fun: &mut Function)
+{
+    unit_fun.for_each_fun(|fun| fun.reg_code = compile_regs(fun));
+}
+
+/// Compile the stack-based code of a function into register-based code
+pub fn compile_regs(fun: &Function) -> RegCode
+{
+    let depths = stack_depths(fun);
+    let is_target = branch_targets(fun);
+    let max_depth = depths.iter().flatten().max().copied().unwrap_or(0) + 1;
+
+    let mut c = RegCompiler {
+        code: RegCode::default(),
+        num_locals: fun.num_locals,
+        stack: Vec::default(),
+        last_dst: None,
+        branches: Vec::default(),
+    };
+
+    // Index of the register instruction each stack instruction maps to
+    let mut new_idxs = Vec::with_capacity(fun.insns.len() + 1);
+
+    // Set if the previous instruction can continue to the next one
+    let mut falls_through = false;
+
+    for (idx, insn) in fun.insns.iter().enumerate() {
+        let depth = match depths[idx] {
+            Some(depth) => depth,
+            None => {
+                new_idxs.push(c.code.insns.len());
+                falls_through = false;
+                continue;
+            }
+        };
+
+        // Operands must be in their own registers where control flow merges
+        if is_target[idx] || !falls_through {
+            if falls_through {
+                c.flush();
+            }
+            c.reset(depth);
+        }
+        debug_assert!(c.stack.len() == depth);
+
+        new_idxs.push(c.code.insns.len());
+        falls_through = c.compile_insn(fun, idx, insn);
+    }
+
+    new_idxs.push(c.code.insns.len());
+
+    // Now that every instruction has been placed, compute the branch offsets
+    let new_offset = |from: usize, old_target: usize| -> i32 {
+        (new_idxs[old_target] as isize - (from as isize + 1)).try_into().unwrap()
+    };
+
+    for (from, old_target) in c.branches {
+        match &mut c.code.insns[from] {
+            RegInsn::Jump { offset } |
+            RegInsn::IfTrue { offset, .. } |
+            RegInsn::IfFalse { offset, .. } |
+            RegInsn::JumpIfGeConst { offset, .. } |
+            RegInsn::IterNext { offset, .. } => *offset = new_offset(from, old_target),
+            _ => panic!()
+        }
+    }
+
+    for (from, insn) in c.code.insns.iter().enumerate() {
+        if let RegInsn::JumpTable { table_idx, .. } = insn {
+            let table = &mut c.code.jump_tables[*table_idx as usize];
+            for offset in table.offsets.iter_mut().chain(std::iter::once(&mut table.default_offset)) {
+                // The offset holds the old target until it is fixed up
+                *offset = new_offset(from, *offset as usize) as isize;
+            }
+        }
+    }
+
+    c.code.entry_idxs = fun.entry_idxs.iter().map(|idx| new_idxs[*idx]).collect();
+
+    // One extra register holds constants that don't fit in instructions
+    c.code.num_regs = fun.num_locals + max_depth + 1;
+    c.code
+}
+
+/// State of the compilation of one function
+struct RegCompiler
+{
+    code: RegCode,
+
+    num_locals: usize,
+
+    /// Register holding each value of the operand stack
+    /// Values that were read from a local or copied with Dup refer
+    /// to the register they came from until they need to be moved
+    stack: Vec<u32>,
+
+    /// Destination register of the last instruction, if its
+    /// result can be written directly to another register instead
+    last_dst: Option<u32>,
+
+    /// Branch instructions, with the stack instruction index they target
+    branches: Vec<(usize, usize)>,
+}
+
+impl RegCompiler
+{
+    /// Register used for the operand at the given stack depth
+    fn temp(&self, depth: usize) -> u32
+    {
+        (self.num_locals + depth) as u32
+    }
+
+    fn emit(&mut self, insn: RegInsn)
+    {
+        self.code.insns.push(insn);
+        self.last_dst = None;
+    }
+
+    /// Emit an instruction writing its result into the register
+    /// of the operand at the top of the stack
+    fn emit_op(&mut self, make_insn: impl FnOnce(u32) -> RegInsn)
+    {
+        let dst = self.temp(self.stack.len());
+        self.emit(make_insn(dst));
+        self.stack.push(dst);
+        self.last_dst = Some(dst);
+    }
+
+    fn emit_branch(&mut self, insn: RegInsn, old_target: usize)
+    {
+        self.branches.push((self.code.insns.len(), old_target));
+        self.emit(insn);
+    }
+
+    fn pop(&mut self) -> u32
+    {
+        self.stack.pop().unwrap()
+    }
+
+    /// Move the operand at the given depth into its own register
+    fn materialize(&mut self, depth: usize)
+    {
+        let dst = self.temp(depth);
+        let src = self.stack[depth];
+
+        if src != dst {
+            self.emit(RegInsn::Move { dst, src });
+            self.stack[depth] = dst;
+        }
+    }
+
+    /// Move all the operands into their own registers
+    fn flush(&mut self)
+    {
+        for depth in 0..self.stack.len() {
+            self.materialize(depth);
+        }
+    }
+
+    /// Start a new block where all operands are in their own registers
+    fn reset(&mut self, depth: usize)
+    {
+        self.stack = (0..depth).map(|depth| self.temp(depth)).collect();
+        self.last_dst = None;
+    }
+
+    /// Move operands that refer to a local before it gets overwritten
+    fn save_local(&mut self, idx: u32)
+    {
+        for depth in 0..self.stack.len() {
+            if self.stack[depth] == idx {
+                self.materialize(depth);
+            }
+        }
+    }
+
+    /// Write the value at the top of the stack into a local
+    fn set_local(&mut self, idx: u32)
+    {
+        let src = self.pop();
+        self.save_local(idx);
+
+        // If the value was just computed into its own register,
+        // compute it directly into the local instead
+        if self.last_dst == Some(src) && src == self.temp(self.stack.len()) {
+            let last_insn = self.code.insns.last_mut().unwrap();
+            match last_insn {
+                RegInsn::Move { dst, .. } |
+                RegInsn::LoadConst { dst, .. } |
+                RegInsn::GetIndex { dst, .. } |
+                RegInsn::Add { dst, .. } |
+                RegInsn::Sub { dst, .. } |
+                RegInsn::Mul { dst, .. } |
+                RegInsn::Mod { dst, .. } |
+                RegInsn::AddConst { dst, .. } |
+                RegInsn::Neg { dst, .. } |
+                RegInsn::Eq { dst, .. } |
+                RegInsn::Ne { dst, .. } |
+                RegInsn::Lt { dst, .. } |
+                RegInsn::Gt { dst, .. } |
+                RegInsn::Not { dst, .. } |
+                RegInsn::Range { dst, .. } |
+                RegInsn::GetIter { dst, .. } => *dst = idx,
+                _ => panic!()
+            }
+            self.last_dst = None;
+        }
+        else if src != idx {
+            self.emit(RegInsn::Move { dst: idx, src });
+        }
+    }
+
+    fn binary_op(&mut self, make_insn: fn(u32, u32, u32) -> RegInsn)
+    {
+        let b = self.pop();
+        let a = self.pop();
+        self.emit_op(|dst| make_insn(dst, a, b));
+    }
+
+    fn unary_op(&mut self, make_insn: fn(u32, u32) -> RegInsn)
+    {
+        let src = self.pop();
+        self.emit_op(|dst| make_insn(dst, src));
+    }
+
+    /// Compile one stack instruction
+    /// Returns true if execution can continue to the next instruction
+    fn compile_insn(&mut self, fun: &Function, idx: usize, insn: &Insn) -> bool
+    {
+        let target = |offset: isize| (idx as isize + 1 + offset) as usize;
+
+        match *insn {
+            Insn::Panic => {
+                self.emit(RegInsn::Panic);
+                return false;
+            }
+
+            Insn::Halt => {
+                self.emit(RegInsn::Halt);
+                return false;
+            }
+
+            // Debugging is only supported by the stack backend
+            Insn::Debugger => {}
+
+            Insn::GetLocal { idx } => self.stack.push(idx as u32),
+            Insn::SetLocal { idx } => self.set_local(idx as u32),
+            Insn::Push { val } => self.emit_op(|dst| RegInsn::LoadConst { dst, val }),
+
+            Insn::Pop => {
+                self.pop();
+            }
+
+            Insn::Dup => {
+                let top = *self.stack.last().unwrap();
+                self.stack.push(top);
+            }
+
+            Insn::GetIndex => self.binary_op(|dst, arr, idx| RegInsn::GetIndex { dst, arr, idx }),
+            Insn::Add => self.binary_op(|dst, a, b| RegInsn::Add { dst, a, b }),
+            Insn::Sub => self.binary_op(|dst, a, b| RegInsn::Sub { dst, a, b }),
+            Insn::Mul => self.binary_op(|dst, a, b| RegInsn::Mul { dst, a, b }),
+            Insn::Mod => self.binary_op(|dst, a, b| RegInsn::Mod { dst, a, b }),
+            Insn::Eq => self.binary_op(|dst, a, b| RegInsn::Eq { dst, a, b }),
+            Insn::Ne => self.binary_op(|dst, a, b| RegInsn::Ne { dst, a, b }),
+            Insn::Lt => self.binary_op(|dst, a, b| RegInsn::Lt { dst, a, b }),
+            Insn::Gt => self.binary_op(|dst, a, b| RegInsn::Gt { dst, a, b }),
+            Insn::Range => self.binary_op(|dst, a, b| RegInsn::Range { dst, a, b }),
+            Insn::Neg => self.unary_op(|dst, src| RegInsn::Neg { dst, src }),
+            Insn::Not => self.unary_op(|dst, src| RegInsn::Not { dst, src }),
+            Insn::GetIter => self.unary_op(|dst, src| RegInsn::GetIter { dst, src }),
+
+            Insn::AddConst { val } => {
+                let src = self.pop();
+                match i32::try_from(val) {
+                    Ok(val) => self.emit_op(|dst| RegInsn::AddConst { dst, src, val }),
+                    Err(_) => {
+                        // Load the constant into the spare register
+                        let tmp = self.temp(self.stack.len() + 1);
+                        self.emit(RegInsn::LoadConst { dst: tmp, val: Value::Int64(val) });
+                        self.emit_op(|dst| RegInsn::Add { dst, a: src, b: tmp });
+                    }
+                }
+            }
+
+            Insn::AddLocalConst { idx, val } => {
+                self.save_local(idx);
+                self.emit(RegInsn::AddConst { dst: idx, src: idx, val });
+            }
+
+            Insn::Le | Insn::Ge => panic!("unsupported instruction {:?}", insn),
+
+            Insn::Jump { offset } => {
+                self.flush();
+                self.emit_branch(RegInsn::Jump { offset: 0 }, target(offset));
+                return false;
+            }
+
+            Insn::IfTrue { offset } => {
+                let src = self.pop();
+                self.flush();
+                self.emit_branch(RegInsn::IfTrue { src, offset: 0 }, target(offset));
+            }
+
+            Insn::IfFalse { offset } => {
+                let src = self.pop();
+                self.flush();
+                self.emit_branch(RegInsn::IfFalse { src, offset: 0 }, target(offset));
+            }
+
+            Insn::JumpIfLocalGeConst { idx, val, offset } => {
+                self.flush();
+                self.emit_branch(RegInsn::JumpIfGeConst { src: idx, val, offset: 0 }, target(offset as isize));
+            }
+
+            Insn::JumpTable { table_idx } => {
+                let src = self.pop();
+                self.flush();
+
+                // Store the old targets, which get fixed up once
+                // all instructions have been placed
+                let table = &fun.jump_tables[table_idx];
+                let table_idx = self.code.jump_tables.len() as u32;
+                self.code.jump_tables.push(JumpTable {
+                    min_val: table.min_val,
+                    offsets: table.offsets.iter().map(|offset| target(*offset) as isize).collect(),
+                    default_offset: target(table.default_offset) as isize,
+                });
+
+                self.emit(RegInsn::JumpTable { src, table_idx });
+                return false;
+            }
+
+            Insn::IterNext { offset } => {
+                self.flush();
+                let iter = self.temp(self.stack.len() - 1);
+                let dst = self.temp(self.stack.len());
+                self.emit_branch(RegInsn::IterNext { iter, dst, offset: 0 }, target(offset));
+                self.stack.push(dst);
+            }
+
+            Insn::Yield => {
+                let src = self.pop();
+                self.flush();
+                self.emit(RegInsn::Yield { src });
+
+                // The registers are restored when the generator resumes
+                self.reset(self.stack.len());
+            }
+
+            Insn::Resume => {
+                let depth = self.stack.len() - 1;
+                self.materialize(depth);
+                self.emit(RegInsn::Resume { dst: self.temp(depth) });
+            }
+
+            Insn::Call { argc } | Insn::TailCall { argc } => {
+                // The callee and arguments must be in consecutive registers
+                let base_depth = self.stack.len() - argc - 1;
+                for depth in base_depth..self.stack.len() {
+                    self.materialize(depth);
+                }
+
+                let base = self.temp(base_depth);
+                let argc = argc as u32;
+                match insn {
+                    Insn::Call { .. } => self.emit(RegInsn::Call { base, argc }),
+                    _ => self.emit(RegInsn::TailCall { base, argc }),
+                }
+
+                self.stack.truncate(base_depth);
+                self.stack.push(base);
+            }
+
+            Insn::Return => {
+                let src = self.pop();
+                self.emit(RegInsn::Return { src });
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+
+    fn compile_src(src: &str) -> RegCode
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        compile_regs(&unit_fn)
+    }
+
+    #[test]
+    fn locals_as_registers()
+    {
+        // s = s + i writes the local directly, without moves
+        let code = compile_src("let s = 0; let i = 0; while (i < 10) { s = s + i; i = i + 1; } return s;");
+        assert!(code.insns.iter().any(|insn| matches!(insn, RegInsn::Add { dst: 0, a: 0, b: 1 })));
+        assert!(code.insns.iter().any(|insn| matches!(insn, RegInsn::AddConst { dst: 1, src: 1, val: 1 })));
+        assert!(!code.insns.iter().any(|insn| matches!(insn, RegInsn::Move { .. })));
+    }
+
+    #[test]
+    fn call_args()
+    {
+        // Arguments read from locals get moved next to the callee
+        let code = compile_src("let x = 1; let y = 2; println(x, y);");
+        let num_moves = code.insns.iter().filter(|insn| matches!(insn, RegInsn::Move { .. })).count();
+        assert_eq!(num_moves, 2);
+        assert!(code.insns.iter().any(|insn| matches!(insn, RegInsn::Call { base: 2, argc: 2 })));
+    }
+
+    #[test]
+    fn local_overwritten()
+    {
+        // The old value of x must be saved before x is assigned
+        let code = compile_src("let x = 1; let y = x + (x = 5); return y;");
+        assert!(code.insns.iter().any(|insn| matches!(insn, RegInsn::Move { src: 0, .. })));
+    }
+
+    #[test]
+    fn jump_table()
+    {
+        let code = compile_src("let x = 2; match (x) { 0 => x = 1; 1 => x = 2; 2 => x = 3; } return x;");
+        assert_eq!(code.jump_tables.len(), 1);
+
+        let table_idx = code.insns.iter().position(|insn| matches!(insn, RegInsn::JumpTable { .. })).unwrap();
+        let table = &code.jump_tables[0];
+        for offset in table.offsets.iter().chain(std::iter::once(&table.default_offset)) {
+            let target = table_idx as isize + 1 + offset;
+            assert!(target >= 0 && (target as usize) < code.insns.len());
+        }
+    }
+}
@@ -1,16 +1,16 @@
 use std::io;
-use crate::vm::Value;
-use Value::*;
+use crate::vm::{VM, Value, ValueKind, HostIter, StringBuilder};
+use ValueKind::*;
 
-pub type HostFn = fn(args: *const Value, argc: usize) -> Value;
+pub type HostFn = fn(vm: &mut VM, args: *const Value, argc: usize) -> Value;
 
 /// Print values to standard output
-fn print(args: *const Value, argc: usize) -> Value
+fn print(vm: &mut VM, args: *const Value, argc: usize) -> Value
 {
     for i in 0..argc {
         let arg = unsafe { *args.add(i) };
 
-        match arg {
+        match arg.kind() {
             Int64(v) => print!("{}", v),
             Str(str_ptr) => print!("{}", unsafe{ &*str_ptr }),
             Nil => print!("nil"),
@@ -22,29 +22,321 @@ fn print(args: *const Value, argc: usize) -> Value
 }
 
 /// Print values to standard output, and then output a newline
-fn println(args: *const Value, argc: usize) -> Value
+fn println(vm: &mut VM, args: *const Value, argc: usize) -> Value
 {
-    print(args, argc);
+    print(vm, args, argc);
     println!();
     Value::Nil
 }
 
 /// Read an integer from standard input
-fn read_int(args: *const Value, argc: usize) -> Value
+fn read_int(vm: &mut VM, args: *const Value, argc: usize) -> Value
 {
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
     let n: i64 = input.trim().parse().unwrap();
-    Value::Int64(n)
+    vm.int64(n)
+}
+
+/// Get the length of an array, string or string builder
+fn len(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 1 {
+        panic!("len expects 1 argument");
+    }
+
+    match unsafe { *args }.kind() {
+        Array(arr_ptr) => Value::Int64(unsafe { &*arr_ptr }.len() as i64),
+        Str(str_ptr) => Value::Int64(unsafe { &*str_ptr }.chars().count() as i64),
+        Builder(builder_ptr) => Value::Int64(unsafe { &*builder_ptr }.num_chars() as i64),
+        _ => panic!("len expects an array or a string")
+    }
+}
+
+/// Create an empty string builder
+fn string_builder(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 0 {
+        panic!("string_builder expects no arguments");
+    }
+
+    vm.into_gc_heap(StringBuilder::default())
+}
+
+/// Append a string or an integer to a string builder
+/// The buffer grows geometrically, so building a string is linear time
+fn append(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 2 {
+        panic!("append expects 2 arguments");
+    }
+
+    let (builder_val, val) = unsafe { (*args, *args.add(1)) };
+
+    let builder = match builder_val.kind() {
+        Builder(builder_ptr) => unsafe { &mut *builder_ptr },
+        _ => panic!("append expects a string builder")
+    };
+
+    let int_str;
+    let s = match val.kind() {
+        Str(str_ptr) => unsafe { (*str_ptr).as_str() },
+        Int64(v) => {
+            int_str = v.to_string();
+            int_str.as_str()
+        }
+        _ => panic!("append expects a string or an integer")
+    };
+
+    if builder.as_str().len() + s.len() > vm.config().max_string_len {
+        vm.raise_error("string too long");
+        return Value::Nil;
+    }
+
+    // Check the heap limit before the buffer grows
+    if !vm.grow_object(builder_val, builder.growth(s.len())) {
+        return Value::Nil;
+    }
+
+    builder.push_str(s);
+
+    Value::Nil
+}
+
+/// Copy the contents of a string builder into a new string
+fn to_string(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 1 {
+        panic!("to_string expects 1 argument");
+    }
+
+    match unsafe { *args }.kind() {
+        Builder(builder_ptr) => vm.into_gc_heap(unsafe { &*builder_ptr }.as_str()),
+        _ => panic!("to_string expects a string builder")
+    }
+}
+
+/// Get the interned string with the same contents as a string
+fn intern(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 1 {
+        panic!("intern expects 1 argument");
+    }
+
+    match unsafe { *args }.kind() {
+        Str(str_ptr) => vm.intern(unsafe { &*str_ptr }),
+        _ => panic!("intern expects a string")
+    }
+}
+
+/// Run a full garbage collection
+fn gc(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    vm.gc_collect();
+    Value::Nil
+}
+
+/// Get a garbage collector statistic by name
+fn heap_stats(vm: &mut VM, args: *const Value, argc: usize) -> Value
+{
+    if argc != 1 {
+        panic!("heap_stats expects the name of a statistic");
+    }
+
+    let name = match unsafe { *args }.kind() {
+        Str(str_ptr) => unsafe { &*str_ptr },
+        _ => panic!("heap_stats expects the name of a statistic")
+    };
+
+    let stats = vm.gc_stats();
+
+    let val = match name.as_str() {
+        "collections" => stats.num_collections(),
+        "minor_collections" => stats.num_minor,
+        "major_collections" => stats.num_major,
+        "pause_us" => stats.total_pause().as_micros() as usize,
+        "max_pause_us" => stats.max_pause.as_micros() as usize,
+        "bytes_freed" => stats.bytes_freed,
+        "live_bytes" => stats.live_bytes,
+        "live_objects" => stats.live_objects.total(),
+        "functions" => stats.live_objects.fun,
+        "strings" => stats.live_objects.str,
+        "iterators" => stats.live_objects.iter,
+        "generators" => stats.live_objects.gen,
+        "arrays" => stats.live_objects.array,
+        "builders" => stats.live_objects.builder,
+        "big_ints" => stats.live_objects.int,
+        _ => panic!("unknown heap statistic {}", name)
+    };
+
+    Value::Int64(val as i64)
+}
+
+/// Runtime functions, by name
+const RUNTIME_FNS: &[(&str, HostFn)] = &[
+    ("print", print),
+    ("println", println),
+    ("read_int", read_int),
+    ("len", len),
+    ("intern", intern),
+    ("string_builder", string_builder),
+    ("append", append),
+    ("to_string", to_string),
+    ("gc", gc),
+    ("heap_stats", heap_stats),
+];
+
+/// Reference to a runtime function by its index in the runtime function
+/// table. The address of a function isn't a stable identity, since
+/// identical functions can be merged, and one function can have different
+/// addresses in different codegen units.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RuntimeFn(usize);
+
+impl RuntimeFn
+{
+    /// Get the runtime function at an index of the table,
+    /// which must come from RuntimeFn::idx
+    pub fn from_idx(idx: usize) -> Self
+    {
+        debug_assert!(idx < RUNTIME_FNS.len());
+        Self(idx)
+    }
+
+    pub fn idx(self) -> usize
+    {
+        self.0
+    }
+
+    /// Name of the function, used to serialize references to it
+    pub fn name(self) -> &'static str
+    {
+        RUNTIME_FNS[self.0].0
+    }
+
+    pub fn call(self, vm: &mut VM, args: *const Value, argc: usize) -> Value
+    {
+        (RUNTIME_FNS[self.0].1)(vm, args, argc)
+    }
 }
 
 /// Look up a runtime function by name
-pub fn get_runtime_fn(name: &str) -> Option<HostFn>
+pub fn get_runtime_fn(name: &str) -> Option<RuntimeFn>
+{
+    RUNTIME_FNS.iter().position(|(fn_name, _)| *fn_name == name).map(RuntimeFn)
+}
+
+/// Iterator over a range of integers, excluding the end value
+pub struct RangeIter
+{
+    next: i64,
+    end: i64,
+}
+
+impl RangeIter
+{
+    pub fn new(start: i64, end: i64) -> Self
+    {
+        Self { next: start, end }
+    }
+}
+
+impl HostIter for RangeIter
+{
+    fn next(&mut self, vm: &mut VM) -> Option<Value>
+    {
+        if self.next >= self.end {
+            return None;
+        }
+
+        let val = self.next;
+        self.next += 1;
+        Some(vm.int64(val))
+    }
+
+    fn trace(&self, roots: &mut Vec<Value>)
+    {
+    }
+}
+
+/// Iterator over the characters of a string
+struct StrIter
+{
+    str_val: Value,
+
+    /// Byte position of the next character
+    pos: usize,
+}
+
+impl HostIter for StrIter
+{
+    fn next(&mut self, vm: &mut VM) -> Option<Value>
+    {
+        let str = match self.str_val.kind() {
+            Str(str_ptr) => unsafe { &*str_ptr },
+            _ => panic!()
+        };
+
+        let ch = str[self.pos..].chars().next()?;
+        self.pos += ch.len_utf8();
+        Some(vm.into_gc_heap(ch.to_string()))
+    }
+
+    fn trace(&self, roots: &mut Vec<Value>)
+    {
+        roots.push(self.str_val);
+    }
+}
+
+/// Iterator over the elements of an array
+struct ArrayIter
+{
+    arr_val: Value,
+
+    /// Index of the next element
+    idx: usize,
+}
+
+impl HostIter for ArrayIter
+{
+    fn next(&mut self, vm: &mut VM) -> Option<Value>
+    {
+        let arr = match self.arr_val.kind() {
+            Array(arr_ptr) => unsafe { &*arr_ptr },
+            _ => panic!()
+        };
+
+        let val = arr.get(self.idx)?;
+        self.idx += 1;
+        Some(*val)
+    }
+
+    fn trace(&self, roots: &mut Vec<Value>)
+    {
+        roots.push(self.arr_val);
+    }
+}
+
+/// Create an iterator over a value, or return None if the
+/// value doesn't have the type this function iterates over
+pub type IterFn = fn(val: Value) -> Option<Box<dyn HostIter>>;
+
+/// Iterable types available in every VM, tried in order by for-in
+/// loops. Host code can add more with VM::register_iterable.
+pub const RUNTIME_ITERABLES: &[IterFn] = &[str_iter, array_iter];
+
+fn str_iter(val: Value) -> Option<Box<dyn HostIter>>
+{
+    match val.kind() {
+        Str(_) => Some(Box::new(StrIter { str_val: val, pos: 0 })),
+        _ => None
+    }
+}
+
+fn array_iter(val: Value) -> Option<Box<dyn HostIter>>
 {
-    match name {
-        "print" => Some(print),
-        "println" => Some(println),
-        "read_int" => Some(read_int),
+    match val.kind() {
+        Array(_) => Some(Box::new(ArrayIter { arr_val: val, idx: 0 })),
         _ => None
     }
 }
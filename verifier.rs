@@ -0,0 +1,280 @@
+use std::fmt;
+use crate::vm::*;
+use crate::bytecode::list_funs;
+
+/// Error found when verifying the bytecode of a function
+#[derive(Debug)]
+pub struct VerifyError
+{
+    pub fun_name: String,
+
+    /// Instruction the error was found at, if any
+    pub insn_idx: Option<usize>,
+
+    pub msg: String,
+}
+
+impl fmt::Display for VerifyError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.insn_idx {
+            Some(insn_idx) => write!(f, "{} at instruction {} of function {}", self.msg, insn_idx, self.fun_name),
+            None => write!(f, "{} in function {}", self.msg, self.fun_name)
+        }
+    }
+}
+
+/// Verify a unit function and all the functions it refers to
+pub fn verify_unit(unit_fun: &Function) -> Result<(), VerifyError>
+{
+    for fun_ptr in list_funs(unit_fun) {
+        verify_fun(unsafe { &*fun_ptr })?;
+    }
+
+    Ok(())
+}
+
+/// Number of values an instruction pops, and the number it pushes
+/// when execution continues to the next instruction
+pub(crate) fn stack_effect(insn: &Insn) -> (usize, usize)
+{
+    use Insn::*;
+
+    match *insn {
+        Panic | Halt | Debugger | Jump { .. } | JumpIfLocalGeConst { .. } => (0, 0),
+        GetLocal { .. } | Push { .. } => (0, 1),
+        Dup => (1, 2),
+        SetLocal { .. } | Pop | IfTrue { .. } | IfFalse { .. } | JumpTable { .. } => (1, 0),
+        GetIndex | Add | Sub | Mul | Mod | Eq | Ne | Lt | Le | Gt | Ge | Range => (2, 1),
+        Neg | Not | GetIter | Resume | Return | AddConst { .. } => (1, 1),
+        Yield => (1, 0),
+        AddLocalConst { .. } => (0, 0),
+        // The iterator stays on the stack under the next value
+        IterNext { .. } => (1, 2),
+        // The callee is below the arguments
+        Call { argc } | TailCall { argc } => (argc + 1, 1),
+    }
+}
+
+/// Check that a function can run without reading or branching out of
+/// bounds. Jump targets and local indices must be in range, the stack
+/// depth must be the same along every path to an instruction and can't
+/// go negative, and no path may run past the last instruction.
+pub fn verify_fun(fun: &Function) -> Result<(), VerifyError>
+{
+    use Insn::*;
+
+    let error = |insn_idx: Option<usize>, msg: &str| VerifyError {
+        fun_name: fun.name.clone(),
+        insn_idx,
+        msg: msg.to_string(),
+    };
+
+    if fun.insns.is_empty() {
+        return Err(error(None, "function has no instructions"));
+    }
+
+    if fun.params.len() > fun.num_locals {
+        return Err(error(None, "more parameters than local variables"));
+    }
+
+    // Calls start at one of the entry points, depending on how many of
+    // the parameters with a default value are supplied. Unit functions
+    // aren't called, so they have no parameters or entry points.
+    let num_defaults = fun.params.len().checked_sub(fun.num_required + fun.has_rest as usize);
+    let num_entries = num_defaults.map(|num_defaults| num_defaults + 1);
+    let is_unit = fun.params.is_empty() && fun.entry_idxs.is_empty();
+    if !is_unit && num_entries != Some(fun.entry_idxs.len()) {
+        return Err(error(None, "inconsistent parameter and entry point counts"));
+    }
+
+    if !fun.insn_lines.is_empty() && fun.insn_lines.len() != fun.insns.len() {
+        return Err(error(None, "line table doesn't match the instructions"));
+    }
+
+    for var in &fun.local_vars {
+        if var.idx >= fun.num_locals || var.start > var.end || var.end > fun.insns.len() {
+            return Err(error(None, &format!("invalid range for local variable {}", var.name)));
+        }
+    }
+
+    // Branch target of an instruction, if it's in bounds
+    let target = |insn_idx: usize, offset: isize| {
+        let target_idx = insn_idx as isize + 1 + offset;
+        if target_idx >= 0 && (target_idx as usize) < fun.insns.len() {
+            Ok(target_idx as usize)
+        } else {
+            Err(error(Some(insn_idx), "branch target out of bounds"))
+        }
+    };
+
+    // Check the operands of every instruction, including unreachable ones
+    for (insn_idx, insn) in fun.insns.iter().enumerate() {
+        match *insn {
+            GetLocal { idx } | SetLocal { idx } if idx >= fun.num_locals => {
+                return Err(error(Some(insn_idx), "local variable index out of bounds"));
+            }
+            AddLocalConst { idx, .. } | JumpIfLocalGeConst { idx, .. } if idx as usize >= fun.num_locals => {
+                return Err(error(Some(insn_idx), "local variable index out of bounds"));
+            }
+            JumpTable { table_idx } if table_idx >= fun.jump_tables.len() => {
+                return Err(error(Some(insn_idx), "jump table index out of bounds"));
+            }
+            Yield if !fun.is_generator => {
+                return Err(error(Some(insn_idx), "yield outside of a generator"));
+            }
+            _ => {}
+        }
+
+        match *insn {
+            Jump { offset } | IfTrue { offset } | IfFalse { offset } | IterNext { offset } => {
+                target(insn_idx, offset)?;
+            }
+            JumpIfLocalGeConst { offset, .. } => {
+                target(insn_idx, offset as isize)?;
+            }
+            JumpTable { table_idx } => {
+                let table = &fun.jump_tables[table_idx];
+                for offset in table.offsets.iter().chain(std::iter::once(&table.default_offset)) {
+                    target(insn_idx, *offset)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Propagate stack depths along every path from the entry points
+    let mut depths: Vec<Option<usize>> = vec![None; fun.insns.len()];
+    let mut worklist = vec![(0, 0)];
+    for entry_idx in &fun.entry_idxs {
+        if *entry_idx >= fun.insns.len() {
+            return Err(error(None, "entry point out of bounds"));
+        }
+        worklist.push((*entry_idx, 0));
+    }
+
+    while let Some((insn_idx, depth)) = worklist.pop() {
+        if let Some(prev_depth) = depths[insn_idx] {
+            if prev_depth != depth {
+                return Err(error(Some(insn_idx), "inconsistent stack depth"));
+            }
+            continue;
+        }
+        depths[insn_idx] = Some(depth);
+
+        let insn = &fun.insns[insn_idx];
+        let (num_pops, num_pushes) = stack_effect(insn);
+        if depth < num_pops {
+            return Err(error(Some(insn_idx), "stack underflow"));
+        }
+        let depth = depth - num_pops;
+
+        match *insn {
+            Jump { offset } => worklist.push((target(insn_idx, offset)?, depth)),
+            IfTrue { offset } | IfFalse { offset } => worklist.push((target(insn_idx, offset)?, depth)),
+            JumpIfLocalGeConst { offset, .. } => worklist.push((target(insn_idx, offset as isize)?, depth)),
+            JumpTable { table_idx } => {
+                let table = &fun.jump_tables[table_idx];
+                for offset in table.offsets.iter().chain(std::iter::once(&table.default_offset)) {
+                    worklist.push((target(insn_idx, *offset)?, depth));
+                }
+            }
+            // The iterator is popped once it is exhausted
+            IterNext { offset } => worklist.push((target(insn_idx, offset)?, depth)),
+            _ => {}
+        }
+
+        // Instructions that execution can't continue after, which doesn't
+        // include tail calls since calls to host functions return normally
+        if matches!(insn, Panic | Halt | Return | Jump { .. } | JumpTable { .. }) {
+            continue;
+        }
+
+        if insn_idx + 1 >= fun.insns.len() {
+            return Err(error(Some(insn_idx), "execution can run past the last instruction"));
+        }
+
+        worklist.push((insn_idx + 1, depth + num_pushes));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+    use Insn::*;
+
+    fn verify(insns: Vec<Insn>) -> Result<(), String>
+    {
+        let mut fun = Function::new("f");
+        fun.num_locals = 1;
+        fun.insns = insns;
+        verify_fun(&fun).map_err(|err| err.msg)
+    }
+
+    fn int(v: i64) -> Value
+    {
+        Value::Int64(v)
+    }
+
+    #[test]
+    fn valid_code()
+    {
+        assert_eq!(verify(vec![Push { val: int(1) }, Return]), Ok(()));
+        assert_eq!(verify(vec![Push { val: int(1) }, IfTrue { offset: 1 }, Halt, GetLocal { idx: 0 }, Return]), Ok(()));
+
+        // Everything the parser generates is valid
+        let mut vm = VM::new();
+        for src in [
+            "let s = 0; for (i in 0..10) s = s + i; return s;",
+            "fun f(a, b = 2, ...rest) { return f(a); } fun g() { yield 1; } for (x in g()) f(x);",
+            "let x = 2; match (x) { 0 => x = 1; 1 => x = 2; 2 => x = 3; }",
+            "let i = 0; while (i < 10) { if (i == 5) i = i + 2; else i = i + 1; }",
+        ] {
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            verify_unit(&unit_fn).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_code()
+    {
+        assert_eq!(verify(vec![Jump { offset: 5 }]), Err("branch target out of bounds".to_string()));
+        assert_eq!(verify(vec![Jump { offset: -2 }]), Err("branch target out of bounds".to_string()));
+        assert_eq!(
+            verify(vec![Push { val: Value::Nil }, Return, Jump { offset: 100 }]),
+            Err("branch target out of bounds".to_string())
+        );
+        assert_eq!(verify(vec![GetLocal { idx: 1 }, Return]), Err("local variable index out of bounds".to_string()));
+        assert_eq!(verify(vec![AddLocalConst { idx: 3, val: 1 }, Halt]), Err("local variable index out of bounds".to_string()));
+        assert_eq!(verify(vec![Pop, Halt]), Err("stack underflow".to_string()));
+        assert_eq!(verify(vec![Push { val: int(1) }, Call { argc: 1 }, Return]), Err("stack underflow".to_string()));
+        assert_eq!(verify(vec![Push { val: int(1) }]), Err("execution can run past the last instruction".to_string()));
+        assert_eq!(verify(vec![Insn::JumpTable { table_idx: 0 }]), Err("jump table index out of bounds".to_string()));
+        assert_eq!(verify(vec![Push { val: int(1) }, Yield, Return]), Err("yield outside of a generator".to_string()));
+        assert_eq!(verify(vec![]), Err("function has no instructions".to_string()));
+
+        // One path pushes a value and the other doesn't
+        assert_eq!(
+            verify(vec![
+                Push { val: int(1) },
+                IfTrue { offset: 1 },
+                Push { val: int(2) },
+                Push { val: int(3) },
+                Return,
+            ]),
+            Err("inconsistent stack depth".to_string())
+        );
+
+        let mut fun = Function::new("f");
+        fun.params = vec!["a".to_string(), "b".to_string()];
+        fun.num_locals = 2;
+        fun.insns = vec![Push { val: int(1) }, Return];
+        fun.entry_idxs = vec![0];
+        let err = verify_fun(&fun).unwrap_err();
+        assert_eq!(err.to_string(), "inconsistent parameter and entry point counts in function f");
+    }
+}
@@ -1,18 +1,252 @@
-use std::collections::HashMap;
-use crate::runtime::HostFn;
+use std::cell::{Cell, OnceCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem::{size_of, size_of_val};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::runtime::{RuntimeFn, IterFn, RangeIter, RUNTIME_ITERABLES};
+use crate::regvm::{RegInsn, RegCode};
+use crate::jit::{self, JitCode, JitCtx, JIT_CONTINUE, JIT_BRANCH, JIT_PANIC};
+use crate::profiler::Profile;
 
-/// Dynamically typed value
+/// Dynamically typed value, packed into a single 64-bit word
+/// The low bits hold a tag and the high bits hold an integer or
+/// a pointer. Integers too large to be packed are boxed on the heap.
+/// Use kind() to unpack a value so it can be matched on.
+#[derive(Copy, Clone)]
+pub struct Value(u64);
+
+/// Unpacked form of a value, used to match on values
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub enum Value
+pub enum ValueKind
 {
     Int64(i64),
     UInt64(u64),
-    HostFn(HostFn),
+    HostFn(RuntimeFn),
     Fun(*mut Function),
     Str(*mut String),
+    Iter(*mut Box<dyn HostIter>),
+    Gen(*mut Generator),
+    Array(*mut Vec<Value>),
+    Builder(*mut StringBuilder),
     Nil,
 }
 
+/// Integer that doesn't fit in the bits of a packed value
+#[derive(Debug, Copy, Clone)]
+pub enum BoxedInt
+{
+    Int64(i64),
+    UInt64(u64),
+}
+
+pub const TAG_BITS: u32 = 4;
+pub const TAG_MASK: u64 = (1 << TAG_BITS) - 1;
+
+pub const TAG_INT64: u64 = 0;
+const TAG_UINT64: u64 = 1;
+const TAG_HOSTFN: u64 = 2;
+const TAG_FUN: u64 = 3;
+pub const TAG_STR: u64 = 4;
+const TAG_ITER: u64 = 5;
+const TAG_GEN: u64 = 6;
+const TAG_ARRAY: u64 = 7;
+const TAG_NIL: u64 = 8;
+const TAG_BUILDER: u64 = 9;
+pub const TAG_BOXED: u64 = 10;
+
+/// Constructors, named after the kinds of values they produce
+#[allow(non_snake_case, non_upper_case_globals)]
+impl Value
+{
+    pub const Nil: Value = Value(TAG_NIL);
+
+    /// Range of integers that can be packed without boxing
+    pub const MIN_PACKED_INT: i64 = i64::MIN >> TAG_BITS;
+    pub const MAX_PACKED_INT: i64 = i64::MAX >> TAG_BITS;
+
+    /// Make an integer value, which must fit in a packed value
+    /// Use VM::int64 for integers that may need to be boxed
+    #[inline(always)]
+    pub fn Int64(v: i64) -> Value
+    {
+        match Value::try_int64(v) {
+            Some(val) => val,
+            None => needs_boxing(v as i128)
+        }
+    }
+
+    /// Make an unsigned integer value, which must fit in a packed value
+    /// Use VM::uint64 for integers that may need to be boxed
+    pub fn UInt64(v: u64) -> Value
+    {
+        match Value::try_uint64(v) {
+            Some(val) => val,
+            None => needs_boxing(v as i128)
+        }
+    }
+
+    pub fn HostFn(f: RuntimeFn) -> Value
+    {
+        Value::from_addr(f.idx(), TAG_HOSTFN)
+    }
+
+    pub fn Fun(p: *mut Function) -> Value
+    {
+        Value::from_addr(p as usize, TAG_FUN)
+    }
+
+    pub fn Str(p: *mut String) -> Value
+    {
+        Value::from_addr(p as usize, TAG_STR)
+    }
+
+    pub fn Iter(p: *mut Box<dyn HostIter>) -> Value
+    {
+        Value::from_addr(p as usize, TAG_ITER)
+    }
+
+    pub fn Gen(p: *mut Generator) -> Value
+    {
+        Value::from_addr(p as usize, TAG_GEN)
+    }
+
+    pub fn Array(p: *mut Vec<Value>) -> Value
+    {
+        Value::from_addr(p as usize, TAG_ARRAY)
+    }
+
+    pub fn Builder(p: *mut StringBuilder) -> Value
+    {
+        Value::from_addr(p as usize, TAG_BUILDER)
+    }
+}
+
+impl Value
+{
+    /// Pack an integer, if it fits without boxing
+    #[inline(always)]
+    pub fn try_int64(v: i64) -> Option<Value>
+    {
+        let word = (v << TAG_BITS) as u64;
+        if ((word as i64) >> TAG_BITS) != v {
+            return None;
+        }
+        Some(Value(word | TAG_INT64))
+    }
+
+    /// Pack an unsigned integer, if it fits without boxing
+    pub fn try_uint64(v: u64) -> Option<Value>
+    {
+        if v >> (64 - TAG_BITS) != 0 {
+            return None;
+        }
+        Some(Value((v << TAG_BITS) | TAG_UINT64))
+    }
+
+    /// Unpack the value
+    #[inline(always)]
+    pub fn kind(self) -> ValueKind
+    {
+        use ValueKind::*;
+
+        // Integers are the most common case
+        if self.0 & TAG_MASK == TAG_INT64 {
+            return Int64((self.0 as i64) >> TAG_BITS);
+        }
+
+        let payload = self.0 >> TAG_BITS;
+
+        match self.0 & TAG_MASK {
+            TAG_UINT64 => UInt64(payload),
+            TAG_HOSTFN => HostFn(RuntimeFn::from_idx(payload as usize)),
+            TAG_FUN => Fun(payload as *mut Function),
+            TAG_STR => Str(payload as *mut String),
+            TAG_ITER => Iter(payload as *mut Box<dyn HostIter>),
+            TAG_GEN => Gen(payload as *mut Generator),
+            TAG_ARRAY => Array(payload as *mut Vec<Value>),
+            TAG_BUILDER => Builder(payload as *mut StringBuilder),
+            TAG_BOXED => match unsafe { *(payload as *const BoxedInt) } {
+                BoxedInt::Int64(v) => Int64(v),
+                BoxedInt::UInt64(v) => UInt64(v),
+            }
+            _ => Nil,
+        }
+    }
+
+    /// Get the packed representation of the value
+    pub fn bits(self) -> u64
+    {
+        self.0
+    }
+
+    /// Pack a pointer or runtime function index with the given tag
+    #[inline(always)]
+    fn from_addr(addr: usize, tag: u64) -> Self
+    {
+        debug_assert!((addr as u64) >> (64 - TAG_BITS) == 0);
+        Value(((addr as u64) << TAG_BITS) | tag)
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn needs_boxing(v: i128) -> !
+{
+    panic!("integer {} must be boxed, use VM::int64 or VM::uint64", v);
+}
+
+/// Integers are packed whenever they fit, so two values can only
+/// be equal with different bits if they are both boxed integers
+impl PartialEq for Value
+{
+    #[inline(always)]
+    fn eq(&self, other: &Value) -> bool
+    {
+        if self.0 == other.0 {
+            return true;
+        }
+
+        if self.0 & TAG_MASK == TAG_BOXED && other.0 & TAG_MASK == TAG_BOXED {
+            return self.kind() == other.kind();
+        }
+
+        false
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialEq<ValueKind> for Value
+{
+    fn eq(&self, other: &ValueKind) -> bool
+    {
+        self.kind() == *other
+    }
+}
+
+impl fmt::Debug for Value
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        self.kind().fmt(f)
+    }
+}
+
+/// Iterator protocol used by for-in loops
+/// Host code implements this trait to make new types iterable
+pub trait HostIter
+{
+    /// Produce the next value, or None once the iteration is done
+    fn next(&mut self, vm: &mut VM) -> Option<Value>;
+
+    /// Push the heap values this iterator refers to so they can be marked by the GC
+    /// Iterators that store new heap values after being allocated must
+    /// call VM::write_barrier so that those values are kept alive
+    fn trace(&self, roots: &mut Vec<Value>);
+}
+
 /// Instruction opcode types
 #[derive(Debug, Copy, Clone)]
 pub enum Insn
@@ -20,6 +254,9 @@ pub enum Insn
     Panic,
     Halt,
 
+    // Stop in the debugger, if debugging is enabled
+    Debugger,
+
     // Local variable access
     GetLocal { idx: usize },
     SetLocal { idx: usize },
@@ -29,6 +266,9 @@ pub enum Insn
     Pop,
     Dup,
 
+    // Array element access
+    GetIndex,
+
     // Arithmetic operations
     Add,
     Sub,
@@ -49,51 +289,340 @@ pub enum Insn
     // Unary logical not
     Not,
 
+    // Create an iterator over an integer range
+    Range,
+
+    // Iteration for for-in loops
+    GetIter,
+    IterNext { offset: isize },
+
+    // Generators
+    Yield,
+    Resume,
+
     // Branch instructions
     Jump { offset: isize },
     IfTrue { offset: isize },
     IfFalse { offset: isize },
+    JumpTable { table_idx: usize },
     Call { argc: usize },
+    TailCall { argc: usize },
     Return,
+
+    // Superinstructions produced by the optimizer
+    AddConst { val: i64 },
+    AddLocalConst { idx: u32, val: i32 },
+    JumpIfLocalGeConst { idx: u32, val: i32, offset: i32 },
+}
+
+impl Insn
+{
+    /// Name of the opcode, without the operands
+    pub fn name(&self) -> &'static str
+    {
+        use Insn::*;
+
+        match self {
+            Panic => "Panic",
+            Halt => "Halt",
+            Debugger => "Debugger",
+            GetLocal { .. } => "GetLocal",
+            SetLocal { .. } => "SetLocal",
+            Push { .. } => "Push",
+            Pop => "Pop",
+            Dup => "Dup",
+            GetIndex => "GetIndex",
+            Add => "Add",
+            Sub => "Sub",
+            Mul => "Mul",
+            Mod => "Mod",
+            Neg => "Neg",
+            Eq => "Eq",
+            Ne => "Ne",
+            Lt => "Lt",
+            Le => "Le",
+            Gt => "Gt",
+            Ge => "Ge",
+            Not => "Not",
+            Range => "Range",
+            GetIter => "GetIter",
+            IterNext { .. } => "IterNext",
+            Yield => "Yield",
+            Resume => "Resume",
+            Jump { .. } => "Jump",
+            IfTrue { .. } => "IfTrue",
+            IfFalse { .. } => "IfFalse",
+            JumpTable { .. } => "JumpTable",
+            Call { .. } => "Call",
+            TailCall { .. } => "TailCall",
+            Return => "Return",
+            AddConst { .. } => "AddConst",
+            AddLocalConst { .. } => "AddLocalConst",
+            JumpIfLocalGeConst { .. } => "JumpIfLocalGeConst",
+        }
+    }
+}
+
+/// Jump table used to dispatch match statements on dense integer cases
+pub struct JumpTable
+{
+    /// Integer value corresponding to the first entry
+    pub min_val: i64,
+
+    /// Branch offset for each integer value, starting from min_val
+    pub offsets: Vec<isize>,
+
+    /// Branch offset for values outside of the table
+    pub default_offset: isize,
+}
+
+/// Name of a local variable, and the instructions where it's in scope
+#[derive(Debug, Clone)]
+pub struct LocalVar
+{
+    pub name: String,
+
+    /// Index of the local variable slot
+    pub idx: usize,
+
+    /// Range of instructions the variable is visible in
+    pub start: usize,
+    pub end: usize,
 }
 
 pub struct Function
 {
+    /// Unique identifier, which unlike the address of a
+    /// function is never reused once it is freed
+    pub id: u64,
+
     /// Name of the function
     pub name: String,
 
-    /// Parameter list
+    /// Name of the source file the function was parsed from
+    pub src_name: String,
+
+    /// Parameter list, including the rest parameter
     pub params: Vec<String>,
 
+    /// Number of parameters without a default value
+    pub num_required: usize,
+
+    /// Set if the last parameter collects the extra arguments
+    pub has_rest: bool,
+
+    /// Index of the instruction to start execution at, indexed by the
+    /// number of parameters with a default value that were supplied
+    pub entry_idxs: Vec<usize>,
+
     /// Unbound variable list
     pub unbound_vars: Vec<String>,
 
     /// Number of local variables
     pub num_locals: usize,
 
+    /// Names of the local variables, used by the debugger
+    pub local_vars: Vec<LocalVar>,
+
     /// Bytecode making up this function
     pub insns: Vec<Insn>,
+
+    /// Source line of each instruction, empty if unknown
+    pub insn_lines: Vec<u32>,
+
+    /// Jump tables referenced by JumpTable instructions
+    pub jump_tables: Vec<JumpTable>,
+
+    /// Set if the function contains yield, so that calling
+    /// it creates a generator instead of running its body
+    pub is_generator: bool,
+
+    /// Register-based code, used by the register backend
+    pub reg_code: RegCode,
+
+    /// Number of calls and loop iterations, used to decide
+    /// when to compile the function into machine code
+    pub hotness: Cell<u32>,
+
+    /// Machine code, once compiled, None if compilation failed
+    pub jit_code: OnceCell<Option<JitCode>>,
 }
 
 impl Function
 {
     pub fn new(name: &str) -> Self
     {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
         Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             name: name.to_string(),
+            src_name: String::default(),
             params: Vec::default(),
+            num_required: 0,
+            has_rest: false,
+            entry_idxs: Vec::default(),
             unbound_vars: Vec::default(),
             num_locals: 0,
+            local_vars: Vec::default(),
             insns: Vec::default(),
+            insn_lines: Vec::default(),
+            jump_tables: Vec::default(),
+            is_generator: false,
+            reg_code: RegCode::default(),
+            hotness: Cell::new(0),
+            jit_code: OnceCell::new(),
+        }
+    }
+
+    /// Size in bytes of the memory owned by this function
+    fn owned_bytes(&self) -> usize
+    {
+        let str_bytes = |strs: &Vec<String>| {
+            strs.capacity() * size_of::<String>() + strs.iter().map(|s| s.capacity()).sum::<usize>()
+        };
+
+        let table_bytes = |tables: &Vec<JumpTable>| -> usize {
+            tables.capacity() * size_of::<JumpTable>() +
+            tables.iter().map(|table| table.offsets.capacity() * size_of::<isize>()).sum::<usize>()
+        };
+
+        let reg_bytes =
+            self.reg_code.insns.capacity() * size_of::<RegInsn>() +
+            self.reg_code.entry_idxs.capacity() * size_of::<usize>() +
+            table_bytes(&self.reg_code.jump_tables);
+
+        self.name.capacity() +
+        self.src_name.capacity() +
+        str_bytes(&self.params) +
+        self.local_vars.capacity() * size_of::<LocalVar>() +
+        self.local_vars.iter().map(|var| var.name.capacity()).sum::<usize>() +
+        str_bytes(&self.unbound_vars) +
+        self.entry_idxs.capacity() * size_of::<usize>() +
+        self.insns.capacity() * size_of::<Insn>() +
+        self.insn_lines.capacity() * size_of::<u32>() +
+        table_bytes(&self.jump_tables) +
+        reg_bytes
+    }
+
+    /// Push the heap values referenced by this function's
+    /// instructions so they can be marked by the GC
+    pub fn trace(&self, roots: &mut Vec<Value>)
+    {
+        for insn in &self.insns {
+            if let Insn::Push { val } = insn {
+                roots.push(*val);
+            }
+        }
+    }
+
+    /// Call a function on this function and all the
+    /// functions it refers to, each one only once
+    pub fn for_each_fun(&mut self, mut f: impl FnMut(&mut Function))
+    {
+        f(self);
+
+        // Nested functions are referenced through Push constants
+        let mut visited = HashSet::new();
+        let mut roots = Vec::default();
+        self.trace(&mut roots);
+
+        while let Some(val) = roots.pop() {
+            if let ValueKind::Fun(fun_ptr) = val.kind() {
+                if visited.insert(fun_ptr) {
+                    let fun = unsafe { &mut *fun_ptr };
+                    f(fun);
+                    fun.trace(&mut roots);
+                }
+            }
+        }
+    }
+}
+
+/// Suspended execution state of a generator
+pub struct Generator
+{
+    /// Generator function being executed
+    fun: Value,
+
+    /// Saved arguments, locals and temporaries
+    stack: Vec<Value>,
+
+    /// Index of the instruction to resume execution at
+    pc_idx: usize,
+
+    /// Set while the generator is executing
+    running: bool,
+
+    /// Set once the generator function has returned
+    done: bool,
+}
+
+/// Mutable string, used to build long strings in linear time
+#[derive(Default)]
+pub struct StringBuilder
+{
+    buf: String,
+
+    /// Number of characters in the buffer, so that the
+    /// length doesn't have to be counted every time
+    num_chars: usize,
+}
+
+impl StringBuilder
+{
+    pub fn as_str(&self) -> &str
+    {
+        &self.buf
+    }
+
+    pub fn num_chars(&self) -> usize
+    {
+        self.num_chars
+    }
+
+    /// Number of bytes the buffer grows by to append a string of the
+    /// given length. The capacity at least doubles when it grows, so
+    /// that appending takes amortized constant time.
+    pub fn growth(&self, len: usize) -> usize
+    {
+        let capacity = self.buf.capacity();
+        let new_len = self.buf.len() + len;
+
+        if new_len <= capacity {
+            return 0;
         }
+
+        new_len.max(2 * capacity) - capacity
+    }
+
+    /// Append a string, growing the buffer by exactly growth(s.len())
+    pub fn push_str(&mut self, s: &str)
+    {
+        let new_capacity = self.buf.capacity() + self.growth(s.len());
+        self.buf.reserve_exact(new_capacity - self.buf.len());
+        self.buf.push_str(s);
+        self.num_chars += s.chars().count();
     }
 }
 
+/// Bit set on objects reached during the mark phase
+const MARK_BIT: usize = 1 << 0;
+
+/// Bit set on objects that were promoted to the old generation
+const OLD_BIT: usize = 1 << 1;
+
+/// Bit set on old objects that are in the remembered set
+const REMEMBERED_BIT: usize = 1 << 2;
+
+/// Bit set on strings that are in the symbol table
+const INTERNED_BIT: usize = 1 << 3;
+
 /// Hold an object to be placed in the GC heap and mark bits
 #[repr(C)]
 pub struct HeapObject<T>
 {
-    /// Mark bits/boolean
+    /// Mark bits, as well as the generation of the object
     mark: usize,
 
     /// Object stored on the heap
@@ -104,6 +633,11 @@ pub enum GCObject
 {
     Fun(Box<HeapObject<Function>>),
     Str(Box<HeapObject<String>>),
+    Iter(Box<HeapObject<Box<dyn HostIter>>>),
+    Gen(Box<HeapObject<Generator>>),
+    Array(Box<HeapObject<Vec<Value>>>),
+    Builder(Box<HeapObject<StringBuilder>>),
+    Int(Box<HeapObject<BoxedInt>>),
 }
 
 impl GCObject
@@ -115,26 +649,144 @@ impl GCObject
         match self {
             Self::Fun(gc_box) => Value::Fun(&mut (gc_box.object) as *mut Function),
             Self::Str(gc_box) => Value::Str(&mut (gc_box.object) as *mut String),
+            Self::Iter(gc_box) => Value::Iter(&mut (gc_box.object) as *mut Box<dyn HostIter>),
+            Self::Gen(gc_box) => Value::Gen(&mut (gc_box.object) as *mut Generator),
+            Self::Array(gc_box) => Value::Array(&mut (gc_box.object) as *mut Vec<Value>),
+            Self::Builder(gc_box) => Value::Builder(&mut (gc_box.object) as *mut StringBuilder),
+            Self::Int(gc_box) => Value::from_addr(&mut (gc_box.object) as *mut BoxedInt as usize, TAG_BOXED),
         }
     }
 
-    fn clear_mark(&mut self)
+    fn mark_bits(&mut self) -> &mut usize
+    {
+        match self {
+            Self::Fun(gc_box) => &mut gc_box.mark,
+            Self::Str(gc_box) => &mut gc_box.mark,
+            Self::Iter(gc_box) => &mut gc_box.mark,
+            Self::Gen(gc_box) => &mut gc_box.mark,
+            Self::Array(gc_box) => &mut gc_box.mark,
+            Self::Builder(gc_box) => &mut gc_box.mark,
+            Self::Int(gc_box) => &mut gc_box.mark,
+        }
+    }
+
+    /// Get the address of the mark bits, which identifies the object
+    fn mark_bits_addr(&self) -> *const usize
     {
         match self {
-            Self::Fun(gc_box) => gc_box.mark = 0,
-            Self::Str(gc_box) => gc_box.mark = 0,
+            Self::Fun(gc_box) => &gc_box.mark,
+            Self::Str(gc_box) => &gc_box.mark,
+            Self::Iter(gc_box) => &gc_box.mark,
+            Self::Gen(gc_box) => &gc_box.mark,
+            Self::Array(gc_box) => &gc_box.mark,
+            Self::Builder(gc_box) => &gc_box.mark,
+            Self::Int(gc_box) => &gc_box.mark,
         }
     }
 
-    fn is_marked(&self) -> bool
+    fn clear_mark(&mut self)
+    {
+        *self.mark_bits() &= !MARK_BIT;
+    }
+
+    fn is_marked(&mut self) -> bool
+    {
+        *self.mark_bits() & MARK_BIT != 0
+    }
+
+    /// Move the object to the old generation
+    fn promote(&mut self)
+    {
+        *self.mark_bits() |= OLD_BIT;
+    }
+
+    /// Size in bytes of this object, including the memory it owns
+    fn size_bytes(&self) -> usize
     {
         match self {
-            Self::Fun(gc_box) => gc_box.mark != 0,
-            Self::Str(gc_box) => gc_box.mark != 0,
+            Self::Fun(gc_box) => gc_box.object.heap_size(),
+            Self::Str(gc_box) => gc_box.object.heap_size(),
+            Self::Iter(gc_box) => gc_box.object.heap_size(),
+            Self::Gen(gc_box) => gc_box.object.heap_size(),
+            Self::Array(gc_box) => gc_box.object.heap_size(),
+            Self::Builder(gc_box) => gc_box.object.heap_size(),
+            Self::Int(gc_box) => gc_box.object.heap_size(),
         }
     }
 }
 
+/// Objects that can be placed in the GC heap
+pub trait HeapSize
+{
+    /// Size in bytes the object takes in the heap, including the memory
+    /// it owns, so that it can be checked before the object is moved in
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for Function
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<Function>>() + self.owned_bytes()
+    }
+}
+
+impl HeapSize for String
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<String>>() + self.capacity()
+    }
+}
+
+impl HeapSize for &str
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<String>>() + self.len()
+    }
+}
+
+impl HeapSize for Box<dyn HostIter>
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<Box<dyn HostIter>>>() + size_of_val(&**self)
+    }
+}
+
+impl HeapSize for Generator
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<Generator>>() + self.stack.capacity() * size_of::<Value>()
+    }
+}
+
+impl HeapSize for Vec<Value>
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<Vec<Value>>>() + self.capacity() * size_of::<Value>()
+    }
+}
+
+impl HeapSize for StringBuilder
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<StringBuilder>>() + self.buf.capacity()
+    }
+}
+
+impl HeapSize for BoxedInt
+{
+    fn heap_size(&self) -> usize
+    {
+        size_of::<HeapObject<BoxedInt>>()
+    }
+}
+
 impl From<Function> for GCObject {
     fn from(fun: Function) -> GCObject {
         let heap_obj = HeapObject {
@@ -165,491 +817,3392 @@ impl From<&str> for GCObject {
     }
 }
 
-impl Value
-{
-    /// Check if a value is marked (or not a markable object)
-    fn is_marked(self) -> bool
-    {
-        let mark_bits_ptr = match self {
-            Value::Fun(ptr) => unsafe { (ptr as *mut usize).offset(-1) },
-            Value::Str(ptr) => unsafe { (ptr as *mut usize).offset(-1) },
-            _ => return true
+impl From<Box<dyn HostIter>> for GCObject {
+    fn from(iter: Box<dyn HostIter>) -> GCObject {
+        let heap_obj = HeapObject {
+            mark: 0,
+            object: iter
         };
-
-        return unsafe { *mark_bits_ptr != 0 };
+        GCObject::Iter(Box::new(heap_obj))
+    }
+}
+
+impl From<Generator> for GCObject {
+    fn from(gen: Generator) -> GCObject {
+        let heap_obj = HeapObject {
+            mark: 0,
+            object: gen
+        };
+        GCObject::Gen(Box::new(heap_obj))
+    }
+}
+
+impl From<Vec<Value>> for GCObject {
+    fn from(arr: Vec<Value>) -> GCObject {
+        let heap_obj = HeapObject {
+            mark: 0,
+            object: arr
+        };
+        GCObject::Array(Box::new(heap_obj))
+    }
+}
+
+impl From<StringBuilder> for GCObject {
+    fn from(builder: StringBuilder) -> GCObject {
+        let heap_obj = HeapObject {
+            mark: 0,
+            object: builder
+        };
+        GCObject::Builder(Box::new(heap_obj))
+    }
+}
+
+impl From<BoxedInt> for GCObject {
+    fn from(int: BoxedInt) -> GCObject {
+        let heap_obj = HeapObject {
+            mark: 0,
+            object: int
+        };
+        GCObject::Int(Box::new(heap_obj))
+    }
+}
+
+impl Value
+{
+    /// Get a pointer to the mark bits of a GC object
+    fn mark_bits_ptr(self) -> Option<*mut usize>
+    {
+        match self.0 & TAG_MASK {
+            TAG_FUN | TAG_STR | TAG_ITER | TAG_GEN | TAG_ARRAY | TAG_BUILDER | TAG_BOXED => {
+                let ptr = (self.0 >> TAG_BITS) as *mut usize;
+                Some(unsafe { ptr.offset(-1) })
+            }
+            _ => None
+        }
+    }
+
+    /// Check if a value is marked (or not a markable object)
+    fn is_marked(self) -> bool
+    {
+        match self.mark_bits_ptr() {
+            Some(mark_bits_ptr) => unsafe { *mark_bits_ptr & MARK_BIT != 0 },
+            None => true
+        }
+    }
+
+    /// Check if a value is in the old generation (or not a GC object)
+    fn is_old(self) -> bool
+    {
+        match self.mark_bits_ptr() {
+            Some(mark_bits_ptr) => unsafe { *mark_bits_ptr & OLD_BIT != 0 },
+            None => true
+        }
     }
 
     /// Mark a GC object
     fn mark(self)
     {
-        let mark_bits_ptr = match self {
-            Value::Fun(ptr) => unsafe { (ptr as *mut usize).offset(-1) },
-            Value::Str(ptr) => unsafe { (ptr as *mut usize).offset(-1) },
-            _ => return
-        };
+        if let Some(mark_bits_ptr) = self.mark_bits_ptr() {
+            unsafe { *mark_bits_ptr |= MARK_BIT };
+        }
+    }
 
-        unsafe { *mark_bits_ptr = 1 };
+    /// Check if a value is a string from the symbol table
+    pub fn is_interned(self) -> bool
+    {
+        match self.mark_bits_ptr() {
+            Some(mark_bits_ptr) => unsafe { *mark_bits_ptr & INTERNED_BIT != 0 },
+            None => false
+        }
     }
 }
 
-pub struct VM
+/// Check if two string values have the same contents
+/// Interned strings are unique, so they are compared by address
+fn str_eq(v0: Value, v1: Value) -> bool
 {
-    /// Value stack
-    stack: Vec<Value>,
+    if v0 == v1 {
+        return true;
+    }
 
-    /// Program counter / instruction pointer
-    pc: *const Insn,
+    if v0.is_interned() && v1.is_interned() {
+        return false;
+    }
 
-    /// Frame pointer (index of the bottom of the frame)
-    fp: usize,
+    match (v0.kind(), v1.kind()) {
+        (ValueKind::Str(s0), ValueKind::Str(s1)) => unsafe { *s0 == *s1 },
+        _ => panic!("expected strings")
+    }
+}
+
+/// Index of the instruction a pc points to, if it's in the function
+fn insn_idx(fun: &Function, pc: *const Insn) -> Option<usize>
+{
+    let offset = (pc as usize).checked_sub(fun.insns.as_ptr() as usize)?;
+    let idx = offset / size_of::<Insn>();
+    if idx < fun.insns.len() { Some(idx) } else { None }
+}
 
-    /// List of objects allocated in the GC heap
-    gc_objects: Vec<GCObject>,
+/// Activation record for a function call
+struct Frame
+{
+    /// Function executing in this frame
+    fun: *const Function,
 
-    /// Current total size of allocated objects in bytes
-    heap_size: usize,
+    /// Frame pointer of the caller
+    prev_fp: usize,
 
-    /// Maximum heap size in bytes
-    max_heap_size: usize,
+    /// Instruction in the caller to return to,
+    /// null when returning to the host
+    ret_pc: *const Insn,
+
+    /// Register instruction in the caller to return to, used
+    /// instead of ret_pc by the register backend
+    ret_reg_pc: *const RegInsn,
 }
 
-impl VM
+/// Interpreter used to execute bytecode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend
 {
-    pub fn new() -> Self
-    {
-        Self {
-            stack: Vec::default(),
-            pc: 0 as *const Insn,
-            fp: 0,
-            gc_objects: Vec::default(),
-            heap_size: 0,
-            max_heap_size: 10_000_000
-        }
-    }
+    /// Stack-based interpreter, executing Function::insns
+    Stack,
 
-    /// Place an object under management of the GC heap
-    pub fn into_gc_heap<T>(&mut self, obj: T) -> Value where GCObject: From<T>
-    {
-        let mut obj: GCObject = obj.into();
-        let val = obj.get_ptr_value();
+    /// Register-based interpreter, executing Function::reg_code
+    Register,
 
-        // Update the heap size
-        self.heap_size += std::mem::size_of::<T>();
+    /// Stack-based interpreter, with hot functions compiled
+    /// into machine code on x86-64 Linux
+    Jit,
+}
 
-        // If we've exceeded the max heap size
-        if self.heap_size > self.max_heap_size {
-            // Don't trigger a GC if we're not currently executing anything
-            // i.e. during compilation
-            if self.stack.len() > 0 {
-                self.gc_collect();
-            }
-        }
+/// Outcome of running code in the VM
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EvalStatus
+{
+    /// Execution completed with a return value
+    Done(Value),
 
-        // We push the new object after a potential collection
-        // because we don't want to collect the new object
-        self.gc_objects.push(obj);
+    /// The fuel ran out, execution can be continued with VM::resume
+    OutOfFuel,
 
-        val
-    }
+    /// Execution was stopped through an InterruptHandle,
+    /// and can be continued with VM::resume
+    Interrupted,
 
-    /// Transitively mark a GC root and everything reachable from it
-    fn mark_root(&self, root: Value)
-    {
-        let mut stack: Vec<Value> = Vec::default();
+    /// Execution stopped at a breakpoint, a debugger statement or
+    /// after a step, and can be continued with VM::resume
+    Break,
+}
 
-        stack.push(root);
+/// How far execution runs before stopping again when debugging
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StepMode
+{
+    /// Run until a breakpoint or a debugger statement
+    Continue,
 
-        while stack.len() > 0 {
-            let val = stack.pop().unwrap();
+    /// Stop at the next line, including in called functions
+    Into,
 
-            if val.is_marked() {
-                continue;
-            }
+    /// Stop at the next line of the current function or its callers
+    Over,
+
+    /// Stop once the current function returns
+    Out,
+}
 
-            match val {
-                Value::Fun(fun_ptr) => {
-                    let fun = unsafe { &*fun_ptr };
+/// Location and local variables of a frame on the call stack
+pub struct FrameInfo
+{
+    pub fun_name: String,
+    pub src_name: String,
 
-                    for insn in &fun.insns {
-                        match insn {
-                            Insn::Push { val } => stack.push(*val),
-                            _ => {}
-                        }
-                    }
+    /// Line being executed, None if unknown
+    pub line_no: Option<u32>,
 
-                    todo!()
-                }
+    /// Local variables in scope, by name
+    pub locals: Vec<(String, Value)>,
+}
 
-                _ => {}
-            }
+/// Handle used to stop the execution of a VM from another thread
+#[derive(Clone)]
+pub struct InterruptHandle
+{
+    flag: Arc<AtomicBool>,
+}
 
-            val.mark();
-        }
+impl InterruptHandle
+{
+    /// Request that the VM stop before its next instruction
+    pub fn interrupt(&self)
+    {
+        self.flag.store(true, Ordering::Relaxed);
     }
+}
 
-    /// Perform a GC collection cycle (mark & sweep)
-    pub fn gc_collect(&mut self)
-    {
-        //println!("gc objs before collection: {}", self.gc_objects.len());
+/// Error produced when execution can't continue
+#[derive(Debug)]
+pub struct RuntimeError
+{
+    pub msg: String,
 
-        // Clear all the marks
-        for obj in &mut self.gc_objects {
-            obj.clear_mark();
-        }
+    /// Names of the functions on the call stack, innermost first
+    pub stack_trace: Vec<String>,
+}
 
-        // Mark all stack values as roots
-        for val in &self.stack {
-            self.mark_root(*val);
-        }
+impl fmt::Display for RuntimeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)?;
 
-        // Delete unmarked objects
-        self.gc_objects.retain(|obj| obj.is_marked());
+        for fun_name in &self.stack_trace {
+            write!(f, "\n  {}", fun_name)?;
+        }
 
-        //println!("gc objs after collection: {}", self.gc_objects.len());
+        Ok(())
     }
+}
 
-    /// Get the size of the stack
-    pub fn stack_size(&self) -> usize
+/// Number of innermost and outermost frames to keep in stack traces
+const TRACE_HEAD_FRAMES: usize = 10;
+const TRACE_TAIL_FRAMES: usize = 10;
+
+/// Resource limits of a VM
+#[derive(Debug, Copy, Clone)]
+pub struct VMConfig
+{
+    /// Maximum heap size in bytes, past which allocations fail
+    /// with an out of memory error after a full collection
+    pub max_heap_size: usize,
+
+    /// Maximum number of active call frames
+    pub max_frames: usize,
+
+    /// Maximum number of values on the stack
+    pub max_stack_size: usize,
+
+    /// Maximum length of strings in bytes
+    pub max_string_len: usize,
+}
+
+impl Default for VMConfig
+{
+    fn default() -> Self
     {
-        self.stack.len()
+        Self {
+            max_heap_size: 10_000_000,
+            max_frames: 10_000,
+            max_stack_size: 1_000_000,
+            max_string_len: 2_000_000,
+        }
     }
+}
 
-    /// Pop a value from the stack
-    pub fn stack_pop(&mut self) -> Value
+/// Policy deciding when garbage collections are triggered
+#[derive(Debug, Copy, Clone)]
+pub struct GCPolicy
+{
+    /// Heap size in bytes below which no collection is triggered
+    pub min_threshold: usize,
+
+    /// After a collection, the next one is triggered once the
+    /// heap grows to this multiple of the live bytes
+    pub growth_factor: f64,
+
+    /// Use minor collections of the young generation in between
+    /// full collections, instead of only full collections
+    pub generational: bool,
+
+    /// Size in bytes of the young generation past which
+    /// a minor collection is triggered
+    pub nursery_size: usize,
+}
+
+impl Default for GCPolicy
+{
+    fn default() -> Self
     {
-        self.stack.pop().expect("stack empty")
+        Self {
+            min_threshold: 1_000_000,
+            growth_factor: 2.0,
+            generational: true,
+            nursery_size: 256_000,
+        }
     }
+}
 
-    /// Push a value on the stack
-    pub fn stack_push(&mut self, val: Value)
+/// Garbage collector statistics
+#[derive(Debug, Copy, Clone, Default)]
+pub struct GCStats
+{
+    /// Number of minor collections of the young generation
+    pub num_minor: usize,
+
+    /// Number of full collections
+    pub num_major: usize,
+
+    /// Total time spent in minor collections
+    pub minor_pause: Duration,
+
+    /// Total time spent in full collections
+    pub major_pause: Duration,
+
+    /// Longest pause of any collection
+    pub max_pause: Duration,
+
+    /// Total number of bytes freed by collections
+    pub bytes_freed: usize,
+
+    /// Number of objects of each kind in the heap, which can
+    /// include garbage not yet freed by a collection
+    pub live_objects: ObjectCounts,
+
+    /// Total size of the objects in the heap in bytes
+    pub live_bytes: usize,
+}
+
+impl GCStats
+{
+    /// Total number of collections, minor and full
+    pub fn num_collections(&self) -> usize
     {
-        self.stack.push(val);
+        self.num_minor + self.num_major
     }
 
-    /// Push a Rust string onto the value stack
-    pub fn push_str(&mut self, val: String)
+    /// Total time spent in collections
+    pub fn total_pause(&self) -> Duration
     {
-        let val = self.into_gc_heap(val);
-        self.stack.push(val);
+        self.minor_pause + self.major_pause
     }
+}
 
-    /// Push a Rust boolean onto the value stack
-    pub fn push_bool(&mut self, val: bool)
-    {
-        let val = Value::Int64(
-            if val { 1 } else { 0 }
-        );
+/// Number of objects of each kind in the GC heap
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ObjectCounts
+{
+    pub fun: usize,
+    pub str: usize,
+    pub iter: usize,
+    pub gen: usize,
+    pub array: usize,
+    pub builder: usize,
+    pub int: usize,
+}
 
-        self.stack.push(val);
+impl ObjectCounts
+{
+    pub fn total(&self) -> usize
+    {
+        self.fun + self.str + self.iter + self.gen + self.array + self.builder + self.int
     }
+}
 
-    pub fn eval(&mut self, fun: &Function) -> Value
-    {
-        use Insn::*;
-        use Value::*;
+pub struct VM
+{
+    /// Value stack
+    stack: Vec<Value>,
 
-        // Push the return address
-        self.stack.push(Nil);
+    /// Stack of active call frames
+    frames: Vec<Frame>,
 
-        // Push the previous frame pointer
-        self.stack.push(Value::UInt64(self.fp as u64));
+    /// Program counter / instruction pointer
+    pc: *const Insn,
 
-        // Set the frame pointer
-        self.fp = self.stack.len();
+    /// Program counter of the register backend
+    reg_pc: *const RegInsn,
 
-        // Push space for all the locals
-        self.stack.resize(self.stack.len() + fun.num_locals, Value::Nil);
+    /// Interpreter used by eval
+    backend: Backend,
 
-        // Set the instruction pointer
-        self.pc = &fun.insns[0] as *const Insn;
+    /// Number of calls and loop iterations after which
+    /// a function gets compiled into machine code
+    jit_threshold: u32,
 
-        loop
-        {
-            let insn = unsafe { *self.pc };
-            //dbg!(insn);
+    /// Frame pointer (index of the bottom of the frame)
+    fp: usize,
 
-            match insn {
-                Panic => panic!("panic"),
+    /// Objects allocated since the last collection
+    young_objects: Vec<GCObject>,
 
-                Halt => return Value::Nil,
+    /// Objects that survived a collection
+    old_objects: Vec<GCObject>,
 
-                Push { val } => {
-                    self.stack.push(val);
-                }
+    /// Old objects that may refer to young objects, which
+    /// are roots for minor collections
+    remembered: Vec<Value>,
 
-                Pop => {
-                    self.stack.pop();
-                }
+    /// Current total size of allocated objects in bytes
+    heap_size: usize,
 
-                Dup => {
-                    let val = self.stack_pop();
-                    self.stack.push(val);
-                    self.stack.push(val);
-                }
+    /// Size of the young generation in bytes
+    young_size: usize,
 
-                SetLocal{ idx } => {
-                    let val = self.stack_pop();
-                    self.stack[self.fp + idx] = val;
-                }
+    /// Garbage collector statistics
+    gc_stats: GCStats,
 
-                GetLocal{ idx } => {
-                    let val = self.stack[self.fp + idx];
-                    self.stack.push(val);
-                }
+    /// Heap size in bytes past which the next collection is triggered
+    gc_threshold: usize,
 
-                Add => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.stack.push(Int64(v0 + v1)),
-                        (Str(s0), Str(s1)) => unsafe {
-                            let mut out_str = String::from("");
-                            out_str.push_str(&*s0);
-                            out_str.push_str(&*s1);
-                            self.push_str(out_str);
-                        }
-                        _ => panic!()
-                    }
-                }
+    /// Policy used to compute the collection threshold
+    gc_policy: GCPolicy,
 
-                Sub => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.stack.push(Int64(v0 - v1)),
-                        _ => panic!()
-                    }
-                }
+    /// Resource limits
+    config: VMConfig,
 
-                Mul => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.stack.push(Int64(v0 * v1)),
-                        _ => panic!()
-                    }
-                }
+    /// Error to stop execution with before the next instruction,
+    /// e.g. when an allocation exceeds the heap limit
+    pending_error: Option<&'static str>,
 
-                Neg => {
-                    let v0 = self.stack_pop();
-                    match v0 {
-                        Int64(v0) => self.stack.push(Int64(-v0)),
-                        _ => panic!()
-                    }
-                }
+    /// Collect on every allocation, to expose rooting bugs
+    gc_stress: bool,
 
-                Mod => {
-                    let v1 = self.stack_pop();
+    /// Verify the heap after every instruction
+    verify_heap: bool,
+
+    /// Number of instructions left to execute, unlimited if None
+    fuel: Option<u64>,
+
+    /// Flag set by InterruptHandle to stop execution
+    interrupt: Arc<AtomicBool>,
+
+    /// Entry frame depth of a suspended execution, if any
+    suspended: Option<usize>,
+
+    /// Symbol table of interned strings, which doesn't keep them alive
+    interned: HashMap<String, Value>,
+
+    /// Functions creating iterators over the iterable types
+    iterables: Vec<IterFn>,
+
+    /// Check breakpoints and stepping before each instruction
+    debugging: bool,
+
+    /// Breakpoint lines, by source name
+    breakpoints: HashMap<String, HashSet<u32>>,
+
+    /// Current step, with the frame depth and line it started at
+    step: (StepMode, usize, u32),
+
+    /// Frame depth, function and line of the last instruction checked,
+    /// so that execution stops when a line is entered and not again
+    /// for each of its instructions
+    debug_pos: (usize, *const Function, u32),
+
+    /// Instruction execution last stopped at, so that a debugger
+    /// statement there doesn't stop again when resuming
+    break_pc: *const Insn,
+
+    /// Profile being collected, if profiling is enabled
+    profile: Option<Box<Profile>>,
+}
+
+impl VM
+{
+    pub fn new() -> Self
+    {
+        Self::with_config(VMConfig::default())
+    }
+
+    pub fn with_config(config: VMConfig) -> Self
+    {
+        Self {
+            stack: Vec::default(),
+            frames: Vec::default(),
+            pc: std::ptr::null(),
+            reg_pc: std::ptr::null(),
+            backend: Backend::Stack,
+            jit_threshold: 1000,
+            fp: 0,
+            young_objects: Vec::default(),
+            old_objects: Vec::default(),
+            remembered: Vec::default(),
+            heap_size: 0,
+            young_size: 0,
+            gc_stats: GCStats::default(),
+            gc_threshold: GCPolicy::default().min_threshold,
+            gc_policy: GCPolicy::default(),
+            config,
+            pending_error: None,
+            gc_stress: false,
+            verify_heap: false,
+            fuel: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            suspended: None,
+            interned: HashMap::default(),
+            iterables: RUNTIME_ITERABLES.to_vec(),
+            debugging: false,
+            breakpoints: HashMap::default(),
+            step: (StepMode::Continue, 0, 0),
+            debug_pos: (0, std::ptr::null(), 0),
+            break_pc: std::ptr::null(),
+            profile: None,
+        }
+    }
+
+    /// Get the resource limits of this VM
+    pub fn config(&self) -> &VMConfig
+    {
+        &self.config
+    }
+
+    /// Select the interpreter used to execute code
+    pub fn set_backend(&mut self, backend: Backend)
+    {
+        assert!(self.suspended.is_none(), "cannot change backend while an execution is suspended");
+        assert!(!self.debugging || backend == Backend::Stack, "debugging requires the stack backend");
+        assert!(self.profile.is_none() || backend == Backend::Stack, "profiling requires the stack backend");
+        self.backend = backend;
+    }
+
+    /// Get the interpreter used to execute code
+    pub fn backend(&self) -> Backend
+    {
+        self.backend
+    }
+
+    /// Set the number of calls and loop iterations after
+    /// which the Jit backend compiles a function
+    pub fn set_jit_threshold(&mut self, threshold: u32)
+    {
+        self.jit_threshold = threshold;
+    }
+
+    /// Set the number of instructions that can be executed before
+    /// eval returns OutOfFuel. None means no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>)
+    {
+        self.fuel = fuel;
+    }
+
+    /// Get the amount of fuel left
+    pub fn fuel(&self) -> Option<u64>
+    {
+        self.fuel
+    }
+
+    /// Get a handle that other threads can use to interrupt execution
+    pub fn interrupt_handle(&self) -> InterruptHandle
+    {
+        InterruptHandle {
+            flag: self.interrupt.clone()
+        }
+    }
+
+    /// Check if an execution was suspended and can be resumed
+    pub fn is_suspended(&self) -> bool
+    {
+        self.suspended.is_some()
+    }
+
+    /// Enable stopping at breakpoints, debugger statements and after
+    /// steps, which only the stack backend supports. This adds a check
+    /// before each instruction, so it's disabled by default.
+    pub fn set_debugging(&mut self, debugging: bool)
+    {
+        assert!(!debugging || self.backend == Backend::Stack, "debugging requires the stack backend");
+        self.debugging = debugging;
+    }
+
+    /// Enable counting the instructions executed and timing the calls
+    /// to each function, which only the stack backend supports. This
+    /// starts a new profile, which collects data until it's taken.
+    pub fn set_profiling(&mut self, profiling: bool)
+    {
+        assert!(!profiling || self.backend == Backend::Stack, "profiling requires the stack backend");
+        self.profile = if profiling { Some(Box::default()) } else { None };
+    }
+
+    /// Take the profile collected so far, leaving an empty one
+    /// if profiling is still enabled
+    pub fn take_profile(&mut self) -> Option<Profile>
+    {
+        let profile = self.profile.as_mut()?;
+        Some(std::mem::take(profile))
+    }
+
+    /// Update the profile's copy of the call stack
+    fn profile_sync(&mut self)
+    {
+        let frames = &self.frames;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.sync(frames.len(), |idx| frames[idx].fun);
+        }
+    }
+
+    /// Stop execution when a line of a source file is entered
+    pub fn add_breakpoint(&mut self, src_name: &str, line_no: u32)
+    {
+        self.breakpoints.entry(src_name.to_string()).or_default().insert(line_no);
+    }
+
+    /// Remove a breakpoint, returns false if there was none
+    pub fn remove_breakpoint(&mut self, src_name: &str, line_no: u32) -> bool
+    {
+        match self.breakpoints.get_mut(src_name) {
+            Some(lines) => lines.remove(&line_no),
+            None => false
+        }
+    }
+
+    /// Set where a suspended execution stops next when resumed,
+    /// relative to the line it is stopped at
+    pub fn step(&mut self, mode: StepMode)
+    {
+        let line_no = self.backtrace().first().and_then(|frame| frame.line_no).unwrap_or(0);
+        self.step = (mode, self.frames.len(), line_no);
+    }
+
+    /// Get the location and local variables of each frame
+    /// of a suspended execution, innermost first
+    pub fn backtrace(&self) -> Vec<FrameInfo>
+    {
+        let mut frame_infos = Vec::default();
+
+        // The current frame is at the pc, and callers at their call instruction
+        let mut pc = self.pc;
+        let mut fp = self.fp;
+
+        for frame in self.frames.iter().rev() {
+            let fun = unsafe { &*frame.fun };
+            let insn_idx = insn_idx(fun, pc);
+
+            // Innermost variables shadow the ones declared before them
+            let mut locals: Vec<(String, Value)> = Vec::default();
+            if let Some(insn_idx) = insn_idx {
+                for var in &fun.local_vars {
+                    if var.start <= insn_idx && insn_idx < var.end {
+                        locals.retain(|(name, _)| *name != var.name);
+                        locals.push((var.name.clone(), self.stack[fp + var.idx]));
+                    }
+                }
+            }
+
+            frame_infos.push(FrameInfo {
+                fun_name: fun.name.clone(),
+                src_name: fun.src_name.clone(),
+                line_no: insn_idx.and_then(|idx| fun.insn_lines.get(idx).copied()),
+                locals,
+            });
+
+            pc = frame.ret_pc;
+            fp = frame.prev_fp;
+        }
+
+        frame_infos
+    }
+
+    /// Check if execution should stop in the debugger before the
+    /// current instruction, which happens when a line is entered
+    fn debug_stop(&mut self) -> bool
+    {
+        let depth = self.frames.len();
+        let fun = unsafe { &*self.frames[depth - 1].fun };
+        let line_no = match insn_idx(fun, self.pc).and_then(|idx| fun.insn_lines.get(idx)) {
+            Some(line_no) => *line_no,
+            None => return false
+        };
+
+        let pos = (depth, fun as *const Function, line_no);
+        if pos == self.debug_pos {
+            return false;
+        }
+        self.debug_pos = pos;
+
+        let (mode, step_depth, step_line) = self.step;
+        let step_done = match mode {
+            StepMode::Continue => false,
+            StepMode::Into => true,
+            // Calls made on the line being stepped over return to it
+            StepMode::Over => depth < step_depth || (depth == step_depth && line_no != step_line),
+            StepMode::Out => depth < step_depth,
+        };
+
+        let at_breakpoint = self.breakpoints.get(&fun.src_name).is_some_and(|lines| lines.contains(&line_no));
+
+        if step_done || at_breakpoint {
+            self.step.0 = StepMode::Continue;
+            self.break_pc = self.pc;
+            return true;
+        }
+
+        false
+    }
+
+    /// Enable collecting on every allocation and verifying the heap
+    /// after every instruction. This is very slow, but helps catch
+    /// values that aren't properly rooted or missing write barriers.
+    pub fn set_gc_debug(&mut self, gc_stress: bool, verify_heap: bool)
+    {
+        self.gc_stress = gc_stress;
+        self.verify_heap = verify_heap;
+    }
+
+    /// Set the policy deciding when garbage collections are triggered
+    pub fn set_gc_policy(&mut self, policy: GCPolicy)
+    {
+        self.gc_policy = policy;
+        self.gc_threshold = self.next_gc_threshold();
+    }
+
+    /// Compute the collection threshold for the current heap size
+    fn next_gc_threshold(&self) -> usize
+    {
+        let threshold = (self.heap_size as f64 * self.gc_policy.growth_factor) as usize;
+        threshold.max(self.gc_policy.min_threshold)
+    }
+
+    /// Get the total size of the objects in the GC heap in bytes
+    pub fn heap_size(&self) -> usize
+    {
+        self.heap_size
+    }
+
+    /// Get the number of objects in the GC heap
+    pub fn num_gc_objects(&self) -> usize
+    {
+        self.young_objects.len() + self.old_objects.len()
+    }
+
+    /// Get the garbage collector statistics
+    pub fn gc_stats(&self) -> GCStats
+    {
+        let mut stats = self.gc_stats;
+
+        for obj in self.young_objects.iter().chain(self.old_objects.iter()) {
+            match obj {
+                GCObject::Fun(_) => stats.live_objects.fun += 1,
+                GCObject::Str(_) => stats.live_objects.str += 1,
+                GCObject::Iter(_) => stats.live_objects.iter += 1,
+                GCObject::Gen(_) => stats.live_objects.gen += 1,
+                GCObject::Array(_) => stats.live_objects.array += 1,
+                GCObject::Builder(_) => stats.live_objects.builder += 1,
+                GCObject::Int(_) => stats.live_objects.int += 1,
+            }
+        }
+
+        stats.live_bytes = self.heap_size;
+        stats
+    }
+
+    /// Make room for num_bytes more in the heap, collecting garbage
+    /// if that passes the collection threshold. Returns false, and
+    /// raises an out of memory error, if the heap limit would be
+    /// exceeded even after a full collection.
+    fn reserve_heap(&mut self, num_bytes: usize) -> bool
+    {
+        // Don't trigger a GC if we're not currently executing anything
+        // i.e. during compilation
+        if self.stack.is_empty() {
+            return true;
+        }
+
+        if self.gc_stress {
+            if self.gc_policy.generational {
+                self.gc_collect_minor();
+            }
+            else
+            {
+                self.gc_collect();
+            }
+        }
+        // If we would exceed the collection threshold
+        else if self.heap_size + num_bytes > self.gc_threshold {
+            self.gc_collect();
+        }
+        else if self.gc_policy.generational && self.young_size + num_bytes > self.gc_policy.nursery_size {
+            self.gc_collect_minor();
+        }
+
+        // If we would exceed the heap limit, try to free everything
+        // we can, and give up if there still isn't enough space
+        if self.heap_size + num_bytes > self.config.max_heap_size {
+            self.gc_collect();
+
+            if self.heap_size + num_bytes > self.config.max_heap_size {
+                self.pending_error = Some("out of memory");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Place an object under management of the GC heap
+    /// If the heap limit would be exceeded, the object is dropped and
+    /// nil is returned, with an out of memory error raised before the
+    /// next instruction
+    // Named for moving the object into the heap, not for converting the VM
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_gc_heap<T: HeapSize>(&mut self, obj: T) -> Value where GCObject: From<T>
+    {
+        let obj_size = obj.heap_size();
+
+        // Collect before the object is added to the heap,
+        // so that the new object isn't collected
+        if !self.reserve_heap(obj_size) {
+            return Value::Nil;
+        }
+
+        let mut obj: GCObject = obj.into();
+        let val = obj.get_ptr_value();
+
+        // Update the heap size
+        self.heap_size += obj_size;
+        self.young_size += obj_size;
+
+        self.young_objects.push(obj);
+
+        val
+    }
+
+    /// Account for memory a heap object is about to acquire after it
+    /// was allocated, e.g. when a string builder grows its buffer
+    /// Returns false, and raises an out of memory error, if the heap
+    /// limit would be exceeded, in which case the object must not grow
+    pub fn grow_object(&mut self, obj: Value, num_bytes: usize) -> bool
+    {
+        if !self.reserve_heap(num_bytes) {
+            return false;
+        }
+
+        self.heap_size += num_bytes;
+
+        if !obj.is_old() {
+            self.young_size += num_bytes;
+        }
+
+        true
+    }
+
+    /// Stop execution with an error before the next instruction,
+    /// used by host functions to report exceeded limits
+    pub fn raise_error(&mut self, msg: &'static str)
+    {
+        self.pending_error = Some(msg);
+    }
+
+    /// Write barrier, to be called after storing values into a heap object
+    /// Old objects that may refer to young objects are remembered so that
+    /// minor collections can treat them as roots
+    pub fn write_barrier(&mut self, obj: Value)
+    {
+        if let Some(mark_bits_ptr) = obj.mark_bits_ptr() {
+            let mark_bits = unsafe { &mut *mark_bits_ptr };
+
+            if *mark_bits & OLD_BIT != 0 && *mark_bits & REMEMBERED_BIT == 0 {
+                *mark_bits |= REMEMBERED_BIT;
+                self.remembered.push(obj);
+            }
+        }
+    }
+
+    /// Push the values directly referenced by a GC object
+    fn trace_children(val: Value, stack: &mut Vec<Value>)
+    {
+        match val.kind() {
+            ValueKind::Fun(fun_ptr) => {
+                let fun = unsafe { &*fun_ptr };
+                fun.trace(stack);
+            }
+
+            ValueKind::Iter(iter_ptr) => {
+                let iter = unsafe { &*iter_ptr };
+                iter.trace(stack);
+            }
+
+            ValueKind::Gen(gen_ptr) => {
+                let gen = unsafe { &*gen_ptr };
+                stack.push(gen.fun);
+                stack.extend_from_slice(&gen.stack);
+            }
+
+            ValueKind::Array(arr_ptr) => {
+                let arr = unsafe { &*arr_ptr };
+                stack.extend_from_slice(arr);
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Transitively mark a GC root and everything reachable from it
+    /// Minor collections don't trace through old objects, which are
+    /// assumed to be alive
+    fn mark_root(&self, root: Value, minor: bool)
+    {
+        let mut stack: Vec<Value> = Vec::default();
+
+        stack.push(root);
+
+        while let Some(val) = stack.pop() {
+            if val.is_marked() || (minor && val.is_old()) {
+                continue;
+            }
+
+            Self::trace_children(val, &mut stack);
+            val.mark();
+        }
+    }
+
+    /// Mark the objects reachable from the stack and active frames
+    fn mark_roots(&self, minor: bool)
+    {
+        // Mark all stack values as roots
+        for val in &self.stack {
+            self.mark_root(*val, minor);
+        }
+
+        // The functions of active frames are roots as well. The unit
+        // function being executed isn't on the GC heap or on the stack,
+        // but the constants it references need to be kept alive
+        let mut fun_roots: Vec<Value> = Vec::default();
+        for frame in &self.frames {
+            let fun = unsafe { &*frame.fun };
+            fun.trace(&mut fun_roots);
+        }
+
+        for val in fun_roots {
+            self.mark_root(val, minor);
+        }
+    }
+
+    /// Record the duration of a collection and the
+    /// number of bytes it freed in the statistics
+    fn record_collection(&mut self, start_time: Instant, heap_size_before: usize, minor: bool)
+    {
+        let pause = start_time.elapsed();
+
+        self.gc_stats.bytes_freed += heap_size_before.saturating_sub(self.heap_size);
+
+        if minor {
+            self.gc_stats.num_minor += 1;
+            self.gc_stats.minor_pause += pause;
+        }
+        else
+        {
+            self.gc_stats.num_major += 1;
+            self.gc_stats.major_pause += pause;
+        }
+
+        self.gc_stats.max_pause = self.gc_stats.max_pause.max(pause);
+    }
+
+    /// Perform a minor collection, which only frees young objects
+    /// The surviving young objects are promoted to the old generation
+    pub fn gc_collect_minor(&mut self)
+    {
+        let start_time = Instant::now();
+        let heap_size_before = self.heap_size;
+
+        for obj in &mut self.young_objects {
+            obj.clear_mark();
+        }
+
+        self.mark_roots(true);
+
+        // Old objects that were written to may refer to young objects
+        let mut remembered_roots: Vec<Value> = Vec::default();
+        for obj in std::mem::take(&mut self.remembered) {
+            if let Some(mark_bits_ptr) = obj.mark_bits_ptr() {
+                unsafe { *mark_bits_ptr &= !REMEMBERED_BIT };
+            }
+
+            Self::trace_children(obj, &mut remembered_roots);
+        }
+
+        for val in remembered_roots {
+            self.mark_root(val, true);
+        }
+
+        // Remove the dead strings from the symbol table
+        self.interned.retain(|_, val| val.is_old() || val.is_marked());
+
+        // Delete unmarked young objects and promote the survivors
+        self.young_objects.retain_mut(|obj| obj.is_marked());
+
+        let mut survivor_size = 0;
+        for mut obj in self.young_objects.drain(..) {
+            survivor_size += obj.size_bytes();
+            obj.promote();
+            self.old_objects.push(obj);
+        }
+
+        self.heap_size = self.heap_size - self.young_size + survivor_size;
+        self.young_size = 0;
+
+        self.record_collection(start_time, heap_size_before, true);
+    }
+
+    /// Check that every value reachable from the stack and active frames
+    /// points to a live object in the GC heap, and that old objects
+    /// referring to young objects are in the remembered set
+    /// Panics with a description of the problem if this isn't the case
+    pub fn verify_heap(&self)
+    {
+        let live: HashSet<*const usize> = self.young_objects.iter()
+            .chain(self.old_objects.iter())
+            .map(|obj| obj.mark_bits_addr())
+            .collect();
+
+        let is_live = |val: Value| match val.mark_bits_ptr() {
+            Some(mark_bits_ptr) => live.contains(&(mark_bits_ptr as *const usize)),
+            None => true
+        };
+
+        let mut stack: Vec<Value> = Vec::default();
+
+        for (idx, val) in self.stack.iter().enumerate() {
+            if !is_live(*val) {
+                panic!("stack slot {} holds a dangling pointer: {:?}", idx, val);
+            }
+            stack.push(*val);
+        }
+
+        for frame in &self.frames {
+            let fun = unsafe { &*frame.fun };
+            let mut consts = Vec::default();
+            fun.trace(&mut consts);
+
+            for val in consts {
+                if !is_live(val) {
+                    panic!("function {} holds a dangling pointer: {:?}", fun.name, val);
+                }
+                stack.push(val);
+            }
+        }
+
+        let mut visited: HashSet<*const usize> = HashSet::default();
+
+        while let Some(val) = stack.pop() {
+            let mark_bits_ptr = match val.mark_bits_ptr() {
+                Some(mark_bits_ptr) => mark_bits_ptr,
+                None => continue
+            };
+
+            if !visited.insert(mark_bits_ptr) {
+                continue;
+            }
+
+            let mark_bits = unsafe { *mark_bits_ptr };
+            let needs_barrier = mark_bits & OLD_BIT != 0 && mark_bits & REMEMBERED_BIT == 0;
+
+            let mut children = Vec::default();
+            Self::trace_children(val, &mut children);
+
+            for child in children {
+                if !is_live(child) {
+                    panic!("{:?} holds a dangling pointer: {:?}", val, child);
+                }
+
+                if needs_barrier && !child.is_old() {
+                    panic!("old object {:?} refers to young object {:?} without a write barrier", val, child);
+                }
+
+                stack.push(child);
+            }
+        }
+    }
+
+    /// Perform a full GC collection cycle (mark & sweep)
+    pub fn gc_collect(&mut self)
+    {
+        let start_time = Instant::now();
+        let heap_size_before = self.heap_size;
+
+        //println!("gc objs before collection: {}", self.num_gc_objects());
+
+        // Clear all the marks
+        for obj in self.young_objects.iter_mut().chain(self.old_objects.iter_mut()) {
+            obj.clear_mark();
+        }
+
+        self.mark_roots(false);
+
+        // All young objects get promoted, so there is nothing left to
+        // remember. This is done before sweeping remembered objects.
+        for obj in std::mem::take(&mut self.remembered) {
+            if let Some(mark_bits_ptr) = obj.mark_bits_ptr() {
+                unsafe { *mark_bits_ptr &= !REMEMBERED_BIT };
+            }
+        }
+
+        // Remove the dead strings from the symbol table
+        self.interned.retain(|_, val| val.is_marked());
+
+        // Delete unmarked objects
+        self.young_objects.retain_mut(|obj| obj.is_marked());
+        self.old_objects.retain_mut(|obj| obj.is_marked());
+
+        // Promote the surviving young objects
+        for mut obj in self.young_objects.drain(..) {
+            obj.promote();
+            self.old_objects.push(obj);
+        }
+
+        // Recompute the heap size, since objects such as functions and
+        // arrays may have grown since they were allocated
+        self.heap_size = self.old_objects.iter_mut().map(|obj| obj.size_bytes()).sum();
+        self.young_size = 0;
+
+        // The next collection happens once the heap has grown
+        // proportionally to the live set
+        self.gc_threshold = self.next_gc_threshold();
+
+        self.record_collection(start_time, heap_size_before, false);
+
+        //println!("gc objs after collection: {}", self.num_gc_objects());
+    }
+
+    /// Get the size of the stack
+    pub fn stack_size(&self) -> usize
+    {
+        self.stack.len()
+    }
+
+    /// Pop a value from the stack
+    pub fn stack_pop(&mut self) -> Value
+    {
+        self.stack.pop().expect("stack empty")
+    }
+
+    /// Push a value on the stack
+    pub fn stack_push(&mut self, val: Value)
+    {
+        self.stack.push(val);
+    }
+
+    /// Get the unique string with the given contents from the symbol
+    /// table, allocating it if needed. Interned strings can be compared
+    /// by address.
+    pub fn intern(&mut self, str: &str) -> Value
+    {
+        if let Some(val) = self.interned.get(str) {
+            return *val;
+        }
+
+        let val = self.into_gc_heap(str);
+        let Some(mark_bits_ptr) = val.mark_bits_ptr() else {
+            // Out of memory, don't cache the failed allocation
+            return val;
+        };
+        unsafe { *mark_bits_ptr |= INTERNED_BIT };
+
+        self.interned.insert(str.to_string(), val);
+        val
+    }
+
+    /// Make values for which iter_fn returns an iterator usable
+    /// in for-in loops, after the types that are already iterable
+    pub fn register_iterable(&mut self, iter_fn: IterFn)
+    {
+        self.iterables.push(iter_fn);
+    }
+
+    /// Get an iterator over an iterable value
+    fn get_iter(&self, val: Value) -> Option<Box<dyn HostIter>>
+    {
+        self.iterables.iter().find_map(|iter_fn| iter_fn(val))
+    }
+
+    /// Make an integer value, boxing it on the heap if it's too large
+    /// to be packed. Returns nil if the heap is out of memory.
+    #[inline(always)]
+    pub fn int64(&mut self, v: i64) -> Value
+    {
+        match Value::try_int64(v) {
+            Some(val) => val,
+            None => self.into_gc_heap(BoxedInt::Int64(v))
+        }
+    }
+
+    /// Make an unsigned integer value, boxing it if needed
+    pub fn uint64(&mut self, v: u64) -> Value
+    {
+        match Value::try_uint64(v) {
+            Some(val) => val,
+            None => self.into_gc_heap(BoxedInt::UInt64(v))
+        }
+    }
+
+    /// Push a Rust string onto the value stack
+    pub fn push_str(&mut self, val: String)
+    {
+        let val = self.into_gc_heap(val);
+        self.stack.push(val);
+    }
+
+    /// Push a Rust integer onto the value stack
+    #[inline(always)]
+    pub fn push_int(&mut self, val: i64)
+    {
+        let val = self.int64(val);
+        self.stack.push(val);
+    }
+
+    /// Push a Rust boolean onto the value stack
+    pub fn push_bool(&mut self, val: bool)
+    {
+        let val = Value::Int64(
+            if val { 1 } else { 0 }
+        );
+
+        self.stack.push(val);
+    }
+
+    /// Check if pushing a new frame holding the given
+    /// number of values would exceed the stack limits
+    fn stack_overflow(&self, num_vals: usize) -> bool
+    {
+        self.frames.len() >= self.config.max_frames || self.stack.len() + num_vals > self.config.max_stack_size
+    }
+
+    /// Produce a runtime error with a stack trace, then unwind the frames
+    /// above the given depth so that the VM remains usable
+    fn unwind(&mut self, msg: &str, entry_depth: usize) -> RuntimeError
+    {
+        let num_frames = self.frames.len() - entry_depth;
+        let mut stack_trace = Vec::default();
+
+        let mut fp = self.fp;
+        let mut entry_fp = fp;
+
+        for (i, frame) in self.frames[entry_depth..].iter().rev().enumerate() {
+            let fun = unsafe { &*frame.fun };
+
+            // Generators whose frame is unwound can't be resumed
+            if fun.is_generator {
+                if let ValueKind::Gen(gen_ptr) = self.stack[fp - 1].kind() {
+                    let gen = unsafe { &mut *gen_ptr };
+                    gen.running = false;
+                    gen.done = true;
+                }
+            }
+
+            // Keep only the innermost and outermost frames of deep stacks
+            if i < TRACE_HEAD_FRAMES || i + TRACE_TAIL_FRAMES >= num_frames {
+                stack_trace.push(fun.name.clone());
+            }
+            else if i == TRACE_HEAD_FRAMES {
+                let num_omitted = num_frames - TRACE_HEAD_FRAMES - TRACE_TAIL_FRAMES;
+                stack_trace.push(format!("... {} more frames", num_omitted));
+            }
+
+            entry_fp = fp;
+            fp = frame.prev_fp;
+        }
+
+        // Pop everything down to the callee slot of the entry frame
+        self.stack.truncate(entry_fp - 1);
+        self.frames.truncate(entry_depth);
+        self.fp = fp;
+
+        RuntimeError {
+            msg: msg.to_string(),
+            stack_trace,
+        }
+    }
+
+    /// Discard a suspended execution so that the VM can be reused
+    pub fn abort(&mut self)
+    {
+        let entry_depth = self.suspended.take().expect("no suspended execution to abort");
+        self.unwind("aborted", entry_depth);
+        self.profile_sync();
+    }
+
+    /// Check the argument count of a call to a script function, with
+    /// the arguments at the top of the stack. Missing arguments with
+    /// a default value are padded with nil, and the extra arguments
+    /// are collected into an array for the rest parameter, so that
+    /// one value per parameter is left on the stack.
+    /// Returns the index in entry_idxs to start execution at, or an
+    /// error message if the number of arguments doesn't match.
+    fn prep_args(&mut self, fun: &Function, argc: usize) -> Result<usize, String>
+    {
+        let num_fixed = fun.params.len() - if fun.has_rest { 1 } else { 0 };
+
+        if argc < fun.num_required || (!fun.has_rest && argc > num_fixed) {
+            let expected = if fun.has_rest {
+                format!("at least {}", fun.num_required)
+            } else if fun.num_required < num_fixed {
+                format!("{} to {}", fun.num_required, num_fixed)
+            } else {
+                format!("{}", num_fixed)
+            };
+
+            return Err(format!(
+                "function {} expected {} arguments but got {}",
+                fun.name,
+                expected,
+                argc
+            ));
+        }
+
+        if fun.has_rest {
+            // Allocate the array while the arguments are still on the stack
+            let rest_val = self.into_gc_heap(Vec::<Value>::new());
+
+            if argc > num_fixed {
+                let extra_args = self.stack.split_off(self.stack.len() - (argc - num_fixed));
+                // The array is nil if the allocation failed, in which
+                // case the error is raised before the call starts
+                if let ValueKind::Array(arr_ptr) = rest_val.kind() {
+                    unsafe { *arr_ptr = extra_args };
+                }
+                self.write_barrier(rest_val);
+            }
+
+            self.stack.resize(self.stack.len() + num_fixed - argc.min(num_fixed), Value::Nil);
+            self.stack.push(rest_val);
+        }
+        else
+        {
+            self.stack.resize(self.stack.len() + num_fixed - argc, Value::Nil);
+        }
+
+        // Skip the default values of the arguments that were supplied
+        Ok(argc.min(num_fixed) - fun.num_required)
+    }
+
+    /// Switch execution to a suspended generator
+    /// The generator value must be at the top of the stack, where it
+    /// serves as the callee slot of the new frame.
+    /// Returns false if the generator has already finished.
+    fn resume_gen(&mut self, gen_ptr: *mut Generator) -> bool
+    {
+        let gen = unsafe { &mut *gen_ptr };
+
+        if gen.done {
+            return false;
+        }
+
+        if gen.running {
+            panic!("cannot resume a generator that is already running");
+        }
+
+        let fun_ptr = match gen.fun.kind() {
+            ValueKind::Fun(fun_ptr) => fun_ptr,
+            _ => panic!()
+        };
+        let fun = unsafe { &*fun_ptr };
+
+        self.frames.push(Frame {
+            fun: fun_ptr,
+            prev_fp: self.fp,
+            ret_pc: self.pc,
+            ret_reg_pc: self.reg_pc,
+        });
+
+        // Restore the saved locals and temporaries
+        self.fp = self.stack.len();
+        self.stack.append(&mut gen.stack);
+        gen.running = true;
+
+        match self.backend {
+            Backend::Stack | Backend::Jit => self.pc = &fun.insns[gen.pc_idx],
+            Backend::Register => self.reg_pc = &fun.reg_code.insns[gen.pc_idx],
+        }
+
+        true
+    }
+
+    pub fn eval(&mut self, fun: &Function) -> Result<EvalStatus, RuntimeError>
+    {
+        use ValueKind::*;
+
+        assert!(self.suspended.is_none(), "cannot eval while an execution is suspended");
+
+        // Frames above this depth get unwound if an error occurs
+        let entry_depth = self.frames.len();
+
+        // Stop at the first line when stepping or at a breakpoint
+        self.debug_pos = (0, std::ptr::null(), 0);
+        self.break_pc = std::ptr::null();
+
+        // Push a nil callee slot for the unit function
+        self.stack.push(Value::Nil);
+
+        // The frame returns to the host
+        self.frames.push(Frame {
+            fun: fun as *const Function,
+            prev_fp: self.fp,
+            ret_pc: std::ptr::null(),
+            ret_reg_pc: std::ptr::null(),
+        });
+
+        // Set the frame pointer
+        self.fp = self.stack.len();
+
+        // The register backend also needs space for temporaries
+        let num_slots = match self.backend {
+            Backend::Stack | Backend::Jit => fun.num_locals,
+            Backend::Register => fun.reg_code.num_regs,
+        };
+
+        if self.stack_overflow(num_slots) {
+            return Err(self.unwind("stack overflow", entry_depth));
+        }
+
+        // Push space for all the locals
+        self.stack.resize(self.stack.len() + num_slots, Value::Nil);
+
+        // Set the instruction pointer
+        match self.backend {
+            Backend::Stack | Backend::Jit => {
+                self.pc = &fun.insns[0] as *const Insn;
+
+                if self.backend == Backend::Jit {
+                    self.jit_tier_up();
+                }
+
+                self.run(entry_depth)
+            }
+            Backend::Register => {
+                assert!(!fun.reg_code.insns.is_empty(), "function {} has no register code", fun.name);
+                self.reg_pc = &fun.reg_code.insns[0];
+                self.run_regs(entry_depth)
+            }
+        }
+    }
+
+    /// Continue an execution that ran out of fuel or was interrupted.
+    /// The function passed to eval must still be alive.
+    pub fn resume(&mut self) -> Result<EvalStatus, RuntimeError>
+    {
+        let entry_depth = self.suspended.take().expect("no suspended execution to resume");
+        match self.backend {
+            Backend::Stack | Backend::Jit => self.run(entry_depth),
+            Backend::Register => self.run_regs(entry_depth),
+        }
+    }
+
+    /// Checks done before executing each instruction
+    /// Returns the status to return with if execution has to stop
+    /// Breakpoints and stepping are only checked by the debugging
+    /// version, so that they cost nothing when not debugging, and
+    /// likewise for the profiling version
+    #[inline(always)]
+    fn poll<const DEBUG: bool, const PROFILE: bool>(&mut self, entry_depth: usize) -> Option<Result<EvalStatus, RuntimeError>>
+    {
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                self.suspended = Some(entry_depth);
+                return Some(Ok(EvalStatus::OutOfFuel));
+            }
+            *fuel -= 1;
+        }
+
+        // An allocation that would have exceeded the heap limit failed
+        // and returned nil, raise the error before it gets used
+        if let Some(msg) = self.pending_error.take() {
+            return Some(Err(self.unwind(msg, entry_depth)));
+        }
+
+        if self.interrupt.load(Ordering::Relaxed) {
+            self.interrupt.store(false, Ordering::Relaxed);
+            self.suspended = Some(entry_depth);
+            return Some(Ok(EvalStatus::Interrupted));
+        }
+
+        if self.verify_heap {
+            self.verify_heap();
+        }
+
+        if DEBUG && self.debug_stop() {
+            self.suspended = Some(entry_depth);
+            return Some(Ok(EvalStatus::Break));
+        }
+
+        if PROFILE {
+            self.profile_sync();
+            let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+            if let (Some(profile), Some(idx)) = (self.profile.as_mut(), insn_idx(fun, self.pc)) {
+                profile.count_insn(idx);
+            }
+        }
+
+        None
+    }
+
+    /// Execute instructions starting at the current pc
+    fn run(&mut self, entry_depth: usize) -> Result<EvalStatus, RuntimeError>
+    {
+        if self.profile.is_none() {
+            if self.debugging {
+                return self.run_insns::<true, false>(entry_depth);
+            }
+            else
+            {
+                return self.run_insns::<false, false>(entry_depth);
+            }
+        }
+
+        self.profile.as_mut().unwrap().start_clock();
+
+        let result = if self.debugging {
+            self.run_insns::<true, true>(entry_depth)
+        }
+        else
+        {
+            self.run_insns::<false, true>(entry_depth)
+        };
+
+        // Pop the frames that returned or were unwound
+        self.profile_sync();
+        self.profile.as_mut().unwrap().stop_clock();
+
+        result
+    }
+
+    fn run_insns<const DEBUG: bool, const PROFILE: bool>(&mut self, entry_depth: usize) -> Result<EvalStatus, RuntimeError>
+    {
+        use Insn::*;
+        use ValueKind::*;
+
+        loop
+        {
+            if let Some(status) = self.poll::<DEBUG, PROFILE>(entry_depth) {
+                return status;
+            }
+
+            let insn = unsafe { *self.pc };
+            //dbg!(insn);
+
+            match insn {
+                Panic => panic!("panic"),
+
+                Halt => return Ok(EvalStatus::Done(Value::Nil)),
+
+                Debugger => {
+                    // Stop before this instruction, so that the debugger
+                    // shows its line, and continue past it when resumed
+                    if DEBUG && self.break_pc != self.pc {
+                        self.step.0 = StepMode::Continue;
+                        self.break_pc = self.pc;
+                        self.suspended = Some(entry_depth);
+                        return Ok(EvalStatus::Break);
+                    }
+                    self.break_pc = std::ptr::null();
+                }
+
+                Push { val } => {
+                    self.stack.push(val);
+                }
+
+                Pop => {
+                    self.stack.pop();
+                }
+
+                Dup => {
+                    let val = self.stack_pop();
+                    self.stack.push(val);
+                    self.stack.push(val);
+                }
+
+                SetLocal{ idx } => {
+                    let val = self.stack_pop();
+                    self.stack[self.fp + idx] = val;
+                }
+
+                GetLocal{ idx } => {
+                    let val = self.stack[self.fp + idx];
+                    self.stack.push(val);
+                }
+
+                GetIndex => {
+                    let idx = self.stack_pop();
+                    let arr = self.stack_pop();
+                    match (arr.kind(), idx.kind()) {
+                        (Array(arr_ptr), Int64(idx)) => {
+                            let arr = unsafe { &*arr_ptr };
+                            match usize::try_from(idx).ok().and_then(|idx| arr.get(idx)) {
+                                Some(val) => self.stack.push(*val),
+                                None => panic!("array index {} out of bounds", idx)
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                Add => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_int(v0 + v1),
+                        (Str(s0), Str(s1)) => unsafe {
+                            if (&*s0).len() + (&*s1).len() > self.config.max_string_len {
+                                return Err(self.unwind("string too long", entry_depth));
+                            }
+
+                            let mut out_str = String::from("");
+                            out_str.push_str(&*s0);
+                            out_str.push_str(&*s1);
+                            self.push_str(out_str);
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                Sub => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_int(v0 - v1),
+                        _ => panic!()
+                    }
+                }
+
+                Mul => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_int(v0 * v1),
+                        _ => panic!()
+                    }
+                }
+
+                Neg => {
                     let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.stack.push(Int64(v0 % v1)),
+                    match v0.kind() {
+                        Int64(v0) => self.push_int(-v0),
+                        _ => panic!()
+                    }
+                }
+
+                AddConst { val } => {
+                    let v0 = self.stack_pop();
+                    match v0.kind() {
+                        Int64(v0) => self.push_int(v0 + val),
+                        _ => panic!()
+                    }
+                }
+
+                AddLocalConst { idx, val } => {
+                    let local_idx = self.fp + idx as usize;
+                    match self.stack[local_idx].kind() {
+                        Int64(v) => self.stack[local_idx] = self.int64(v + val as i64),
+                        _ => panic!()
+                    }
+                }
+
+                Mod => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_int(v0 % v1),
+                        _ => panic!()
+                    }
+                }
+
+                Eq => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_bool(v0 == v1),
+                        (Str(_), Str(_)) => self.push_bool(str_eq(v0, v1)),
+                        // Values of different types are never equal
+                        _ => self.push_bool(v0 == v1)
+                    };
+                }
+
+                Ne => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_bool(v0 != v1),
+                        (Str(_), Str(_)) => self.push_bool(!str_eq(v0, v1)),
+                        _ => self.push_bool(v0 != v1)
+                    };
+                }
+
+                Lt => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_bool(v0 < v1),
+                        _ => panic!()
+                    };
+                }
+
+                Gt => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.push_bool(v0 > v1),
+                        _ => panic!()
+                    };
+                }
+
+                Not => {
+                    let v0 = self.stack_pop();
+                    match v0.kind() {
+                        Int64(v0) => self.push_bool(v0 == 0),
+                        _ => panic!()
+                    };
+                }
+
+                Range => {
+                    let v1 = self.stack_pop();
+                    let v0 = self.stack_pop();
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => {
+                            let range: Box<dyn HostIter> = Box::new(RangeIter::new(v0, v1));
+                            let range = self.into_gc_heap(range);
+                            self.stack.push(range);
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                GetIter => {
+                    // Leave the iterable on the stack while allocating
+                    // the iterator so that it can't be collected
+                    let val = self.stack[self.stack.len() - 1];
+
+                    // Iterators and generators are their own iterators
+                    let iter_val = match val.kind() {
+                        Iter(_) | Gen(_) => val,
+                        _ => match self.get_iter(val) {
+                            Some(iter) => self.into_gc_heap(iter),
+                            None => panic!("value is not iterable")
+                        }
+                    };
+
+                    self.stack_pop();
+                    self.stack.push(iter_val);
+                }
+
+                IterNext{ offset } => {
+                    // The iterator stays on the stack until it is exhausted
+                    let iter_ptr = match self.stack[self.stack.len() - 1].kind() {
+                        Iter(iter_ptr) => iter_ptr,
+                        Gen(gen_ptr) => {
+                            let gen = unsafe { &*gen_ptr };
+                            if self.stack_overflow(gen.stack.len()) {
+                                return Err(self.unwind("stack overflow", entry_depth));
+                            }
+
+                            // Run the generator until it yields the next value
+                            if self.resume_gen(gen_ptr) {
+                                continue;
+                            }
+
+                            self.stack_pop();
+                            self.pc = unsafe { self.pc.offset(offset) };
+                            self.pc = unsafe { self.pc.add(1) };
+                            continue;
+                        }
+                        _ => panic!()
+                    };
+
+                    let iter = unsafe { &mut *iter_ptr };
+
+                    match iter.next(self) {
+                        Some(val) => self.stack.push(val),
+                        None => {
+                            self.stack_pop();
+                            self.pc = unsafe { self.pc.offset(offset) };
+                        }
+                    }
+                }
+
+                Jump{ offset } => {
+                    self.pc = unsafe { self.pc.offset(offset) };
+
+                    // Loop iterations count towards compiling the function
+                    if offset < 0 && self.backend == Backend::Jit {
+                        self.pc = unsafe { self.pc.add(1) };
+                        self.jit_tier_up();
+                        continue;
+                    }
+                }
+
+                IfTrue{ offset } => {
+                    let v = self.stack_pop();
+                    match v.kind() {
+                        Int64(v) => {
+                            if v != 0 {
+                                self.pc = unsafe { self.pc.offset(offset) }
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                IfFalse{ offset } => {
+                    let v = self.stack_pop();
+                    match v.kind() {
+                        Int64(v) => {
+                            if v == 0 {
+                                self.pc = unsafe { self.pc.offset(offset) }
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                JumpIfLocalGeConst { idx, val, offset } => {
+                    match self.stack[self.fp + idx as usize].kind() {
+                        Int64(v) => {
+                            if v >= val as i64 {
+                                self.pc = unsafe { self.pc.offset(offset as isize) }
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                JumpTable { table_idx } => {
+                    let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+                    let table = &fun.jump_tables[table_idx];
+                    let v = self.stack_pop();
+
+                    // Values that aren't integers or fall outside the
+                    // table go to the default case
+                    let entry_idx = match v.kind() {
+                        Int64(v) => v.checked_sub(table.min_val).and_then(|i| usize::try_from(i).ok()),
+                        _ => None
+                    };
+
+                    let offset = match entry_idx {
+                        Some(i) if i < table.offsets.len() => table.offsets[i],
+                        _ => table.default_offset
+                    };
+
+                    self.pc = unsafe { self.pc.offset(offset) };
+                }
+
+                Call { argc } | TailCall { argc } => {
+                    // The callee was pushed on the stack first
+                    let callee = self.stack[self.stack.len() - argc - 1];
+
+                    // The last argument is at the top
+                    // This pointer is invalid if argc is zero
+                    let args = match argc {
+                        0 => std::ptr::null(),
+                        _ => &self.stack[self.stack.len() - argc] as *const Value
+                    };
+
+                    match callee.kind() {
+                        HostFn(host_fn) => {
+                            if PROFILE {
+                                self.profile.as_mut().unwrap().count_host_call(host_fn);
+                            }
+
+                            let retv = host_fn.call(self, args, argc);
+
+                            // Pop the arguments and the callee
+                            self.stack.truncate(self.stack.len() - argc - 1);
+                            self.stack.push(retv);
+                        }
+
+                        Fun(fun_ptr) => {
+                            let fun = unsafe { &*fun_ptr };
+                            let entry_idx = match self.prep_args(fun, argc) {
+                                Ok(idx) => fun.entry_idxs[idx],
+                                Err(msg) => return Err(self.unwind(&msg, entry_depth))
+                            };
+                            let argc = fun.params.len();
+
+                            // Calling a generator function creates a generator
+                            // holding the arguments, without running the body
+                            if fun.is_generator {
+                                let mut gen_stack = self.stack[self.stack.len() - argc..].to_vec();
+                                gen_stack.resize(fun.num_locals, Value::Nil);
+
+                                let gen = self.into_gc_heap(Generator {
+                                    fun: callee,
+                                    stack: gen_stack,
+                                    pc_idx: entry_idx,
+                                    running: false,
+                                    done: false,
+                                });
+
+                                self.stack.truncate(self.stack.len() - argc - 1);
+                                self.stack.push(gen);
+                            }
+                            else if let TailCall { .. } = insn {
+                                if self.fp + fun.num_locals > self.config.max_stack_size {
+                                    return Err(self.unwind("stack overflow", entry_depth));
+                                }
+
+                                // Move the callee and arguments over the current
+                                // frame, which the callee takes over
+                                let callee_idx = self.stack.len() - argc - 1;
+                                self.stack.copy_within(callee_idx.., self.fp - 1);
+                                self.stack.truncate(self.fp + argc);
+                                self.stack.resize(self.fp + fun.num_locals, Value::Nil);
+
+                                let frame = self.frames.last_mut().unwrap();
+                                frame.fun = fun_ptr;
+
+                                self.pc = &fun.insns[entry_idx] as *const Insn;
+
+                                if self.backend == Backend::Jit {
+                                    self.jit_tier_up();
+                                }
+
+                                continue;
+                            }
+                            else
+                            {
+                                if self.stack_overflow(fun.num_locals - argc) {
+                                    return Err(self.unwind("stack overflow", entry_depth));
+                                }
+
+                                self.frames.push(Frame {
+                                    fun: fun_ptr,
+                                    prev_fp: self.fp,
+                                    ret_pc: self.pc,
+                                    ret_reg_pc: std::ptr::null(),
+                                });
+
+                                // The arguments become the first locals of the callee
+                                self.fp = self.stack.len() - argc;
+                                self.stack.resize(self.fp + fun.num_locals, Value::Nil);
+
+                                self.pc = &fun.insns[entry_idx] as *const Insn;
+
+                                if self.backend == Backend::Jit {
+                                    self.jit_tier_up();
+                                }
+
+                                continue;
+                            }
+                        }
+
+                        _ => panic!("callee is not a function")
+                    }
+                }
+
+                Yield => {
+                    let val = self.stack_pop();
+                    let frame = self.frames.pop().unwrap();
+                    let fun = unsafe { &*frame.fun };
+
+                    // The generator is in the callee slot of this frame
+                    let gen_val = self.stack[self.fp - 1];
+                    let gen = match gen_val.kind() {
+                        Gen(gen_ptr) => unsafe { &mut *gen_ptr },
+                        _ => panic!()
+                    };
+
+                    // Save the generator state so it can be resumed
+                    gen.stack = self.stack.split_off(self.fp);
+                    gen.pc_idx = unsafe { self.pc.offset_from(fun.insns.as_ptr()) } as usize + 1;
+                    gen.running = false;
+                    self.write_barrier(gen_val);
+
+                    self.fp = frame.prev_fp;
+                    self.pc = frame.ret_pc;
+
+                    // When resumed by a for-in loop, the generator stays on
+                    // the stack, otherwise it gets replaced by the value
+                    if !matches!(unsafe { *self.pc }, IterNext { .. }) {
+                        self.stack_pop();
+                    }
+
+                    self.stack.push(val);
+                }
+
+                Resume => {
+                    let gen_ptr = match self.stack[self.stack.len() - 1].kind() {
+                        Gen(gen_ptr) => gen_ptr,
+                        _ => panic!("resume expects a generator")
+                    };
+
+                    let gen = unsafe { &*gen_ptr };
+                    if self.stack_overflow(gen.stack.len()) {
+                        return Err(self.unwind("stack overflow", entry_depth));
+                    }
+
+                    if self.resume_gen(gen_ptr) {
+                        continue;
+                    }
+
+                    // Resuming a finished generator produces nil
+                    self.stack_pop();
+                    self.stack.push(Value::Nil);
+                }
+
+                Return => {
+                    let retv = self.stack_pop();
+                    let frame = self.frames.pop().unwrap();
+                    let fun = unsafe { &*frame.fun };
+
+                    if fun.is_generator {
+                        match self.stack[self.fp - 1].kind() {
+                            Gen(gen_ptr) => unsafe {
+                                (*gen_ptr).running = false;
+                                (*gen_ptr).done = true;
+                            }
+                            _ => panic!()
+                        }
+                    }
+
+                    // Pop the locals, arguments and callee
+                    self.stack.truncate(self.fp - 1);
+                    self.fp = frame.prev_fp;
+
+                    // If we are returning to the host
+                    if frame.ret_pc.is_null() {
+                        return Ok(EvalStatus::Done(retv));
+                    }
+
+                    self.pc = frame.ret_pc;
+
+                    // A generator that finishes ends the for-in loop resuming it
+                    match unsafe { *self.pc } {
+                        IterNext { offset } if fun.is_generator => {
+                            self.pc = unsafe { self.pc.offset(offset) };
+                        }
+                        _ => self.stack.push(retv)
+                    }
+                }
+
+                #[allow(unreachable_patterns)]
+                _ => panic!("unknown instruction in eval: {:?}", insn)
+            }
+
+            // Increment the PC
+            self.pc = unsafe { self.pc.add(1) };
+        }
+    }
+
+    /// Count a call or loop iteration of the current function, compiling
+    /// it once it gets hot, then run its machine code from the current pc
+    fn jit_tier_up(&mut self)
+    {
+        // Fuel and heap verification are checked before each
+        // instruction, which the machine code doesn't do
+        if self.fuel.is_some() || self.verify_heap {
+            return;
+        }
+
+        let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+
+        let code = match fun.jit_code.get() {
+            Some(code) => code,
+            None => {
+                let hotness = fun.hotness.get() + 1;
+                fun.hotness.set(hotness);
+
+                if hotness < self.jit_threshold {
+                    return;
+                }
+
+                fun.jit_code.get_or_init(|| jit::compile(fun))
+            }
+        };
+
+        if let Some(code) = code {
+            self.jit_run(fun, code);
+        }
+    }
+
+    /// Run the machine code of the current function until it returns
+    /// to the interpreter, leaving the pc at the next instruction to execute
+    fn jit_run(&mut self, fun: &Function, code: &JitCode)
+    {
+        let insn_idx = unsafe { self.pc.offset_from(fun.insns.as_ptr()) } as usize;
+
+        // The machine code writes values above the end of the stack,
+        // the stack length gets updated once it returns
+        self.stack.reserve(code.max_depth);
+
+        let mut ctx = JitCtx {
+            base: self.jit_frame_base(),
+            interrupt: Arc::as_ptr(&self.interrupt),
+            vm: self as *mut VM,
+            panic: None,
+        };
+
+        let exit_idx = code.run(&mut ctx, insn_idx);
+
+        if exit_idx == JIT_PANIC {
+            std::panic::resume_unwind(ctx.panic.take().unwrap());
+        }
+
+        let exit_idx = exit_idx as usize;
+        let depth = code.depths[exit_idx].expect("exit at unreachable instruction");
+
+        unsafe { self.stack.set_len(self.fp + fun.num_locals + depth) };
+        self.pc = &fun.insns[exit_idx] as *const Insn;
+    }
+
+    /// Address of the first local of the current frame
+    pub fn jit_frame_base(&mut self) -> *mut Value
+    {
+        unsafe { self.stack.as_mut_ptr().add(self.fp) }
+    }
+
+    /// Set the stack length to match the stack depth of machine code,
+    /// so that values above the locals are seen by the GC
+    fn jit_sync_stack(&mut self, depth: usize)
+    {
+        let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+        unsafe { self.stack.set_len(self.fp + fun.num_locals + depth) };
+    }
+
+    /// Instruction being executed by machine code
+    fn jit_insn(&self, insn_idx: usize) -> Insn
+    {
+        let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+        fun.insns[insn_idx]
+    }
+
+    /// Return value for helpers once an instruction is done,
+    /// stopping at the next instruction if an error is pending
+    fn jit_next(&self, next_idx: usize) -> u64
+    {
+        if self.pending_error.is_some() { next_idx as u64 } else { JIT_CONTINUE }
+    }
+
+    /// Binary operations on strings, called from machine code
+    /// Returns the instruction index to exit at if the
+    /// operation has to be done by the interpreter
+    pub fn jit_binop(&mut self, insn_idx: usize, depth: usize) -> u64
+    {
+        use ValueKind::*;
+
+        self.jit_sync_stack(depth);
+        let v1 = self.stack[self.stack.len() - 1];
+        let v0 = self.stack[self.stack.len() - 2];
+
+        let (s0, s1) = match (v0.kind(), v1.kind()) {
+            (Str(s0), Str(s1)) => unsafe { (&*s0, &*s1) },
+            _ => return insn_idx as u64
+        };
+
+        let result = match self.jit_insn(insn_idx) {
+            Insn::Add => {
+                if s0.len() + s1.len() > self.config.max_string_len {
+                    return insn_idx as u64;
+                }
+
+                // The operands stay on the stack while allocating
+                let mut out_str = String::from("");
+                out_str.push_str(s0);
+                out_str.push_str(s1);
+                self.into_gc_heap(out_str)
+            }
+            Insn::Eq => Value::Int64(str_eq(v0, v1) as i64),
+            Insn::Ne => Value::Int64(!str_eq(v0, v1) as i64),
+            _ => return insn_idx as u64
+        };
+
+        let top = self.stack.len() - 2;
+        self.stack[top] = result;
+        self.jit_next(insn_idx + 1)
+    }
+
+    /// Calls to host functions, called from machine code
+    /// Returns the instruction index to exit at if the
+    /// callee isn't a host function
+    pub fn jit_call_host(&mut self, insn_idx: usize, depth: usize) -> u64
+    {
+        let argc = match self.jit_insn(insn_idx) {
+            Insn::Call { argc } | Insn::TailCall { argc } => argc,
+            _ => panic!()
+        };
+
+        self.jit_sync_stack(depth);
+        let callee_idx = self.stack.len() - argc - 1;
+
+        let host_fn = match self.stack[callee_idx].kind() {
+            ValueKind::HostFn(host_fn) => host_fn,
+            _ => return insn_idx as u64
+        };
+
+        // This pointer is invalid if argc is zero
+        let args = match argc {
+            0 => std::ptr::null(),
+            _ => &self.stack[callee_idx + 1] as *const Value
+        };
+
+        let retv = host_fn.call(self, args, argc);
+        self.stack[callee_idx] = retv;
+        self.jit_next(insn_idx + 1)
+    }
+
+    /// Iteration over host iterators, called from machine code
+    /// Returns JIT_BRANCH once the iterator is exhausted, or the
+    /// instruction index to exit at for generators
+    pub fn jit_iter_next(&mut self, insn_idx: usize, depth: usize) -> u64
+    {
+        let offset = match self.jit_insn(insn_idx) {
+            Insn::IterNext { offset } => offset,
+            _ => panic!()
+        };
+
+        self.jit_sync_stack(depth);
+
+        let iter_ptr = match self.stack[self.stack.len() - 1].kind() {
+            ValueKind::Iter(iter_ptr) => iter_ptr,
+            _ => return insn_idx as u64
+        };
+
+        let iter = unsafe { &mut *iter_ptr };
+
+        match iter.next(self) {
+            Some(val) => {
+                let top = self.stack.len();
+                unsafe { *self.stack.as_mut_ptr().add(top) = val };
+                self.jit_next(insn_idx + 1)
+            }
+            None => {
+                let target_idx = (insn_idx as isize + 1 + offset) as usize;
+                if self.pending_error.is_some() { target_idx as u64 } else { JIT_BRANCH }
+            }
+        }
+    }
+
+    /// Get the value of a register in the current frame
+    #[inline(always)]
+    fn reg(&self, reg: u32) -> Value
+    {
+        self.stack[self.fp + reg as usize]
+    }
+
+    /// Set the value of a register in the current frame
+    #[inline(always)]
+    fn set_reg(&mut self, reg: u32, val: Value)
+    {
+        self.stack[self.fp + reg as usize] = val;
+    }
+
+    /// Set a register to an integer value
+    #[inline(always)]
+    fn set_reg_int(&mut self, reg: u32, val: i64)
+    {
+        let val = self.int64(val);
+        self.set_reg(reg, val);
+    }
+
+    /// Set a register to a boolean value
+    fn set_reg_bool(&mut self, reg: u32, val: bool)
+    {
+        self.set_reg(reg, Value::Int64(if val { 1 } else { 0 }));
+    }
+
+    /// Move the register pc by a branch offset
+    #[inline(always)]
+    fn reg_jump(&mut self, offset: isize)
+    {
+        self.reg_pc = unsafe { self.reg_pc.offset(offset) };
+    }
+
+    /// Resize the stack to hold the registers of the current frame,
+    /// after the registers above a call were used to pass arguments
+    fn restore_regs(&mut self)
+    {
+        let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+        self.stack.resize(self.fp + fun.reg_code.num_regs, Value::Nil);
+    }
+
+    /// Write a value produced by a callee or generator into the
+    /// register of the instruction it is returning to
+    fn set_ret_reg(&mut self, val: Value)
+    {
+        let dst = match unsafe { *self.reg_pc } {
+            RegInsn::Call { base, .. } | RegInsn::TailCall { base, .. } => base,
+            RegInsn::Resume { dst } | RegInsn::IterNext { dst, .. } => dst,
+            insn => panic!("unexpected return instruction {:?}", insn)
+        };
+
+        self.set_reg(dst, val);
+    }
+
+    /// Execute register-based instructions starting at the current pc
+    /// Frames use the same layout as with the stack backend, with
+    /// the registers in place of the locals and temporaries.
+    fn run_regs(&mut self, entry_depth: usize) -> Result<EvalStatus, RuntimeError>
+    {
+        use RegInsn::*;
+        use ValueKind::*;
+
+        loop
+        {
+            if let Some(status) = self.poll::<false, false>(entry_depth) {
+                return status;
+            }
+
+            let insn = unsafe { *self.reg_pc };
+
+            match insn {
+                Panic => panic!("panic"),
+
+                Halt => return Ok(EvalStatus::Done(Value::Nil)),
+
+                Move { dst, src } => {
+                    let val = self.reg(src);
+                    self.set_reg(dst, val);
+                }
+
+                LoadConst { dst, val } => {
+                    self.set_reg(dst, val);
+                }
+
+                GetIndex { dst, arr, idx } => {
+                    match (self.reg(arr).kind(), self.reg(idx).kind()) {
+                        (Array(arr_ptr), Int64(idx)) => {
+                            let arr = unsafe { &*arr_ptr };
+                            match usize::try_from(idx).ok().and_then(|idx| arr.get(idx)) {
+                                Some(val) => self.set_reg(dst, *val),
+                                None => panic!("array index {} out of bounds", idx)
+                            }
+                        }
                         _ => panic!()
                     }
                 }
 
-                Eq => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.push_bool(v0 == v1),
+                Add { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_int(dst, v0 + v1),
                         (Str(s0), Str(s1)) => unsafe {
-                            self.push_bool(&*s0 == &*s1);
+                            if (&*s0).len() + (&*s1).len() > self.config.max_string_len {
+                                return Err(self.unwind("string too long", entry_depth));
+                            }
+
+                            // The operands stay in their registers while allocating
+                            let mut out_str = String::from("");
+                            out_str.push_str(&*s0);
+                            out_str.push_str(&*s1);
+                            let val = self.into_gc_heap(out_str);
+                            self.set_reg(dst, val);
                         }
                         _ => panic!()
+                    }
+                }
+
+                Sub { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_int(dst, v0 - v1),
+                        _ => panic!()
+                    }
+                }
+
+                Mul { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_int(dst, v0 * v1),
+                        _ => panic!()
+                    }
+                }
+
+                Mod { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_int(dst, v0 % v1),
+                        _ => panic!()
+                    }
+                }
+
+                AddConst { dst, src, val } => {
+                    match self.reg(src).kind() {
+                        Int64(v0) => self.set_reg_int(dst, v0 + val as i64),
+                        _ => panic!()
+                    }
+                }
+
+                Neg { dst, src } => {
+                    match self.reg(src).kind() {
+                        Int64(v0) => self.set_reg_int(dst, -v0),
+                        _ => panic!()
+                    }
+                }
+
+                Eq { dst, a, b } => {
+                    let (v0, v1) = (self.reg(a), self.reg(b));
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_bool(dst, v0 == v1),
+                        (Str(_), Str(_)) => self.set_reg_bool(dst, str_eq(v0, v1)),
+                        // Values of different types are never equal
+                        _ => self.set_reg_bool(dst, v0 == v1)
                     };
                 }
 
-                Ne => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.push_bool(v0 != v1),
+                Ne { dst, a, b } => {
+                    let (v0, v1) = (self.reg(a), self.reg(b));
+                    match (v0.kind(), v1.kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_bool(dst, v0 != v1),
+                        (Str(_), Str(_)) => self.set_reg_bool(dst, !str_eq(v0, v1)),
+                        _ => self.set_reg_bool(dst, v0 != v1)
+                    };
+                }
+
+                Lt { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_bool(dst, v0 < v1),
                         _ => panic!()
                     };
                 }
 
-                Lt => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.push_bool(v0 < v1),
+                Gt { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => self.set_reg_bool(dst, v0 > v1),
                         _ => panic!()
                     };
                 }
 
-                Gt => {
-                    let v1 = self.stack_pop();
-                    let v0 = self.stack_pop();
-                    match (v0, v1) {
-                        (Int64(v0), Int64(v1)) => self.push_bool(v0 > v1),
+                Not { dst, src } => {
+                    match self.reg(src).kind() {
+                        Int64(v0) => self.set_reg_bool(dst, v0 == 0),
                         _ => panic!()
                     };
                 }
 
-                Not => {
-                    let v0 = self.stack_pop();
-                    match v0 {
-                        Int64(v0) => self.push_bool(v0 == 0),
+                Range { dst, a, b } => {
+                    match (self.reg(a).kind(), self.reg(b).kind()) {
+                        (Int64(v0), Int64(v1)) => {
+                            let range: Box<dyn HostIter> = Box::new(RangeIter::new(v0, v1));
+                            let range = self.into_gc_heap(range);
+                            self.set_reg(dst, range);
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                GetIter { dst, src } => {
+                    // The iterable stays in its register while
+                    // allocating the iterator so it can't be collected
+                    let val = self.reg(src);
+
+                    // Iterators and generators are their own iterators
+                    let iter_val = match val.kind() {
+                        Iter(_) | Gen(_) => val,
+                        _ => match self.get_iter(val) {
+                            Some(iter) => self.into_gc_heap(iter),
+                            None => panic!("value is not iterable")
+                        }
+                    };
+
+                    self.set_reg(dst, iter_val);
+                }
+
+                IterNext { iter, dst, offset } => {
+                    let iter_ptr = match self.reg(iter).kind() {
+                        Iter(iter_ptr) => iter_ptr,
+                        Gen(gen_ptr) => {
+                            let gen = unsafe { &*gen_ptr };
+                            if self.stack_overflow(gen.stack.len()) {
+                                return Err(self.unwind("stack overflow", entry_depth));
+                            }
+
+                            // The generator serves as the callee slot of its frame
+                            self.stack.truncate(self.fp + iter as usize + 1);
+
+                            // Run the generator until it yields the next value
+                            if self.resume_gen(gen_ptr) {
+                                continue;
+                            }
+
+                            self.restore_regs();
+                            self.reg_jump(offset as isize + 1);
+                            continue;
+                        }
+                        _ => panic!()
+                    };
+
+                    let iter = unsafe { &mut *iter_ptr };
+
+                    match iter.next(self) {
+                        Some(val) => self.set_reg(dst, val),
+                        None => self.reg_jump(offset as isize)
+                    }
+                }
+
+                Jump { offset } => {
+                    self.reg_jump(offset as isize);
+                }
+
+                IfTrue { src, offset } => {
+                    match self.reg(src).kind() {
+                        Int64(v) => {
+                            if v != 0 {
+                                self.reg_jump(offset as isize);
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                IfFalse { src, offset } => {
+                    match self.reg(src).kind() {
+                        Int64(v) => {
+                            if v == 0 {
+                                self.reg_jump(offset as isize);
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                JumpIfGeConst { src, val, offset } => {
+                    match self.reg(src).kind() {
+                        Int64(v) => {
+                            if v >= val as i64 {
+                                self.reg_jump(offset as isize);
+                            }
+                        }
+                        _ => panic!()
+                    }
+                }
+
+                JumpTable { src, table_idx } => {
+                    let fun = unsafe { &*self.frames[self.frames.len() - 1].fun };
+                    let table = &fun.reg_code.jump_tables[table_idx as usize];
+
+                    // Values that aren't integers or fall outside the
+                    // table go to the default case
+                    let entry_idx = match self.reg(src).kind() {
+                        Int64(v) => v.checked_sub(table.min_val).and_then(|i| usize::try_from(i).ok()),
+                        _ => None
+                    };
+
+                    let offset = match entry_idx {
+                        Some(i) if i < table.offsets.len() => table.offsets[i],
+                        _ => table.default_offset
+                    };
+
+                    self.reg_jump(offset);
+                }
+
+                Call { base, argc } | TailCall { base, argc } => {
+                    let callee_idx = self.fp + base as usize;
+                    let callee = self.stack[callee_idx];
+                    let argc = argc as usize;
+
+                    // The registers above the arguments are free, so the
+                    // arguments are passed at the top of the stack
+                    self.stack.truncate(callee_idx + 1 + argc);
+
+                    // This pointer is invalid if argc is zero
+                    let args = match argc {
+                        0 => std::ptr::null(),
+                        _ => &self.stack[callee_idx + 1] as *const Value
+                    };
+
+                    match callee.kind() {
+                        HostFn(host_fn) => {
+                            let retv = host_fn.call(self, args, argc);
+                            self.restore_regs();
+                            self.set_reg(base, retv);
+                        }
+
+                        Fun(fun_ptr) => {
+                            let fun = unsafe { &*fun_ptr };
+                            let entry_idx = match self.prep_args(fun, argc) {
+                                Ok(idx) => fun.reg_code.entry_idxs[idx],
+                                Err(msg) => return Err(self.unwind(&msg, entry_depth))
+                            };
+                            let argc = fun.params.len();
+                            let num_regs = fun.reg_code.num_regs;
+
+                            // Calling a generator function creates a generator
+                            // holding the arguments, without running the body
+                            if fun.is_generator {
+                                let mut gen_stack = self.stack[self.stack.len() - argc..].to_vec();
+                                gen_stack.resize(num_regs, Value::Nil);
+
+                                let gen = self.into_gc_heap(Generator {
+                                    fun: callee,
+                                    stack: gen_stack,
+                                    pc_idx: entry_idx,
+                                    running: false,
+                                    done: false,
+                                });
+
+                                self.restore_regs();
+                                self.set_reg(base, gen);
+                            }
+                            else if let TailCall { .. } = insn {
+                                if self.fp + num_regs > self.config.max_stack_size {
+                                    return Err(self.unwind("stack overflow", entry_depth));
+                                }
+
+                                // Move the callee and arguments over the current
+                                // frame, which the callee takes over
+                                self.stack.copy_within(callee_idx.., self.fp - 1);
+                                self.stack.truncate(self.fp + argc);
+                                self.stack.resize(self.fp + num_regs, Value::Nil);
+
+                                let frame = self.frames.last_mut().unwrap();
+                                frame.fun = fun_ptr;
+
+                                self.reg_pc = &fun.reg_code.insns[entry_idx];
+                                continue;
+                            }
+                            else
+                            {
+                                if self.stack_overflow(num_regs - argc) {
+                                    return Err(self.unwind("stack overflow", entry_depth));
+                                }
+
+                                self.frames.push(Frame {
+                                    fun: fun_ptr,
+                                    prev_fp: self.fp,
+                                    ret_pc: std::ptr::null(),
+                                    ret_reg_pc: self.reg_pc,
+                                });
+
+                                // The arguments become the first registers of the callee
+                                self.fp = self.stack.len() - argc;
+                                self.stack.resize(self.fp + num_regs, Value::Nil);
+
+                                self.reg_pc = &fun.reg_code.insns[entry_idx];
+                                continue;
+                            }
+                        }
+
+                        _ => panic!("callee is not a function")
+                    }
+                }
+
+                Yield { src } => {
+                    let val = self.reg(src);
+                    let frame = self.frames.pop().unwrap();
+                    let fun = unsafe { &*frame.fun };
+
+                    // The generator is in the callee slot of this frame
+                    let gen_val = self.stack[self.fp - 1];
+                    let gen = match gen_val.kind() {
+                        Gen(gen_ptr) => unsafe { &mut *gen_ptr },
                         _ => panic!()
                     };
-                }
 
-                Jump{ offset } => {
-                    self.pc = unsafe { self.pc.offset(offset as isize) };
+                    // Save the generator state so it can be resumed
+                    gen.stack = self.stack.split_off(self.fp);
+                    gen.pc_idx = unsafe { self.reg_pc.offset_from(fun.reg_code.insns.as_ptr()) } as usize + 1;
+                    gen.running = false;
+                    self.write_barrier(gen_val);
+
+                    self.fp = frame.prev_fp;
+                    self.reg_pc = frame.ret_reg_pc;
+
+                    // When resumed by a for-in loop, the generator stays in
+                    // its register, otherwise it gets replaced by the value
+                    self.restore_regs();
+                    self.set_ret_reg(val);
                 }
 
-                IfTrue{ offset } => {
-                    let v = self.stack_pop();
-                    match v {
-                        Int64(v) => {
-                            if v != 0 {
-                                self.pc = unsafe { self.pc.offset(offset as isize) }
-                            }
-                        }
-                        _ => panic!()
+                Resume { dst } => {
+                    let gen_ptr = match self.reg(dst).kind() {
+                        Gen(gen_ptr) => gen_ptr,
+                        _ => panic!("resume expects a generator")
+                    };
+
+                    let gen = unsafe { &*gen_ptr };
+                    if self.stack_overflow(gen.stack.len()) {
+                        return Err(self.unwind("stack overflow", entry_depth));
+                    }
+
+                    // The generator serves as the callee slot of its frame
+                    self.stack.truncate(self.fp + dst as usize + 1);
+
+                    if self.resume_gen(gen_ptr) {
+                        continue;
                     }
+
+                    // Resuming a finished generator produces nil
+                    self.restore_regs();
+                    self.set_reg(dst, Value::Nil);
                 }
 
-                IfFalse{ offset } => {
-                    let v = self.stack_pop();
-                    match v {
-                        Int64(v) => {
-                            if v == 0 {
-                                self.pc = unsafe { self.pc.offset(offset as isize) }
+                Return { src } => {
+                    let retv = self.reg(src);
+                    let frame = self.frames.pop().unwrap();
+                    let fun = unsafe { &*frame.fun };
+
+                    if fun.is_generator {
+                        match self.stack[self.fp - 1].kind() {
+                            Gen(gen_ptr) => unsafe {
+                                (*gen_ptr).running = false;
+                                (*gen_ptr).done = true;
                             }
+                            _ => panic!()
                         }
-                        _ => panic!()
+                    }
+
+                    // Pop the registers and callee
+                    self.stack.truncate(self.fp - 1);
+                    self.fp = frame.prev_fp;
+
+                    // If we are returning to the host
+                    if frame.ret_reg_pc.is_null() {
+                        return Ok(EvalStatus::Done(retv));
+                    }
+
+                    self.reg_pc = frame.ret_reg_pc;
+                    self.restore_regs();
+
+                    // A generator that finishes ends the for-in loop resuming it
+                    match unsafe { *self.reg_pc } {
+                        IterNext { offset, .. } if fun.is_generator => self.reg_jump(offset as isize),
+                        _ => self.set_ret_reg(retv)
                     }
                 }
+            }
 
-                Call { argc } => {
-                    // The callee was pushed on the stack first
-                    let callee = &self.stack[self.stack.len() - argc - 1];
+            // Increment the PC
+            self.reg_jump(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::parser::*;
+    use ValueKind::*;
+
+    fn eval_done(vm: &mut VM, unit_fn: &Function) -> Value
+    {
+        match vm.eval(unit_fn).unwrap() {
+            EvalStatus::Done(val) => val,
+            status => panic!("unexpected status {:?}", status)
+        }
+    }
+
+    /// Check that two backends produced the same value, with the
+    /// same bits unless the values are strings in different heaps
+    fn assert_same_result(stack_val: Value, other_val: Value)
+    {
+        match (stack_val.kind(), other_val.kind()) {
+            (Str(s0), Str(s1)) => unsafe { assert_eq!(&*s0, &*s1) },
+            (Int64(v0), Int64(v1)) => assert_eq!(v0, v1),
+            _ => assert_eq!(stack_val.bits(), other_val.bits())
+        }
+    }
+
+    /// Create a VM using the given backend, compiling
+    /// every function into machine code with the JIT
+    fn new_vm(backend: Backend) -> VM
+    {
+        let mut vm = VM::new();
+        vm.set_backend(backend);
+        vm.set_jit_threshold(0);
+        vm
+    }
+
+    /// Evaluate a unit with every backend
+    fn eval_backends(parse: impl Fn(&mut VM) -> Function) -> Value
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse(&mut vm);
+        let val = eval_done(&mut vm, &unit_fn);
+
+        for backend in [Backend::Register, Backend::Jit] {
+            let mut other_vm = new_vm(backend);
+            let unit_fn = parse(&mut other_vm);
+            assert_same_result(val, eval_done(&mut other_vm, &unit_fn));
+        }
+
+        val
+    }
+
+    fn eval_src(src: &str) -> Value
+    {
+        dbg!(src);
+        eval_backends(|vm| {
+            let mut input = Input::new(src, "test_src");
+            parse_unit(vm, &mut input).unwrap()
+        })
+    }
+
+    fn eval_file(file_name: & str) -> Value
+    {
+        dbg!(file_name);
+        eval_backends(|vm| parse_file(vm, file_name).unwrap())
+    }
+
+    #[test]
+    fn test_eval()
+    {
+        assert_eq!(eval_src(""), Nil);
+        assert_eq!(eval_src("1;"), Nil);
+        assert_eq!(eval_src("return 7;"), Int64(7));
+        assert_eq!(eval_src("return 1 + 7;"), Int64(8));
+        assert_eq!(eval_src("return 1 + 2 + 3;"), Int64(6));
+        assert_eq!(eval_src("return -3;"), Int64(-3));
+    }
+
+    #[test]
+    fn test_value_packing()
+    {
+        assert_eq!(size_of::<Value>(), 8);
+
+        for v in [0, 1, -1, 77, -1000, Value::MIN_PACKED_INT, Value::MAX_PACKED_INT] {
+            assert_eq!(Value::Int64(v).kind(), Int64(v));
+        }
+
+        assert_eq!(Value::UInt64(123).kind(), UInt64(123));
+        assert_eq!(Value::Nil.kind(), Nil);
+        assert_ne!(Value::Int64(0), Value::Nil);
+
+        // Integers that don't fit are boxed, and compare by value
+        let mut vm = VM::new();
+        for v in [i64::MIN, Value::MIN_PACKED_INT - 1, Value::MAX_PACKED_INT + 1, i64::MAX] {
+            let val = vm.int64(v);
+            assert_eq!(val.kind(), Int64(v));
+            assert_eq!(val, vm.int64(v));
+            assert_ne!(val.bits(), vm.int64(v).bits());
+        }
+        assert_eq!(vm.uint64(u64::MAX).kind(), UInt64(u64::MAX));
+        assert_eq!(vm.int64(5), Value::Int64(5));
+        assert_ne!(vm.int64(i64::MAX), vm.int64(i64::MIN));
+
+        let str_val = vm.into_gc_heap("foo");
+        match str_val.kind() {
+            Str(str_ptr) => assert_eq!(unsafe { &*str_ptr }, "foo"),
+            _ => panic!()
+        }
+        assert_ne!(str_val, Value::Nil);
+    }
+
+    #[test]
+    fn test_const_folding()
+    {
+        assert_eq!(eval_src("return 1 + 2 * 3 - 4 % 3;"), Int64(6));
+        assert_eq!(eval_src("return -(2 - 5) + !0 + !7;"), Int64(4));
+        assert_eq!(eval_src("return (1 < 2) + (3 > 4) + (5 == 5) + (5 != 5);"), Int64(2));
+        assert_eq!(eval_src("return 'foo' + 'bar' == 'foobar';"), Int64(1));
+        assert_eq!(eval_src("return len('ab' + 'cd' + 'ef');"), Int64(6));
+        assert_eq!(eval_src("return 'a' != 'b';"), Int64(1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fold_mod_zero()
+    {
+        // Errors still happen at run time
+        eval_src("return 1 % 0;");
+    }
+
+    /// Evaluate source code with every backend, for code that returns
+    /// a heap value, which must be checked before the VM is dropped
+    fn eval_src_with(src: &str, check: impl Fn(Value))
+    {
+        for backend in [Backend::Stack, Backend::Register, Backend::Jit] {
+            let mut vm = new_vm(backend);
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            check(eval_done(&mut vm, &unit_fn));
+        }
+    }
+
+    #[test]
+    fn test_big_ints()
+    {
+        let max = Value::MAX_PACKED_INT;
+
+        // Folded constants and literals
+        eval_src_with(&format!("return {} * 2;", max), |v| assert_eq!(v, Int64(max * 2)));
+        eval_src_with("return 9_223_372_036_854_775_807;", |v| assert_eq!(v, Int64(i64::MAX)));
+        eval_src_with("return -9_223_372_036_854_775_807 - 1;", |v| assert_eq!(v, Int64(i64::MIN)));
+
+        // Arithmetic at run time that leaves the packed range and comes back
+        let src = format!("let x = {}; let y = x + 1; return (y - x) + (y == {} + 1) + (y != x);", max, max);
+        assert_eq!(eval_src(&src), Int64(3));
+        let src = "let x = 1; let i = 0; while (i < 62) { x = x * 2; i = i + 1; } return x;";
+        eval_src_with(src, |v| assert_eq!(v, Int64(1 << 62)));
+        let src = format!("let x = {}; x = x + 1; x = x + 1; return x - 2 == {};", max, max);
+        assert_eq!(eval_src(&src), Int64(1));
+        let src = format!("let x = 0 - {}; return -x - x;", max);
+        eval_src_with(&src, |v| assert_eq!(v, Int64(2 * max)));
+
+        // Boxed integers are kept alive by the GC
+        let src = format!("let a = 0 + {}; for (i in 0..100) {{ let b = 'x' + 'y'; a = a + 1; }} return a;", max);
+        let (val, _vm) = eval_src_gc(&src, false);
+        assert_eq!(val, Int64(max + 100));
+        let (val, _vm) = eval_src_gc(&src, true);
+        assert_eq!(val, Int64(max + 100));
+    }
+
+    #[test]
+    fn test_infix_priority()
+    {
+        // Priority of operations
+        assert_eq!(eval_src("return 1 + 2 * 3;"), Int64(7));
+        assert_eq!(eval_src("return 1 + 2 + 3 + 4;"), Int64(10));
+        assert_eq!(eval_src("return 1 * 2 + 3 * 4;"), Int64(14));
+        assert_eq!(eval_src("return (1 + 2) * 3;"), Int64(9));
+        assert_eq!(eval_src("return (1 * 2) + (3 * 4);"), Int64(14));
+        assert_eq!(eval_src("return 1 + 2 * 3 + 4;"), Int64(11));
+        assert_eq!(eval_src("return -(1 + 2 * 3) + 4;"), Int64(-3));
+
+        // Subtract and operand ordering
+        assert_eq!(eval_src("return 5 - 3;"), Int64(2));
+        assert_eq!(eval_src("return 5 + 2 - 3;"), Int64(4));
+    }
+
+    #[test]
+    fn test_let_stmt()
+    {
+        eval_src("let x = 3;");
+        assert_eq!(eval_src("let x = 2; return x;"), Int64(2));
+        assert_eq!(eval_src("let x = 2; let y = 3; return x + y;"), Int64(5));
+    }
+
+    #[test]
+    fn test_assign()
+    {
+        assert_eq!(eval_src("let x = 2; x = 3; return x;"), Int64(3));
+        assert_eq!(eval_src("let x = 1; let y = 1; x = y = 3; return x+y;"), Int64(6));
+        assert_eq!(eval_src("let x = 1; let y = 1; x = y = x + 3; return x+y;"), Int64(8));
+
+        // Nested scopes
+        assert_eq!(eval_src("let x = 2; { return x; }"), Int64(2));
+        assert_eq!(eval_src("let x = 2; { let x = 3; return x; }"), Int64(3));
+        assert_eq!(eval_src("let x = 2; { let x = 3; x; } return x; "), Int64(2));
+    }
+
+    #[test]
+    fn test_assert()
+    {
+        eval_src("assert 3;");
+    }
+
+    #[test]
+    fn test_if()
+    {
+        assert_eq!(eval_src("let x = 0; if (0) x = 2; return x;"), Int64(0));
+        assert_eq!(eval_src("let x = 0; if (1) x = 2; return x;"), Int64(2));
+        assert_eq!(eval_src("let x = 0; if (0) x = x+2; else x = x+1; return x;"), Int64(1));
+        assert_eq!(eval_src("let x = 0; if (1) x = x+2; else x = x+1; return x;"), Int64(2));
+        assert_eq!(eval_src("let x = 0; if (x) return 1; else return 0;"), Int64(0));
+        assert_eq!(eval_src("let x = 0; if (!x) return 1; else return 0;"), Int64(1));
+    }
+
+    #[test]
+    fn test_while()
+    {
+        assert_eq!(eval_src("let i = 0; while (i < 10) i = i + 1; return i;"), Int64(10));
+    }
+
+    #[test]
+    fn test_match()
+    {
+        // Dense integer cases, compiled to a jump table
+        let src = "let x = 0; match ($V) { 0 => x = 10; 1 => x = 11; 2 => x = 12; 4 => x = 14; _ => x = 99; } return x;";
+        assert_eq!(eval_src(&src.replace("$V", "0")), Int64(10));
+        assert_eq!(eval_src(&src.replace("$V", "2")), Int64(12));
+        assert_eq!(eval_src(&src.replace("$V", "4")), Int64(14));
+        assert_eq!(eval_src(&src.replace("$V", "3")), Int64(99));
+        assert_eq!(eval_src(&src.replace("$V", "-1")), Int64(99));
+        assert_eq!(eval_src(&src.replace("$V", "'a'")), Int64(99));
+
+        // Sparse and mixed cases, compiled to compare-and-branch
+        let src = "let x = 0; match ($V) { 1 => x = 1; 'a' => { x = 2; }, 1000 => x = 3; _ => { x = 4; } } return x;";
+        assert_eq!(eval_src(&src.replace("$V", "1")), Int64(1));
+        assert_eq!(eval_src(&src.replace("$V", "'a'")), Int64(2));
+        assert_eq!(eval_src(&src.replace("$V", "1000")), Int64(3));
+        assert_eq!(eval_src(&src.replace("$V", "'b'")), Int64(4));
+
+        // No default case
+        assert_eq!(eval_src("let x = 0; match (5) { 1 => x = 1; 2 => x = 2; 3 => x = 3; } return x;"), Int64(0));
+        assert_eq!(eval_src("let x = 0; match (5) { 'a' => x = 1; } return x;"), Int64(0));
+
+        // The matched expression is popped off the stack
+        assert_eq!(eval_src("let i = 0; while (i < 10) { match (i) { 1 => i = i + 1; 'a' => i = 0; } i = i + 1; } return i;"), Int64(10));
+    }
+
+    #[test]
+    fn test_for_in()
+    {
+        // Integer ranges
+        assert_eq!(eval_src("let n = 0; for (i in 0..10) n = n + i; return n;"), Int64(45));
+        assert_eq!(eval_src("let n = 0; for (i in 5..5) n = n + 1; return n;"), Int64(0));
+        assert_eq!(eval_src("let n = 0; for (i in 1..2+2) n = n + i; return n;"), Int64(6));
+        assert_eq!(eval_src("let n = 0; for (i in 0..3) for (j in 0..4) n = n + 1; return n;"), Int64(12));
+
+        // Ranges are iterators that get used up
+        assert_eq!(eval_src("let r = 0..3; let n = 0; for (i in r) n = n + 1; for (i in r) n = n + 1; return n;"), Int64(3));
+
+        // Strings iterate over their characters
+        assert_eq!(eval_src("let s = ''; for (c in 'abc') s = c + s; return s == 'cba';"), Int64(1));
+        assert_eq!(eval_src("let n = 0; for (c in '') n = n + 1; return n;"), Int64(0));
+
+        // Returning from inside a loop
+        assert_eq!(eval_src("for (i in 0..10) if (i == 3) return i; return 0;"), Int64(3));
+    }
+
+    #[test]
+    fn test_register_iterable()
+    {
+        // Make integers iterate from 0 up to their value
+        let mut vm = VM::new();
+        vm.register_iterable(|val| match val.kind() {
+            Int64(n) => Some(Box::new(RangeIter::new(0, n))),
+            _ => None
+        });
+
+        let unit_fn = parse_str(&mut vm, "let n = 0; for (i in 5) n = n + i; for (c in 'ab') n = n + 1; return n;").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(12)));
+    }
+
+    #[test]
+    fn test_call()
+    {
+        assert_eq!(eval_src("let f = fun() { return 7; }; return f();"), Int64(7));
+        assert_eq!(eval_src("let f = fun(a, b) { return a - b; }; return f(5, 3);"), Int64(2));
+        assert_eq!(eval_src("let f = fun(a) { let b = a + 1; return b * 2; }; return f(1) + f(2);"), Int64(10));
+        assert_eq!(eval_src("let f = fun() {}; return f();"), Nil);
+        assert_eq!(eval_src("let f = fun(x) { return x + 1; }; let g = fun(f, x) { return f(f(x)); }; return g(f, 1);"), Int64(3));
+        assert_eq!(eval_src("let n = 0; let f = fun() { return 1; }; for (i in 0..5) n = n + f(); return n;"), Int64(5));
+    }
+
+    #[test]
+    fn test_fun_decl()
+    {
+        assert_eq!(eval_src("fun f(x) { return x + 1; } return f(1);"), Int64(2));
+        assert_eq!(eval_src("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } return fib(15);"), Int64(610));
+        assert_eq!(eval_src("fun f() { return 1; } fun g() { fun h() { return f() + 1; } return h() + 1; } return g();"), Int64(3));
+        assert_eq!(eval_src("fun f() { return 1; } { fun f() { return 2; } return f(); }"), Int64(2));
+    }
+
+    #[test]
+    fn test_tail_calls()
+    {
+        // Deep tail recursion runs in constant stack space
+        let mut vm = VM::new();
+        let src = "fun count(n, acc) { if (n == 0) return acc; return count(n - 1, acc + 1); } return count(1_000_000, 0);";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1_000_000)));
+        assert_eq!(vm.stack_size(), 0);
+        assert_eq!(vm.frames.len(), 0);
+
+        // Tail calls to other functions, host functions and generators
+        assert_eq!(eval_src("fun f(x) { return x * 2; } fun g(x) { return f(x + 1); } return g(1) + 1;"), Int64(5));
+        assert_eq!(eval_src("fun apply(f, x) { return f(x); } return apply(fun(x) { return x + 1; }, 1);"), Int64(2));
+        assert_eq!(eval_src("fun f(...args) { return len(args); } return f(1, 2, 3);"), Int64(3));
+        assert_eq!(eval_src("fun gen() { yield 1; } fun f() { return gen(); } return resume(f());"), Int64(1));
+
+        // Tail calls inside of generators are regular calls
+        assert_eq!(eval_src("fun f(x) { return x; } fun gen() { yield 1; return f(2); } let g = gen(); return resume(g) + resume(g);"), Int64(3));
+    }
+
+    #[test]
+    fn test_register_backend()
+    {
+        let mut vm = VM::new();
+        vm.set_backend(Backend::Register);
+
+        // Deep tail recursion runs in constant stack space
+        let src = "fun count(n, acc) { if (n == 0) return acc; return count(n - 1, acc + 1); } return count(1_000_000, 0);";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1_000_000)));
+        assert_eq!(vm.stack_size(), 0);
+
+        // The stack is unwound after an error
+        let unit_fn = parse_str(&mut vm, "fun f(n) { return 1 + f(n + 1); } f(0);").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap_err().msg, "stack overflow");
+        assert_eq!(vm.stack_size(), 0);
+        assert_eq!(vm.frames.len(), 0);
+
+        // Execution can be suspended inside of generators
+        let unit_fn = parse_str(&mut vm, "fun g() { for (i in 0..1000) yield i; } fun f() { let s = 0; for (x in g()) s = s + x; return s; } return f();").unwrap();
+        vm.set_fuel(Some(1000));
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::OutOfFuel);
+        vm.set_fuel(None);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Done(Value::Int64(499500)));
+        assert_eq!(vm.stack_size(), 0);
+    }
+
+    #[test]
+    fn test_jit()
+    {
+        // Functions only get compiled once they are hot
+        let src = "fun f(n) { return n + 1; } let s = 0; for (i in 0..100) s = f(s); return s;";
+        for (threshold, compiled) in [(1000, false), (10, true)] {
+            let mut vm = VM::new();
+            vm.set_backend(Backend::Jit);
+            vm.set_jit_threshold(threshold);
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(100)));
+            assert_eq!(unit_fn.jit_code.get().is_some(), compiled);
+        }
+
+        // Loops running in machine code can be interrupted
+        let mut vm = new_vm(Backend::Jit);
+        let unit_fn = parse_str(&mut vm, "let i = 0; while (1) { i = i + 1; if (i == 10) i = 0; }").unwrap();
+        let handle = vm.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        });
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Interrupted);
+        thread.join().unwrap();
+        vm.abort();
+
+        // Operations that can't be done in machine code fall back to the interpreter
+        assert_eq!(eval_src("let x = 1; let s = ''; for (i in 0..3) { s = s + 'a'; x = x * 1000; } return len(s) + x;"), Int64(1_000_000_003));
+        assert_eq!(eval_src("let a = 'ab'; let b = 'a'; return (a == b + 'b') + (a != 'ab') * 2 + (a == 3) * 4;"), Int64(1));
+        assert_eq!(eval_src("let s = 0; for (c in 'abc') s = s + 1; for (x in 0..4) s = s + x; return s;"), Int64(9));
+        assert_eq!(eval_src("let x = -7; let y = x % 3; let z = x % 5; return y * 10 + z;"), Int64(-12));
+
+        // Running out of memory in a helper stops at the next instruction
+        let mut vm = VM::with_config(VMConfig { max_heap_size: 50_000, ..VMConfig::default() });
+        vm.set_backend(Backend::Jit);
+        vm.set_jit_threshold(0);
+        let unit_fn = parse_str(&mut vm, "let s = 'ab'; while (1) s = s + 'ab';").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap_err().msg, "out of memory");
+        assert_eq!(vm.stack_size(), 0);
+    }
+
+    #[test]
+    fn test_jit_overflow()
+    {
+        // Results that can't be packed exit to the interpreter to be boxed
+        let mut vm = new_vm(Backend::Jit);
+        let unit_fn = parse_str(&mut vm, "let x = 576460752303423487; return x + 1;").unwrap();
+        assert_eq!(eval_done(&mut vm, &unit_fn), Int64(576460752303423488));
+    }
+
+    #[test]
+    #[should_panic(expected = "len expects an array or a string")]
+    fn test_jit_host_panic()
+    {
+        let mut vm = new_vm(Backend::Jit);
+        let unit_fn = parse_str(&mut vm, "let x = 5; return len(x);").unwrap();
+        vm.eval(&unit_fn).unwrap();
+    }
+
+    #[test]
+    fn test_stack_overflow()
+    {
+        let mut vm = VM::new();
+        let src = "fun f(n) { return 1 + f(n + 1); } f(0);";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "stack overflow");
+
+        // The stack trace keeps the innermost and outermost frames
+        assert_eq!(err.stack_trace.len(), TRACE_HEAD_FRAMES + 1 + TRACE_TAIL_FRAMES);
+        assert_eq!(err.stack_trace[0], "f");
+        assert_eq!(err.stack_trace[TRACE_HEAD_FRAMES], format!("... {} more frames", 10_000 - TRACE_HEAD_FRAMES - TRACE_TAIL_FRAMES));
+        assert_eq!(err.stack_trace[err.stack_trace.len() - 1], "src");
+
+        // The VM can still be used afterwards
+        assert_eq!(vm.stack_size(), 0);
+        assert_eq!(vm.frames.len(), 0);
+        let unit_fn = parse_str(&mut vm, "fun f(n) { if (n == 0) return 0; return 1 + f(n - 1); } return f(100);").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(100)));
+
+        // Value stack limit
+        let mut vm = VM::with_config(VMConfig { max_frames: 1000, max_stack_size: 100, ..VMConfig::default() });
+        let src = "fun f(n) { let a = 0; let b = 0; let c = 0; if (n == 0) return 0; return 1 + f(n - 1); } return f(50);";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "stack overflow");
+        assert!(err.stack_trace.len() < 25);
+        assert_eq!(vm.stack_size(), 0);
+    }
+
+    #[test]
+    fn test_out_of_memory()
+    {
+        let config = VMConfig { max_heap_size: 50_000, ..VMConfig::default() };
+
+        // Garbage gets collected to stay under the limit
+        let mut vm = VM::with_config(config);
+        let unit_fn = parse_str(&mut vm, "let a = 'a'; let s = ''; for (i in 0..1000) s = a + 'b'; return s == 'ab';").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+        assert!(vm.heap_size() <= 50_000);
+
+        // Strings that stay live exhaust the heap
+        let src = "fun f(s) { return f(s + s); } let s = 'x'; return f(s);";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "out of memory");
+        assert_eq!(vm.stack_size(), 0);
+
+        // The string that didn't fit was never allocated
+        assert!(vm.heap_size() <= 50_000);
+
+        // The memory is reclaimed and the VM can still be used
+        vm.gc_collect();
+        assert!(vm.heap_size() <= 50_000);
+        let unit_fn = parse_str(&mut vm, "return len('a' + 'b');").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(2)));
+    }
+
+    #[test]
+    fn test_max_string_len()
+    {
+        let mut vm = VM::with_config(VMConfig { max_string_len: 8, ..VMConfig::default() });
+        let unit_fn = parse_str(&mut vm, "return len('abcd' + 'efgh');").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(8)));
 
-                    // The last argument is at the top
-                    // This pointer is invalid if argc is zero
-                    let args = match argc {
-                        0 => 0 as *const Value,
-                        _ => &self.stack[self.stack.len() - argc] as *const Value
-                    };
+        let unit_fn = parse_str(&mut vm, "let s = 'ab'; while (1) s = s + s;").unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "string too long");
+    }
 
-                    match callee {
-                        HostFn(host_fn) => {
-                            let retv = host_fn(args, argc);
-                            self.stack.push(retv);
-                        }
-                        _ => panic!()
-                    }
-                }
+    #[test]
+    fn test_fuel()
+    {
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "let s = 0; for (i in 0..1000) s = s + i; return s;").unwrap();
 
-                Return => {
-                    return self.stack_pop();
-                }
+        vm.set_fuel(Some(100));
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::OutOfFuel);
+        assert!(vm.is_suspended());
+        assert_eq!(vm.fuel(), Some(0));
 
-                #[allow(unreachable_patterns)]
-                _ => panic!("unknown instruction in eval: {:?}", insn)
+        // Keep refueling until the loop completes
+        let mut num_resumes = 0;
+        let retv = loop {
+            vm.set_fuel(Some(100));
+            match vm.resume().unwrap() {
+                EvalStatus::OutOfFuel => num_resumes += 1,
+                EvalStatus::Done(val) => break val,
+                EvalStatus::Interrupted | EvalStatus::Break => panic!()
             }
+        };
 
-            // Increment the PC
-            self.pc = unsafe { self.pc.add(1) };
-        }
-    }
-}
+        assert_eq!(retv, Int64(499500));
+        assert!(num_resumes > 10);
+        assert!(!vm.is_suspended());
+        assert_eq!(vm.stack_size(), 0);
 
-#[cfg(test)]
-mod tests
-{
-    use super::*;
-    use crate::parser::*;
-    use Value::*;
+        // Fuel also counts instructions executed in callees and generators
+        let unit_fn = parse_str(&mut vm, "fun g() { for (i in 0..1000) yield i; } fun f() { let s = 0; for (x in g()) s = s + x; return s; } return f();").unwrap();
+        vm.set_fuel(Some(1000));
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::OutOfFuel);
+        vm.set_fuel(None);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Done(Value::Int64(499500)));
+    }
 
-    fn eval_src(src: &str) -> Value
+    #[test]
+    fn test_abort()
     {
-        dbg!(src);
         let mut vm = VM::new();
-        let mut input = Input::new(src, "test_src");
-        let unit_fn = parse_unit(&mut vm, &mut input).unwrap();
-        return vm.eval(&unit_fn);
+        let unit_fn = parse_str(&mut vm, "fun f() { while (1) {} } f();").unwrap();
+        vm.set_fuel(Some(1000));
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::OutOfFuel);
+
+        // The VM can be reused after aborting
+        vm.abort();
+        assert!(!vm.is_suspended());
+        assert_eq!(vm.stack_size(), 0);
+        vm.set_fuel(None);
+        let unit_fn = parse_str(&mut vm, "return 3;").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(3)));
     }
 
-    fn eval_file(file_name: & str) -> Value
+    #[test]
+    fn test_interrupt()
     {
-        dbg!(file_name);
         let mut vm = VM::new();
-        let unit_fn = parse_file(&mut vm, file_name).unwrap();
-        return vm.eval(&unit_fn);
+        let unit_fn = parse_str(&mut vm, "let i = 0; while (1) { i = i + 1; if (i == 10) i = 0; }").unwrap();
+
+        let handle = vm.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        });
+
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Interrupted);
+        thread.join().unwrap();
+
+        // The flag is cleared, so execution can continue
+        vm.set_fuel(Some(1000));
+        assert_eq!(vm.resume().unwrap(), EvalStatus::OutOfFuel);
+        vm.abort();
     }
 
     #[test]
-    fn test_eval()
+    fn test_default_args()
     {
-        assert_eq!(eval_src(""), Nil);
-        assert_eq!(eval_src("1;"), Nil);
-        assert_eq!(eval_src("return 7;"), Int64(7));
-        assert_eq!(eval_src("return 1 + 7;"), Int64(8));
-        assert_eq!(eval_src("return 1 + 2 + 3;"), Int64(6));
-        assert_eq!(eval_src("return -3;"), Int64(-3));
+        let src = "let f = fun(a, b = 2, c = a + b) { return 100 * a + 10 * b + c; };";
+        assert_eq!(eval_src(&format!("{} return f(1);", src)), Int64(123));
+        assert_eq!(eval_src(&format!("{} return f(1, 5);", src)), Int64(156));
+        assert_eq!(eval_src(&format!("{} return f(1, 5, 7);", src)), Int64(157));
+
+        // Defaults are evaluated at call time
+        assert_eq!(eval_src("let f = fun(s = 'a') { return s; }; let x = f(); let y = f(); return x == y;"), Int64(1));
+
+        // Generator functions can have default values too
+        assert_eq!(eval_src("let g = fun(n = 3) { for (i in 0..n) yield i; }; let n = 0; for (i in g()) n = n + 1; return n;"), Int64(3));
     }
 
     #[test]
-    fn test_infix_priority()
+    fn test_rest_args()
     {
-        // Priority of operations
-        assert_eq!(eval_src("return 1 + 2 * 3;"), Int64(7));
-        assert_eq!(eval_src("return 1 + 2 + 3 + 4;"), Int64(10));
-        assert_eq!(eval_src("return 1 * 2 + 3 * 4;"), Int64(14));
-        assert_eq!(eval_src("return (1 + 2) * 3;"), Int64(9));
-        assert_eq!(eval_src("return (1 * 2) + (3 * 4);"), Int64(14));
-        assert_eq!(eval_src("return 1 + 2 * 3 + 4;"), Int64(11));
-        assert_eq!(eval_src("return -(1 + 2 * 3) + 4;"), Int64(-3));
+        let src = "let f = fun(a, b = 0, ...rest) { let n = 100 * a + 10 * b; for (x in rest) n = n + x; return n; };";
+        assert_eq!(eval_src(&format!("{} return f(1);", src)), Int64(100));
+        assert_eq!(eval_src(&format!("{} return f(1, 2);", src)), Int64(120));
+        assert_eq!(eval_src(&format!("{} return f(1, 2, 3, 4);", src)), Int64(127));
 
-        // Subtract and operand ordering
-        assert_eq!(eval_src("return 5 - 3;"), Int64(2));
-        assert_eq!(eval_src("return 5 + 2 - 3;"), Int64(4));
+        assert_eq!(eval_src("let f = fun(...rest) { return len(rest); }; return f();"), Int64(0));
+        assert_eq!(eval_src("let f = fun(...rest) { return len(rest); }; return f(1, 2, 3);"), Int64(3));
+        assert_eq!(eval_src("let f = fun(...rest) { return rest[1]; }; return f(1, 2, 3);"), Int64(2));
     }
 
-    #[test]
-    fn test_let_stmt()
+    /// Evaluate a unit that fails with every backend, and get the error
+    /// message. The VM must still be usable afterwards.
+    fn eval_error(src: &str) -> String
     {
-        eval_src("let x = 3;");
-        assert_eq!(eval_src("let x = 2; return x;"), Int64(2));
-        assert_eq!(eval_src("let x = 2; let y = 3; return x + y;"), Int64(5));
+        let mut msgs = Vec::default();
+
+        for backend in [Backend::Stack, Backend::Register, Backend::Jit] {
+            let mut vm = new_vm(backend);
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            msgs.push(vm.eval(&unit_fn).unwrap_err().msg);
+            assert_eq!(vm.stack_size(), 0);
+            assert_eq!(vm.frames.len(), 0);
+
+            let unit_fn = parse_str(&mut vm, "return 1;").unwrap();
+            assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+        }
+
+        assert!(msgs.iter().all(|msg| *msg == msgs[0]));
+        msgs.remove(0)
     }
 
     #[test]
-    fn test_assign()
+    fn test_too_few_args()
     {
-        assert_eq!(eval_src("let x = 2; x = 3; return x;"), Int64(3));
-        assert_eq!(eval_src("let x = 1; let y = 1; x = y = 3; return x+y;"), Int64(6));
-        assert_eq!(eval_src("let x = 1; let y = 1; x = y = x + 3; return x+y;"), Int64(8));
-
-        // Nested scopes
-        assert_eq!(eval_src("let x = 2; { return x; }"), Int64(2));
-        assert_eq!(eval_src("let x = 2; { let x = 3; return x; }"), Int64(3));
-        assert_eq!(eval_src("let x = 2; { let x = 3; x; } return x; "), Int64(2));
+        assert_eq!(eval_error("let f = fun(a, b) {}; f(1);"), "function src expected 2 arguments but got 1");
     }
 
     #[test]
-    fn test_assert()
+    fn test_too_many_args()
     {
-        eval_src("assert 3;");
+        assert_eq!(eval_error("fun f(a, b = 1) {} f(1, 2, 3);"), "function f expected 1 to 2 arguments but got 3");
     }
 
     #[test]
-    fn test_if()
+    fn test_missing_rest_args()
     {
-        assert_eq!(eval_src("let x = 0; if (0) x = 2; return x;"), Int64(0));
-        assert_eq!(eval_src("let x = 0; if (1) x = 2; return x;"), Int64(2));
-        assert_eq!(eval_src("let x = 0; if (0) x = x+2; else x = x+1; return x;"), Int64(1));
-        assert_eq!(eval_src("let x = 0; if (1) x = x+2; else x = x+1; return x;"), Int64(2));
-        assert_eq!(eval_src("let x = 0; if (x) return 1; else return 0;"), Int64(0));
-        assert_eq!(eval_src("let x = 0; if (!x) return 1; else return 0;"), Int64(1));
+        assert_eq!(eval_error("fun f(a, ...rest) {} return f();"), "function f expected at least 1 arguments but got 0");
     }
 
     #[test]
-    fn test_while()
+    fn test_generators()
     {
-        assert_eq!(eval_src("let i = 0; while (i < 10) i = i + 1; return i;"), Int64(10));
+        let counter = "let count = fun(n) { let i = 0; while (i < n) { yield i; i = i + 1; } return 'done'; };";
+
+        // Calling a generator function doesn't run its body
+        assert_eq!(eval_src(&format!("{} let g = count(3); return 5;", counter)), Int64(5));
+
+        // Resuming produces the yielded values, then the return value, then nil
+        assert_eq!(eval_src(&format!("{} let g = count(2); return resume(g) + resume(g);", counter)), Int64(1));
+        assert_eq!(eval_src(&format!("{} let g = count(2); resume(g); resume(g); return resume(g) == 'done';", counter)), Int64(1));
+        assert_eq!(eval_src(&format!("{} let g = count(0); resume(g); return resume(g);", counter)), Nil);
+
+        // Generators have independent state
+        assert_eq!(eval_src(&format!("{} let a = count(5); let b = count(5); resume(a); resume(a); return resume(a) + resume(b);", counter)), Int64(2));
+
+        // For-in loops iterate over the yielded values
+        assert_eq!(eval_src(&format!("{} let n = 0; for (i in count(5)) n = n + i; return n;", counter)), Int64(10));
+        assert_eq!(eval_src(&format!("{} let n = 0; for (i in count(3)) for (j in count(i)) n = n + 1; return n;", counter)), Int64(3));
+
+        // Temporaries such as iterators are saved across yields
+        assert_eq!(eval_src("let g = fun() { for (c in 'ab') for (i in 0..2) yield c; }; let s = ''; for (c in g()) s = s + c; return s == 'aabb';"), Int64(1));
+
+        // Generators can resume other generators
+        assert_eq!(eval_src(&format!("{} let dbl = fun(g) {{ for (i in g) yield 2 * i; }}; let n = 0; for (i in dbl(count(4))) n = n + i; return n;", counter)), Int64(12));
     }
 
     #[test]
@@ -660,9 +4213,9 @@ mod tests
 
         vm.stack_push(str_val);
         vm.gc_collect();
-        assert!(vm.gc_objects.len() == 1);
+        assert!(vm.num_gc_objects() == 1);
 
-        match str_val {
+        match str_val.kind() {
             Str(str_ptr) => unsafe {
                 let str = &*str_ptr;
                 assert!(str == "hello");
@@ -672,7 +4225,459 @@ mod tests
 
         vm.stack_pop();
         vm.gc_collect();
-        assert!(vm.gc_objects.len() == 0);
+        assert!(vm.num_gc_objects() == 0);
+    }
+
+    /// Evaluate source code while collecting on every allocation,
+    /// using either full or minor collections
+    fn eval_src_gc(src: &str, minor: bool) -> (Value, VM)
+    {
+        let (val, vm) = eval_src_gc_backend(src, minor, Backend::Stack);
+
+        for backend in [Backend::Register, Backend::Jit] {
+            let (other_val, other_vm) = eval_src_gc_backend(src, minor, backend);
+            assert_same_result(val, other_val);
+        }
+
+        (val, vm)
+    }
+
+    fn eval_src_gc_backend(src: &str, minor: bool, backend: Backend) -> (Value, VM)
+    {
+        dbg!(src);
+        let mut vm = new_vm(backend);
+
+        if minor {
+            vm.set_gc_policy(GCPolicy { nursery_size: 0, ..GCPolicy::default() });
+        }
+        else
+        {
+            vm.set_gc_policy(GCPolicy { min_threshold: 0, growth_factor: 0.0, ..GCPolicy::default() });
+        }
+
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        let val = eval_done(&mut vm, &unit_fn);
+        (val, vm)
+    }
+
+    #[test]
+    fn test_gc_during_eval()
+    {
+        for minor in [false, true] {
+            gc_during_eval(minor);
+        }
+    }
+
+    fn gc_during_eval(minor: bool)
+    {
+        let eval_src_gc = |src| eval_src_gc(src, minor);
+
+        // String constants only referenced by the unit function and by callees
+        let (val, vm) = eval_src_gc("let s = ''; for (i in 0..20) s = s + 'ab'; return len(s);");
+        assert_eq!(val, Int64(40));
+        let (val, vm) = eval_src_gc("fun f(s) { return s + 'bar'; } let x = ''; for (i in 0..20) x = f('foo'); return x == 'foobar';");
+        assert_eq!(val, Int64(1));
+
+        // Functions referenced by other functions
+        let (val, vm) = eval_src_gc("fun f() { return 'a'; } fun g() { return f() + 'b'; } let s = ''; for (i in 0..10) s = g(); return s == 'ab';");
+        assert_eq!(val, Int64(1));
+        let (val, vm) = eval_src_gc("let f = fun() { return fun(x) { return x + '!'; }; }; let s = 'a'; for (i in 0..5) s = f()(s); return s == 'a!!!!!';");
+        assert_eq!(val, Int64(1));
+
+        // Iterators, generators and rest arguments
+        let (val, vm) = eval_src_gc("let s = ''; for (c in 'abc' + 'def') s = s + c + c; return s == 'aabbccddeeff';");
+        assert_eq!(val, Int64(1));
+        let (val, vm) = eval_src_gc("fun gen(s) { for (c in s) yield c + '.'; } let s = ''; for (c in gen('xyz')) s = s + c; return s == 'x.y.z.';");
+        assert_eq!(val, Int64(1));
+        let (val, vm) = eval_src_gc("fun f(...r) { let s = ''; for (x in r) s = s + x; return s; } let a = 'a'; let c = 'c'; return f(a + 'b', c + 'd') == 'abcd';");
+        assert_eq!(val, Int64(1));
+
+        // Garbage doesn't accumulate
+        let (val, vm) = eval_src_gc("let a = 'a'; let s = ''; for (i in 0..1000) s = a + 'b'; return s == 'ab';");
+        assert_eq!(val, Int64(1));
+        if !minor {
+            assert!(vm.num_gc_objects() < 10);
+        }
+    }
+
+    #[test]
+    fn test_heap_size()
+    {
+        let mut vm = VM::new();
+
+        // String contents are accounted for
+        let str_val = vm.into_gc_heap("x".repeat(100_000));
+        assert!(vm.heap_size() >= 100_000);
+        assert!(vm.heap_size() < 101_000);
+
+        // The heap shrinks when objects are freed
+        vm.stack_push(str_val);
+        vm.into_gc_heap("y".repeat(200_000));
+        assert!(vm.heap_size() >= 300_000);
+        vm.gc_collect();
+        assert!(vm.heap_size() >= 100_000);
+        assert!(vm.heap_size() < 101_000);
+
+        vm.stack_pop();
+        vm.gc_collect();
+        assert_eq!(vm.heap_size(), 0);
+    }
+
+    #[test]
+    fn test_gc_threshold()
+    {
+        let mut vm = VM::new();
+        vm.set_gc_policy(GCPolicy { min_threshold: 10_000, growth_factor: 2.0, generational: false, ..GCPolicy::default() });
+        assert_eq!(vm.gc_threshold, 10_000);
+
+        // The threshold grows with the live set
+        let str_val = vm.into_gc_heap("x".repeat(100_000));
+        vm.stack_push(str_val);
+        vm.gc_collect();
+        assert_eq!(vm.gc_threshold, 2 * vm.heap_size());
+
+        // Allocating garbage doesn't trigger a collection until the
+        // heap has doubled, which doesn't happen for small objects
+        for i in 0..100 {
+            vm.into_gc_heap("y".repeat(100));
+        }
+        assert_eq!(vm.num_gc_objects(), 101);
+
+        // Crossing the threshold collects the garbage
+        vm.into_gc_heap("z".repeat(100_000));
+        assert_eq!(vm.num_gc_objects(), 2);
+
+        // And the threshold falls back to the minimum once the live set is gone
+        vm.stack_pop();
+        vm.gc_collect();
+        assert_eq!(vm.gc_threshold, 10_000);
+    }
+
+    #[test]
+    fn test_generational_gc()
+    {
+        let mut vm = VM::new();
+        vm.set_gc_policy(GCPolicy { nursery_size: 10_000, ..GCPolicy::default() });
+
+        // Objects surviving a minor collection get promoted
+        let str_val = vm.into_gc_heap("a");
+        vm.stack_push(str_val);
+        vm.gc_collect_minor();
+        assert!(str_val.is_old());
+        assert_eq!(vm.num_gc_objects(), 1);
+
+        // Old objects are kept alive by minor collections, even if unreachable
+        vm.stack_pop();
+        vm.stack_push(Value::Nil);
+        vm.gc_collect_minor();
+        assert_eq!(vm.num_gc_objects(), 1);
+        vm.gc_collect();
+        assert_eq!(vm.num_gc_objects(), 0);
+
+        // Young objects referenced only from an old object are kept
+        // alive through the remembered set
+        let arr_val = vm.into_gc_heap(Vec::<Value>::new());
+        vm.stack_push(arr_val);
+        vm.gc_collect_minor();
+        let str_val = vm.into_gc_heap("b");
+        match arr_val.kind() {
+            Array(arr_ptr) => unsafe { (*arr_ptr).push(str_val) },
+            _ => panic!()
+        }
+        vm.write_barrier(arr_val);
+        vm.gc_collect_minor();
+        assert_eq!(vm.num_gc_objects(), 2);
+        assert!(str_val.is_old());
+
+        // Allocating past the nursery size triggers minor collections only
+        for i in 0..1000 {
+            vm.into_gc_heap("c".repeat(100));
+        }
+        let stats = vm.gc_stats();
+        assert!(stats.num_minor > 2);
+        assert_eq!(stats.num_major, 1);
+        assert!(stats.max_pause <= stats.minor_pause + stats.major_pause);
+
+        // Generators saving young values when they yield
+        for minor in [false, true] {
+            let (val, vm) = eval_src_gc("fun gen() { let s = ''; for (i in 0..50) { s = s + 'x'; yield s; } } let n = 0; for (s in gen()) n = n + len(s); return n;", minor);
+            assert_eq!(val, Int64(1275));
+        }
+    }
+
+    #[test]
+    fn test_gc_stress()
+    {
+        let srcs = [
+            "let s = ''; for (i in 0..20) s = s + 'ab'; return len(s) == 40;",
+            "fun f() { return 'a'; } fun g() { return f() + 'b'; } let s = ''; for (i in 0..10) s = g(); return s == 'ab';",
+            "let s = ''; for (c in 'abc' + 'def') s = s + c + c; return s == 'aabbccddeeff';",
+            "fun gen(s) { for (c in s) yield c + '.'; } let s = ''; for (c in gen('xyz')) s = s + c; return s == 'x.y.z.';",
+            "fun f(a, b = a + 'c', ...r) { let s = a + b; for (x in r) s = s + x; return s; } let d = 'd'; return f('a', d + 'c', d + 'e') == 'adcde';",
+            "fun count(n, acc) { if (n == 0) return acc; return count(n - 1, acc + 'x'); } return len(count(50, '')) == 50;",
+            "let b = 'b'; match (b + 'c') { 'a' => return 0; 'bc' => return 1; } return 0;",
+        ];
+
+        let backends = [Backend::Stack, Backend::Register, Backend::Jit];
+        for (generational, backend) in [false, true].into_iter().flat_map(|g| backends.map(|b| (g, b))) {
+            for src in srcs {
+                dbg!(src);
+                let mut vm = new_vm(backend);
+                vm.set_gc_policy(GCPolicy { generational, ..GCPolicy::default() });
+                vm.set_gc_debug(true, true);
+                let unit_fn = parse_str(&mut vm, src).unwrap();
+                assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+                assert!(vm.gc_stats().num_collections() > 0);
+            }
+        }
+
+        // Heap verification disables the JIT, so also run without it
+        for src in srcs {
+            let mut vm = new_vm(Backend::Jit);
+            vm.set_gc_debug(true, false);
+            let unit_fn = parse_str(&mut vm, src).unwrap();
+            assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "dangling pointer")]
+    fn test_verify_dangling()
+    {
+        let mut vm = VM::new();
+        let str_val = vm.into_gc_heap("foo");
+        vm.gc_collect();
+
+        // The string was freed, this pointer is now invalid
+        vm.stack_push(str_val);
+        vm.verify_heap();
+    }
+
+    #[test]
+    #[should_panic(expected = "without a write barrier")]
+    fn test_verify_write_barrier()
+    {
+        let mut vm = VM::new();
+        let arr_val = vm.into_gc_heap(Vec::<Value>::new());
+        vm.stack_push(arr_val);
+        vm.gc_collect();
+        vm.verify_heap();
+
+        let str_val = vm.into_gc_heap("foo");
+        match arr_val.kind() {
+            Array(arr_ptr) => unsafe { (*arr_ptr).push(str_val) },
+            _ => panic!()
+        }
+        vm.verify_heap();
+    }
+
+    #[test]
+    fn test_gc_stats()
+    {
+        let mut vm = VM::new();
+        let str_val = vm.into_gc_heap("x".repeat(1000));
+        vm.into_gc_heap(Vec::<Value>::new());
+        vm.into_gc_heap(Vec::<Value>::new());
+
+        let stats = vm.gc_stats();
+        assert_eq!(stats.num_collections(), 0);
+        assert_eq!(stats.live_objects.str, 1);
+        assert_eq!(stats.live_objects.array, 2);
+        assert_eq!(stats.live_objects.total(), 3);
+        assert_eq!(stats.live_bytes, vm.heap_size());
+
+        vm.stack_push(str_val);
+        vm.gc_collect();
+        vm.gc_collect_minor();
+
+        let stats = vm.gc_stats();
+        assert_eq!(stats.num_major, 1);
+        assert_eq!(stats.num_minor, 1);
+        assert_eq!(stats.num_collections(), 2);
+        assert_eq!(stats.live_objects.str, 1);
+        assert_eq!(stats.live_objects.array, 0);
+        assert!(stats.live_bytes > 1000);
+        assert!(stats.bytes_freed > 0);
+        assert!(stats.total_pause() >= stats.max_pause);
+    }
+
+    #[test]
+    fn test_script_gc()
+    {
+        assert_eq!(eval_src("gc(); gc(); return heap_stats('major_collections');"), Int64(2));
+        assert_eq!(eval_src("return heap_stats('strings');"), Int64(1));
+
+        // No memory is leaked by running a loop
+        let src = "
+            fun f(s) { return s + '!'; }
+            fun work() { for (i in 0..100) { let s = f('x' + 'y'); } }
+            gc();
+            let before = heap_stats('live_objects');
+            work();
+            gc();
+            assert heap_stats('live_objects') == before;
+            return heap_stats('bytes_freed') > 0;
+        ";
+        assert_eq!(eval_src(src), Int64(1));
+    }
+
+    #[test]
+    fn test_debugging()
+    {
+        let src = concat!(
+            "fun f(n) {\n",
+            "  let m = n * 2;\n",
+            "  return m + 1;\n",
+            "}\n",
+            "let a = f(1);\n",
+            "let b = f(a);\n",
+            "debugger;\n",
+            "return a + b;\n",
+        );
+
+        // Debugger statements and breakpoints do nothing unless debugging
+        assert_eq!(eval_src(src), Int64(10));
+
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        vm.add_breakpoint("src", 3);
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(10)));
+
+        let location = |vm: &VM| {
+            let frames = vm.backtrace();
+            (frames.len(), frames[0].fun_name.clone(), frames[0].line_no.unwrap())
+        };
+
+        vm.set_debugging(true);
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (2, "f".to_string(), 3));
+        let frames = vm.backtrace();
+        assert_eq!(frames[0].locals, vec![("n".to_string(), Value::Int64(1)), ("m".to_string(), Value::Int64(2))]);
+        assert_eq!(frames[1].line_no, Some(5));
+        assert_eq!(frames[1].locals, vec![]);
+
+        // The breakpoint is hit again on the second call
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (2, "f".to_string(), 3));
+        assert_eq!(vm.backtrace()[0].locals[0].1, Int64(3));
+        assert_eq!(vm.backtrace()[1].locals, vec![("a".to_string(), Value::Int64(3))]);
+        assert!(vm.remove_breakpoint("src", 3));
+
+        // Stepping out returns to the caller's line
+        vm.step(StepMode::Out);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (1, "src".to_string(), 6));
+
+        // Stepping over stops at the debugger statement, which
+        // then doesn't stop again
+        vm.step(StepMode::Over);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (1, "src".to_string(), 7));
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Done(Value::Int64(10)));
+
+        // Step over calls, and into them
+        vm.step(StepMode::Into);
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (1, "src".to_string(), 5));
+        vm.step(StepMode::Over);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (1, "src".to_string(), 6));
+        vm.step(StepMode::Into);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (2, "f".to_string(), 2));
+        vm.step(StepMode::Over);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (2, "f".to_string(), 3));
+
+        // Continuing stops at the debugger statement
+        vm.step(StepMode::Continue);
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Break);
+        assert_eq!(location(&vm), (1, "src".to_string(), 7));
+        assert_eq!(vm.resume().unwrap(), EvalStatus::Done(Value::Int64(10)));
+        assert_eq!(vm.stack_size(), 0);
+    }
+
+    #[test]
+    fn test_interning()
+    {
+        // Identical literals share a single string, including
+        // folded constants and match cases
+        let mut vm = VM::new();
+        let src = "let a = 'ab'; fun f() { return 'a' + 'b'; } match (a) { 'ab' => return f() == a; } return 0;";
+        let unit_fn = parse_str(&mut vm, src).unwrap();
+        // The strings 'a', 'b' and 'ab', and the function f
+        assert_eq!(vm.num_gc_objects(), 4);
+        assert_eq!(vm.interned.len(), 3);
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+
+        // Strings built at runtime are only interned by intern()
+        assert_eq!(eval_src("let a = 'a'; let s = a + 'b'; return s == 'ab';"), Int64(1));
+        assert_eq!(eval_src("let a = 'a'; let s = intern(a + 'b'); return (s == 'ab') + (s != 'ab') * 2 + (s == 'abc') * 4;"), Int64(1));
+        assert_eq!(eval_src("let a = 'a'; return intern(a + 'b') == intern(a + 'b');"), Int64(1));
+
+        // The symbol table doesn't keep strings alive
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "let a = 'x'; for (i in 0..10) intern(a + 'y'); return 0;").unwrap();
+        vm.eval(&unit_fn).unwrap();
+        assert!(vm.interned.contains_key("xy"));
+        let unit_fn = parse_str(&mut vm, "gc();").unwrap();
+        vm.eval(&unit_fn).unwrap();
+        assert!(!vm.interned.contains_key("xy"));
+    }
+
+    #[test]
+    fn test_string_builder()
+    {
+        assert_eq!(eval_src("let b = string_builder(); append(b, 'x'); append(b, -12); append(b, ''); return to_string(b) == 'x-12';"), Int64(1));
+
+        // Build a 1 MB string
+        let src = "let b = string_builder(); for (i in 0..100000) append(b, 'abcdefghij'); let s = to_string(b); return len(s) + (len(b) == len(s));";
+        assert_eq!(eval_src(src), Int64(1_000_001));
+
+        // The builder's buffer counts towards the heap size and is freed
+        let mut vm = VM::new();
+        let unit_fn = parse_str(&mut vm, "let b = string_builder(); for (i in 0..1000) append(b, 'abcdefghij'); return heap_stats('builders');").unwrap();
+        assert_eq!(vm.eval(&unit_fn).unwrap(), EvalStatus::Done(Value::Int64(1)));
+        assert!(vm.heap_size() >= 10_000);
+        vm.gc_collect();
+        assert!(vm.heap_size() < 10_000);
+
+        // Limits apply to the buffer
+        let mut vm = VM::with_config(VMConfig { max_string_len: 8, ..VMConfig::default() });
+        let unit_fn = parse_str(&mut vm, "let b = string_builder(); while (1) append(b, 'abc');").unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "string too long");
+        assert_eq!(vm.stack_size(), 0);
+
+        // The buffer doesn't grow past the heap limit
+        let mut vm = VM::with_config(VMConfig { max_heap_size: 50_000, ..VMConfig::default() });
+        let unit_fn = parse_str(&mut vm, "let b = string_builder(); while (1) append(b, 'abcdefghij');").unwrap();
+        let err = vm.eval(&unit_fn).unwrap_err();
+        assert_eq!(err.msg, "out of memory");
+        assert_eq!(vm.stack_size(), 0);
+        assert!(vm.heap_size() <= 50_000);
+    }
+
+    #[test]
+    fn test_string_builder_len()
+    {
+        // The length is in characters, not bytes
+        let src = "let b = string_builder(); append(b, 'héllo'); append(b, 42); append(b, 'ü'); return len(b) * 100 + len(to_string(b));";
+        assert_eq!(eval_src(src), Int64(808));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown heap statistic")]
+    fn test_unknown_heap_stat()
+    {
+        eval_src("heap_stats('foo');");
+    }
+
+    #[test]
+    #[should_panic(expected = "heap_stats expects the name of a statistic")]
+    fn test_heap_stats_no_args()
+    {
+        eval_src("println(heap_stats());");
     }
 
     #[test]
@@ -688,5 +4693,7 @@ mod tests
         eval_file("examples/syntax.ks");
         eval_file("examples/fizzbuzz.ks");
         eval_file("examples/99bottles.ks");
+        eval_file("examples/loops.ks");
+        eval_file("examples/generators.ks");
     }
 }